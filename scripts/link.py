@@ -0,0 +1,235 @@
+#!/usr/bin/env python3
+"""Linker wrapper that builds the kernel's symbol table at link time.
+
+The kernel reads its symbol table from a `.ksyms` section between
+`__ksyms_start` and `__ksyms_end` (see src/backtrace/symbols.rs). This links
+with rust-lld twice: first with an empty `.ksyms` section, then with the
+function symbols of that first image, sorted by address and demangled, as the
+section contents. The section is writable, so the linker places it after all
+code and its size moves no function. The final image is checked against the
+table and the link fails when anything did move.
+
+Legacy Rust symbols are demangled here, v0 ones (core and alloc use them)
+through c++filt if present.
+
+Table layout, all little endian:
+    header:  b"KSYM", u32 count, u32 strings offset, u32 reserved
+    entries: u64 address, u32 size, u32 name offset, u32 name length, u32 reserved
+    strings: utf-8 names, offsets are relative to the strings offset
+"""
+
+import re
+import shutil
+import struct
+import subprocess
+import sys
+
+SHT_PROGBITS = 1
+SHT_SYMTAB = 2
+SHT_STRTAB = 3
+SHF_WRITE = 1
+SHF_ALLOC = 2
+STT_FUNC = 2
+ET_REL = 1
+EM_X86_64 = 62
+# STB_GLOBAL << 4 | STT_OBJECT
+GLOBAL_OBJECT = 0x11
+
+ELF_HEADER = struct.Struct("<16sHHIQQQIHHHHHH")
+SECTION_HEADER = struct.Struct("<IIQQQQIIQQ")
+SYMBOL = struct.Struct("<IBBHQQ")
+
+HEADER = struct.Struct("<4sIII")
+ENTRY = struct.Struct("<QIIII")
+
+ESCAPES = {
+    "$SP$": "@",
+    "$BP$": "*",
+    "$RF$": "&",
+    "$LT$": "<",
+    "$GT$": ">",
+    "$LP$": "(",
+    "$RP$": ")",
+    "$C$": ",",
+}
+HASH = re.compile(r"^h[0-9a-f]{16}$")
+CRATE_DISAMBIGUATOR = re.compile(r"\[[0-9a-f]+\]")
+
+
+def demangle_v0(names):
+    """Demangles v0 symbols (`_R...`) with c++filt if it is installed."""
+    names = sorted({name for name in names if name.startswith("_R")})
+    if not names or shutil.which("c++filt") is None:
+        return {}
+
+    output = subprocess.run(
+        ["c++filt"], input="\n".join(names), capture_output=True, text=True
+    ).stdout.splitlines()
+    if len(output) != len(names):
+        return {}
+    return {name: CRATE_DISAMBIGUATOR.sub("", demangled) for name, demangled in zip(names, output)}
+
+
+def demangle(name):
+    """Demangles legacy Rust symbols, anything else is returned unchanged."""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+
+    parts = []
+    rest = name[3:-1]
+    while rest:
+        digits = re.match(r"\d+", rest)
+        if not digits:
+            return name
+        length = int(digits.group())
+        start = len(digits.group())
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+
+    if parts and HASH.match(parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        for escape, char in ESCAPES.items():
+            part = part.replace(escape, char)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        return part.replace("..", "::")
+
+    return "::".join(unescape(part) for part in parts)
+
+
+def sections(elf):
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+
+    headers = []
+    for index in range(shnum):
+        fields = struct.unpack_from("<IIQQQQIIQQ", elf, shoff + index * shentsize)
+        headers.append(fields)
+
+    names_offset = headers[shstrndx][4]
+    for fields in headers:
+        name_start = names_offset + fields[0]
+        name = elf[name_start:elf.index(b"\0", name_start)]
+        yield name, fields
+
+
+def function_symbols(elf, all_sections):
+    for _, fields in all_sections:
+        if fields[1] != SHT_SYMTAB:
+            continue
+        _, _, _, _, offset, size, link, _, _, entsize = fields
+        strtab_offset = all_sections[link][1][4]
+
+        for start in range(offset, offset + size, entsize):
+            st_name, st_info, _, st_shndx, st_value, st_size = struct.unpack_from(
+                "<IBBHQQ", elf, start
+            )
+            if st_info & 0xF != STT_FUNC or st_value == 0 or st_shndx == 0:
+                continue
+            name_start = strtab_offset + st_name
+            name = elf[name_start:elf.index(b"\0", name_start)].decode("utf-8", "replace")
+            yield st_value, st_size, name
+
+
+def build_table(symbols):
+    by_address = {}
+    for address, size, name in symbols:
+        by_address.setdefault(address, (size, name))
+    ordered = sorted(by_address.items())
+
+    strings = bytearray()
+    entries = bytearray()
+    for address, (size, name) in ordered:
+        encoded = name.encode("utf-8")
+        entries += ENTRY.pack(address, size, len(strings), len(encoded), 0)
+        strings += encoded
+
+    strings_offset = HEADER.size + len(entries)
+    return HEADER.pack(b"KSYM", len(ordered), strings_offset, 0) + entries + strings
+
+
+def elf_symbols(path):
+    with open(path, "rb") as file:
+        elf = file.read()
+
+    symbols = list(function_symbols(elf, list(sections(elf))))
+    v0_names = demangle_v0(name for _, _, name in symbols)
+    return [
+        (address, size, v0_names.get(name) or demangle(name)) for address, size, name in symbols
+    ]
+
+
+def write_object(path, table):
+    """Writes a relocatable ELF object holding `table` as its `.ksyms`
+    section, with `__ksyms_start` and `__ksyms_end` around it."""
+    strtab = b"\0__ksyms_start\0__ksyms_end\0"
+    shstrtab = b"\0.ksyms\0.symtab\0.strtab\0.shstrtab\0"
+    symtab = (
+        bytes(SYMBOL.size)
+        + SYMBOL.pack(1, GLOBAL_OBJECT, 0, 1, 0, len(table))
+        + SYMBOL.pack(15, GLOBAL_OBJECT, 0, 1, len(table), 0)
+    )
+
+    contents = bytearray(ELF_HEADER.size)
+    offsets = []
+    for data in (table, symtab, strtab, shstrtab):
+        contents += bytes(-len(contents) % 8)
+        offsets.append(len(contents))
+        contents += data
+    contents += bytes(-len(contents) % 8)
+    section_headers = len(contents)
+
+    # name, type, flags, offset, size, link, info, alignment, entry size
+    headers = [
+        (0, 0, 0, 0, 0, 0, 0, 0, 0),
+        (1, SHT_PROGBITS, SHF_WRITE | SHF_ALLOC, offsets[0], len(table), 0, 0, 8, 0),
+        (8, SHT_SYMTAB, 0, offsets[1], len(symtab), 3, 1, 8, SYMBOL.size),
+        (16, SHT_STRTAB, 0, offsets[2], len(strtab), 0, 0, 1, 0),
+        (24, SHT_STRTAB, 0, offsets[3], len(shstrtab), 0, 0, 1, 0),
+    ]
+    for name, kind, flags, offset, size, link, info, align, entsize in headers:
+        contents += SECTION_HEADER.pack(name, kind, flags, 0, offset, size, link, info, align, entsize)
+
+    ident = b"\x7fELF" + bytes([2, 1, 1]) + bytes(9)
+    contents[: ELF_HEADER.size] = ELF_HEADER.pack(
+        ident, ET_REL, EM_X86_64, 1, 0, 0, section_headers, 0,
+        ELF_HEADER.size, 0, 0, SECTION_HEADER.size, len(headers), len(headers) - 1,
+    )
+    with open(path, "wb") as file:
+        file.write(contents)
+
+
+def link(args, ksyms_object):
+    result = subprocess.run(["rust-lld", "-flavor", "gnu", *args, ksyms_object])
+    if result.returncode != 0:
+        sys.exit(result.returncode)
+
+
+def main():
+    args = sys.argv[1:]
+    if "-o" not in args:
+        sys.exit("link.py: no output file given")
+    output = args[args.index("-o") + 1]
+    ksyms_object = output + ".ksyms.o"
+
+    write_object(ksyms_object, b"")
+    link(args, ksyms_object)
+    symbols = elf_symbols(output)
+    if not symbols:
+        sys.exit(f"link.py: {output} has no function symbols, is it stripped?")
+    table = build_table(symbols)
+
+    write_object(ksyms_object, table)
+    link(args, ksyms_object)
+    if build_table(elf_symbols(output)) != table:
+        sys.exit(
+            f"link.py: functions in {output} moved when the {len(table)} byte symbol "
+            "table was added, .ksyms has to come after the code"
+        )
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,176 @@
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, read_u64, AddressSpace, GenericAddress, SdtHeader};
+
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+const DSDT: usize = 40;
+const SCI_INTERRUPT: usize = 46;
+const SMI_COMMAND: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVENT_BLOCK: usize = 56;
+const PM1B_EVENT_BLOCK: usize = 60;
+const PM1A_CONTROL_BLOCK: usize = 64;
+const PM1B_CONTROL_BLOCK: usize = 68;
+const PM_TIMER_BLOCK: usize = 76;
+const PM1_EVENT_LENGTH: usize = 88;
+const PM1_CONTROL_LENGTH: usize = 89;
+const CENTURY: usize = 108;
+const FLAGS: usize = 112;
+const RESET_REGISTER: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_DSDT: usize = 140;
+const X_PM1A_EVENT_BLOCK: usize = 148;
+const X_PM1B_EVENT_BLOCK: usize = 160;
+const X_PM1A_CONTROL_BLOCK: usize = 172;
+const X_PM1B_CONTROL_BLOCK: usize = 184;
+const X_PM_TIMER_BLOCK: usize = 208;
+
+/// FADT flag saying the reset register is supported.
+const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+/// Fixed ACPI Description Table, only the fields the kernel uses.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub revision: u8,
+    /// ISA IRQ the system control interrupt is wired to
+    pub sci_interrupt: u16,
+    /// port ACPI mode is switched on and off through, 0 if the machine is
+    /// always in ACPI mode
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    /// I/O ports of the power management register blocks, 0 if absent
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    /// CMOS register holding the century, 0 if the RTC has none
+    pub century_register: u8,
+    pub flags: u32,
+    /// register the machine is reset by writing `reset_value` to
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    pub dsdt: Option<PhysAddr>,
+}
+
+/// Finds and parses the FADT, `None` when the firmware has none.
+pub fn parse() -> Option<Fadt> {
+    parse_table(super::find_table(FADT_SIGNATURE)?)
+}
+
+pub fn parse_table(table: &[u8]) -> Option<Fadt> {
+    if table.len() < PM1_CONTROL_LENGTH + 1 {
+        return None;
+    }
+    let header = super::header(table);
+
+    // ACPI 1.0 tables end after the century field, the extended fields only
+    // exist in longer tables
+    let byte = |offset: usize| table.get(offset).copied().unwrap_or(0);
+    let extended = |offset: usize| {
+        table
+            .get(offset..offset + 12)
+            .and_then(GenericAddress::parse)
+    };
+    // the extended I/O address wins if the legacy one is not set
+    let block = |offset: usize, x_offset: usize| match read_u32(table, offset) {
+        0 => extended(x_offset)
+            .filter(|address| address.space == AddressSpace::SystemIo)
+            .map_or(0, |address| address.address as u32),
+        port => port,
+    };
+
+    let flags = table
+        .get(FLAGS..FLAGS + 4)
+        .map_or(0, |_| read_u32(table, FLAGS));
+    let dsdt = match table
+        .get(X_DSDT..X_DSDT + 8)
+        .map(|_| read_u64(table, X_DSDT))
+    {
+        Some(x_dsdt) if x_dsdt != 0 => x_dsdt,
+        _ => read_u32(table, DSDT) as u64,
+    };
+
+    Some(Fadt {
+        revision: header.revision,
+        sci_interrupt: read_u16(table, SCI_INTERRUPT),
+        smi_command_port: read_u32(table, SMI_COMMAND),
+        acpi_enable: table[ACPI_ENABLE],
+        acpi_disable: table[ACPI_DISABLE],
+        pm1a_event_block: block(PM1A_EVENT_BLOCK, X_PM1A_EVENT_BLOCK),
+        pm1b_event_block: block(PM1B_EVENT_BLOCK, X_PM1B_EVENT_BLOCK),
+        pm1a_control_block: block(PM1A_CONTROL_BLOCK, X_PM1A_CONTROL_BLOCK),
+        pm1b_control_block: block(PM1B_CONTROL_BLOCK, X_PM1B_CONTROL_BLOCK),
+        pm_timer_block: block(PM_TIMER_BLOCK, X_PM_TIMER_BLOCK),
+        pm1_event_length: table[PM1_EVENT_LENGTH],
+        pm1_control_length: table[PM1_CONTROL_LENGTH],
+        century_register: byte(CENTURY),
+        flags,
+        reset_register: extended(RESET_REGISTER).filter(|_| flags & RESET_REGISTER_SUPPORTED != 0),
+        reset_value: byte(RESET_VALUE),
+        dsdt: (dsdt != 0).then(|| PhysAddr::new(dsdt)),
+    })
+}
+
+#[test_case]
+fn parses_fadt() {
+    fn put(table: &mut [u8], offset: usize, bytes: &[u8]) {
+        table[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    fn io_address(port: u64) -> [u8; 12] {
+        let mut bytes = [1, 32, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes[4..].copy_from_slice(&port.to_le_bytes());
+        bytes
+    }
+
+    let mut table = [0; 244];
+    put(&mut table, 0, FADT_SIGNATURE);
+    put(&mut table, 4, &244u32.to_le_bytes());
+    table[8] = 6;
+    put(&mut table, DSDT, &0x7FE0_0000u32.to_le_bytes());
+    put(&mut table, SCI_INTERRUPT, &9u16.to_le_bytes());
+    put(&mut table, SMI_COMMAND, &0xB2u32.to_le_bytes());
+    table[ACPI_ENABLE] = 0xF1;
+    table[ACPI_DISABLE] = 0xF0;
+    put(&mut table, PM1A_EVENT_BLOCK, &0x600u32.to_le_bytes());
+    put(&mut table, PM1A_CONTROL_BLOCK, &0x604u32.to_le_bytes());
+    table[PM1_EVENT_LENGTH] = 4;
+    table[PM1_CONTROL_LENGTH] = 2;
+    table[CENTURY] = 0x32;
+    put(&mut table, FLAGS, &RESET_REGISTER_SUPPORTED.to_le_bytes());
+    put(&mut table, RESET_REGISTER, &io_address(0xCF9));
+    table[RESET_VALUE] = 0x06;
+    put(&mut table, X_DSDT, &0x7FF0_0000u64.to_le_bytes());
+    // only the extended PM timer block is filled in
+    put(&mut table, X_PM_TIMER_BLOCK, &io_address(0x608));
+
+    let fadt = parse_table(&table).unwrap();
+    assert_eq!(fadt.revision, 6);
+    assert_eq!(fadt.sci_interrupt, 9);
+    assert_eq!(fadt.smi_command_port, 0xB2);
+    assert_eq!((fadt.acpi_enable, fadt.acpi_disable), (0xF1, 0xF0));
+    assert_eq!(fadt.pm1a_event_block, 0x600);
+    assert_eq!(fadt.pm1b_event_block, 0);
+    assert_eq!(fadt.pm1a_control_block, 0x604);
+    assert_eq!(fadt.pm_timer_block, 0x608);
+    assert_eq!((fadt.pm1_event_length, fadt.pm1_control_length), (4, 2));
+    assert_eq!(fadt.century_register, 0x32);
+    assert_eq!(fadt.reset_register.map(|reset| reset.address), Some(0xCF9));
+    assert_eq!(fadt.reset_value, 0x06);
+    assert_eq!(fadt.dsdt, Some(PhysAddr::new(0x7FF0_0000)));
+
+    // an ACPI 1.0 table ends after the century field
+    let fadt = parse_table(&table[..CENTURY + 1]).unwrap();
+    assert_eq!(fadt.century_register, 0x32);
+    assert_eq!(fadt.flags, 0);
+    assert!(fadt.reset_register.is_none());
+    assert_eq!(fadt.pm_timer_block, 0);
+    assert_eq!(fadt.dsdt, Some(PhysAddr::new(0x7FE0_0000)));
+
+    assert!(parse_table(&table[..PM1_CONTROL_LENGTH]).is_none());
+}
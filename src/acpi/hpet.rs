@@ -0,0 +1,76 @@
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, AddressSpace, GenericAddress, SdtHeader};
+
+const HPET_SIGNATURE: &[u8; 4] = b"HPET";
+
+const COUNTER_64_BIT: u32 = 1 << 13;
+
+/// HPET description table, one per timer block.
+#[derive(Debug, Clone, Copy)]
+pub struct HpetTable {
+    pub hardware_revision: u8,
+    pub comparators: u8,
+    pub counter_64_bit: bool,
+    pub vendor_id: u16,
+    pub address: PhysAddr,
+    pub number: u8,
+    /// minimum main counter ticks between periodic interrupts
+    pub minimum_tick: u16,
+}
+
+/// Finds and parses the HPET table, `None` when the firmware has none.
+pub fn parse() -> Option<HpetTable> {
+    parse_table(super::find_table(HPET_SIGNATURE)?)
+}
+
+pub fn parse_table(table: &[u8]) -> Option<HpetTable> {
+    let body = table.get(core::mem::size_of::<SdtHeader>()..)?;
+    if body.len() < 20 {
+        return None;
+    }
+
+    // the registers have to be memory mapped to be of any use
+    let address = GenericAddress::parse(&body[4..16])?;
+    if address.space != AddressSpace::SystemMemory {
+        return None;
+    }
+
+    let block_id = read_u32(body, 0);
+    Some(HpetTable {
+        hardware_revision: block_id as u8,
+        comparators: ((block_id >> 8) & 0x1F) as u8 + 1,
+        counter_64_bit: block_id & COUNTER_64_BIT != 0,
+        vendor_id: (block_id >> 16) as u16,
+        address: PhysAddr::new(address.address),
+        number: body[16],
+        minimum_tick: read_u16(body, 17),
+    })
+}
+
+#[test_case]
+fn parses_hpet_table() {
+    let mut table = [0; 56];
+    table[..4].copy_from_slice(HPET_SIGNATURE);
+    table[4..8].copy_from_slice(&56u32.to_le_bytes());
+    // revision 1, 3 comparators, 64 bit counter, legacy routing, Intel
+    table[36..40].copy_from_slice(&0x8086_A201u32.to_le_bytes());
+    table[40..44].copy_from_slice(&[0, 64, 0, 0]);
+    table[44..52].copy_from_slice(&0xFED0_0000u64.to_le_bytes());
+    table[52] = 0;
+    table[53..55].copy_from_slice(&128u16.to_le_bytes());
+
+    let hpet = parse_table(&table).unwrap();
+    assert_eq!(hpet.hardware_revision, 1);
+    assert_eq!(hpet.comparators, 3);
+    assert!(hpet.counter_64_bit);
+    assert_eq!(hpet.vendor_id, 0x8086);
+    assert_eq!(hpet.address, PhysAddr::new(0xFED0_0000));
+    assert_eq!(hpet.number, 0);
+    assert_eq!(hpet.minimum_tick, 128);
+
+    assert!(parse_table(&table[..50]).is_none());
+    // registers in I/O space can not be used
+    table[40] = 1;
+    assert!(parse_table(&table).is_none());
+}
@@ -0,0 +1,147 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::mem;
+
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u32, read_u64, SdtHeader};
+
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+/// MADT flag saying the machine also has dual 8259 PICs.
+const PCAT_COMPAT: u32 = 1;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Processor local APIC entry, one per CPU.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    /// bit 0 enabled, bit 1 can be brought online
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// first global system interrupt handled by this IOAPIC
+    pub gsi_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// whatever the bus uses, active high for ISA and active low for PCI
+    Conforming,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// whatever the bus uses, edge for ISA and level for PCI
+    Conforming,
+    Edge,
+    Level,
+}
+
+/// ISA IRQ that is wired to a different global system interrupt, or with
+/// different polarity or trigger mode than ISA defaults.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Multiple APIC Description Table, the interrupt controllers of the machine.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// Override for ISA `irq` if the firmware declared one.
+    pub fn isa_override(&self, irq: u8) -> Option<InterruptOverride> {
+        self.overrides
+            .iter()
+            .find(|entry| entry.isa_irq == irq)
+            .copied()
+    }
+}
+
+/// Finds and parses the MADT, `None` when the firmware has none.
+pub fn parse() -> Option<Madt> {
+    parse_table(super::find_table(MADT_SIGNATURE)?)
+}
+
+pub fn parse_table(table: &[u8]) -> Option<Madt> {
+    let header_size = mem::size_of::<SdtHeader>();
+    if table.len() < header_size + 8 {
+        return None;
+    }
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(read_u32(table, header_size) as u64),
+        legacy_pics: read_u32(table, header_size + 4) & PCAT_COMPAT != 0,
+        local_apics: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = header_size + 8;
+    while offset + 2 <= table.len() {
+        let (kind, len) = (table[offset], table[offset + 1] as usize);
+        if len < 2 || offset + len > table.len() {
+            break;
+        }
+        let entry = &table[offset..offset + len];
+
+        match kind {
+            ENTRY_LOCAL_APIC if len >= 8 => madt.local_apics.push(LocalApic {
+                processor_id: entry[2],
+                apic_id: entry[3],
+                flags: read_u32(entry, 4),
+            }),
+            ENTRY_IO_APIC if len >= 12 => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: PhysAddr::new(read_u32(entry, 4) as u64),
+                gsi_base: read_u32(entry, 8),
+            }),
+            ENTRY_INTERRUPT_OVERRIDE if len >= 10 => {
+                let flags = read_u16(entry, 8);
+                madt.overrides.push(InterruptOverride {
+                    isa_irq: entry[3],
+                    gsi: read_u32(entry, 4),
+                    polarity: match flags & 0b11 {
+                        0b01 => Polarity::ActiveHigh,
+                        0b11 => Polarity::ActiveLow,
+                        _ => Polarity::Conforming,
+                    },
+                    trigger: match (flags >> 2) & 0b11 {
+                        0b01 => TriggerMode::Edge,
+                        0b11 => TriggerMode::Level,
+                        _ => TriggerMode::Conforming,
+                    },
+                });
+            }
+            ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE if len >= 12 => {
+                madt.local_apic_address = PhysAddr::new(read_u64(entry, 4));
+            }
+            _ => {}
+        }
+
+        offset += len;
+    }
+
+    Some(madt)
+}
@@ -0,0 +1,81 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::mem;
+
+use x86_64::PhysAddr;
+
+use super::{read_u16, read_u64, SdtHeader};
+
+const MCFG_SIGNATURE: &[u8; 4] = b"MCFG";
+/// Bytes reserved between the header and the first entry.
+const RESERVED: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+/// Memory mapped PCI Express configuration space of one segment group.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamRegion {
+    /// where the configuration space of bus 0 would start, even if
+    /// `start_bus` is higher
+    pub base_address: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl EcamRegion {
+    /// Physical address of the configuration space of a function.
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = (bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        Some(self.base_address + offset)
+    }
+}
+
+/// PCI Express memory mapped configuration table.
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub regions: Vec<EcamRegion>,
+}
+
+/// Finds and parses the MCFG, `None` when the firmware has none, which means
+/// configuration space is only reachable through the legacy I/O ports.
+pub fn parse() -> Option<Mcfg> {
+    parse_table(super::find_table(MCFG_SIGNATURE)?)
+}
+
+pub fn parse_table(table: &[u8]) -> Option<Mcfg> {
+    let entries = table.get(mem::size_of::<SdtHeader>() + RESERVED..)?;
+
+    let regions = entries
+        .chunks_exact(ENTRY_SIZE)
+        .map(|entry| EcamRegion {
+            base_address: PhysAddr::new(read_u64(entry, 0)),
+            segment: read_u16(entry, 8),
+            start_bus: entry[10],
+            end_bus: entry[11],
+        })
+        .collect();
+
+    Some(Mcfg { regions })
+}
+
+#[test_case]
+fn ecam_function_address() {
+    let region = EcamRegion {
+        base_address: PhysAddr::new(0xB000_0000),
+        segment: 0,
+        start_bus: 1,
+        end_bus: 255,
+    };
+    assert_eq!(
+        region.function_address(1, 2, 3),
+        Some(PhysAddr::new(
+            0xB000_0000 + (1 << 20) + (2 << 15) + (3 << 12)
+        ))
+    );
+    assert_eq!(region.function_address(0, 0, 0), None);
+    assert_eq!(region.function_address(1, 32, 0), None);
+}
@@ -1,9 +1,308 @@
+extern crate alloc;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
 pub mod power;
 
-use x86_64::instructions::interrupts;
+use alloc::vec::Vec;
+use core::{mem, ptr, slice, str};
+
+use spin::Once;
+use x86_64::PhysAddr;
+
+use crate::memory::phys_to_virt;
+
+use self::{fadt::Fadt, hpet::HpetTable, madt::Madt, mcfg::Mcfg};
+
+/// Start of the extended BIOS data area is stored as a segment at this address.
+const EBDA_SEGMENT_PTR: u64 = 0x40E;
+const BIOS_AREA: (u64, u64) = (0xE0000, 0x100000);
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+static RSDP: Once<Option<PhysAddr>> = Once::new();
+static ACPI: Once<Acpi> = Once::new();
+
+/// Root System Description Pointer, the fields after `rsdt_address` only exist
+/// from revision 2 on.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header every ACPI system description table starts with.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Where a generic address structure points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// Register location as ACPI describes it, e.g. the FADT reset register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1 byte, 2 word, 3 dword, 4 qword, 0 for whatever fits the register
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Parses the 12 byte structure, `None` if it is too short or the address is 0.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 12 {
+            return None;
+        }
+        let address = read_u64(bytes, 4);
+        if address == 0 {
+            return None;
+        }
+
+        Some(GenericAddress {
+            space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                space => AddressSpace::Other(space),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address,
+        })
+    }
+}
+
+/// A table the firmware lists in the RSDT or XSDT.
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub address: PhysAddr,
+    pub header: SdtHeader,
+    pub checksum_ok: bool,
+}
+
+impl TableInfo {
+    pub fn signature(&self) -> &str {
+        ascii(&self.header.signature)
+    }
+
+    pub fn oem_id(&self) -> &str {
+        ascii(&self.header.oem_id)
+    }
+
+    pub fn oem_table_id(&self) -> &str {
+        ascii(&self.header.oem_table_id)
+    }
+}
+
+/// Firmware strings are space padded ASCII, anything else shows as `?`.
+fn ascii(bytes: &[u8]) -> &str {
+    str::from_utf8(bytes).unwrap_or("?").trim_end()
+}
+
+/// Everything `init` found in the ACPI tables.
+#[derive(Debug, Clone)]
+pub struct Acpi {
+    pub rsdp_address: PhysAddr,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    /// whether the tables are listed in the 64 bit XSDT instead of the RSDT
+    pub xsdt: bool,
+    pub tables: Vec<TableInfo>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<HpetTable>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    pub fn oem_id(&self) -> &str {
+        ascii(&self.oem_id)
+    }
+
+    pub fn table(&self, signature: &[u8; 4]) -> Option<&TableInfo> {
+        self.tables
+            .iter()
+            .find(|table| table.header.signature == *signature)
+    }
+}
+
+/// Finds the RSDP, lists every table and parses the ones the kernel knows.
+/// Needs the heap. Returns `None` on machines without ACPI.
+pub fn init() -> Option<&'static Acpi> {
+    let rsdp_address = (*RSDP.call_once(find_rsdp))?;
+    let rsdp = rsdp()?;
+    let acpi = ACPI.call_once(|| {
+        let tables = table_addresses()
+            .map(|address| {
+                let header = unsafe { read_physical::<SdtHeader>(address) };
+                TableInfo {
+                    address,
+                    header,
+                    checksum_ok: table_bytes(address).is_some(),
+                }
+            })
+            .collect();
+
+        Acpi {
+            rsdp_address,
+            revision: rsdp.revision,
+            oem_id: rsdp.oem_id,
+            xsdt: uses_xsdt(&rsdp),
+            tables,
+            madt: madt::parse(),
+            fadt: fadt::parse(),
+            hpet: hpet::parse(),
+            mcfg: mcfg::parse(),
+        }
+    });
 
-pub fn init() {}
+    log::info!(
+        "ACPI revision {} by {}, {} tables",
+        acpi.revision,
+        acpi.oem_id(),
+        acpi.tables.len()
+    );
+    Some(acpi)
+}
+
+/// Tables found by `init`, `None` before it ran or without ACPI.
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+/// Bytes of physical memory at `addr`, read through the physical memory mapping.
+unsafe fn physical_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    slice::from_raw_parts(phys_to_virt(addr).as_ptr(), len)
+}
+
+unsafe fn read_physical<T: Copy>(addr: PhysAddr) -> T {
+    ptr::read_unaligned(phys_to_virt(addr).as_ptr())
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Header of a table `table_bytes` returned.
+fn header(table: &[u8]) -> SdtHeader {
+    assert!(table.len() >= mem::size_of::<SdtHeader>());
+    unsafe { ptr::read_unaligned(table.as_ptr() as *const SdtHeader) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/// Scans the first KiB of the EBDA and the BIOS area for an RSDP with valid
+/// checksums.
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = unsafe { read_physical::<u16>(PhysAddr::new(EBDA_SEGMENT_PTR)) } as u64 * 16;
+    let areas = [(ebda, ebda + 1024), BIOS_AREA];
+
+    areas
+        .into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .map(PhysAddr::new)
+        .find(|&addr| {
+            let bytes = unsafe { physical_bytes(addr, 20) };
+            if !bytes.starts_with(RSDP_SIGNATURE) || !checksum_ok(bytes) {
+                return false;
+            }
+
+            // revision 2 adds the XSDT address, covered by its own checksum
+            let rsdp = unsafe { read_physical::<Rsdp>(addr) };
+            rsdp.revision < 2
+                || checksum_ok(unsafe { physical_bytes(addr, mem::size_of::<Rsdp>()) })
+        })
+}
+
+fn rsdp() -> Option<Rsdp> {
+    let addr = (*RSDP.call_once(find_rsdp))?;
+    Some(unsafe { read_physical::<Rsdp>(addr) })
+}
+
+fn uses_xsdt(rsdp: &Rsdp) -> bool {
+    rsdp.revision >= 2 && rsdp.xsdt_address != 0
+}
+
+/// Physical addresses of every table listed in the XSDT, or the RSDT on
+/// ACPI 1.0 machines.
+fn table_addresses() -> impl Iterator<Item = PhysAddr> {
+    let rsdp = rsdp();
+    let (root, entry_size) = match rsdp {
+        Some(rsdp) if uses_xsdt(&rsdp) => (Some(PhysAddr::new(rsdp.xsdt_address)), 8),
+        Some(rsdp) => (Some(PhysAddr::new(rsdp.rsdt_address as u64)), 4),
+        None => (None, 4),
+    };
+
+    let count = root.and_then(table_bytes).map_or(0, |root| {
+        (root.len() - mem::size_of::<SdtHeader>()) / entry_size
+    });
+
+    (0..count).map(move |index| {
+        let entry = root.unwrap() + (mem::size_of::<SdtHeader>() + index * entry_size) as u64;
+        unsafe {
+            if entry_size == 8 {
+                PhysAddr::new(read_physical::<u64>(entry))
+            } else {
+                PhysAddr::new(read_physical::<u32>(entry) as u64)
+            }
+        }
+    })
+}
+
+/// Whole table at `addr` including its header, `None` if it claims to be
+/// shorter than its header or the checksum is wrong.
+pub fn table_bytes(addr: PhysAddr) -> Option<&'static [u8]> {
+    let header = unsafe { read_physical::<SdtHeader>(addr) };
+    if (header.length as usize) < mem::size_of::<SdtHeader>() {
+        return None;
+    }
+    let bytes = unsafe { physical_bytes(addr, header.length as usize) };
+    checksum_ok(bytes).then_some(bytes)
+}
 
-pub fn setup_smp_prerequisities() {
-    interrupts::disable();
+/// Finds the table with the given signature, e.g. `b"APIC"` for the MADT.
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses()
+        .filter(|&addr| unsafe { read_physical::<SdtHeader>(addr) }.signature == *signature)
+        .find_map(table_bytes)
 }
@@ -1,19 +1,296 @@
-use uart_16550::SerialPort;
+use x86_64::{
+    instructions::{self, interrupts, port::Port},
+    structures::DescriptorTablePointer,
+    PhysAddr, VirtAddr,
+};
 
-const QEMU_SHUTDOWN: (usize, u16) = (0x604, 0x2000);
-const VIRTUAL_BOX_SHUTDOWN: (usize, u16) = (0x4004, 0x3400);
-const LEGACY_QEMU_SHUTDOWN: (usize, u16) = (0xb004, 0x2000);
+use super::{fadt::Fadt, AddressSpace, GenericAddress};
+use crate::{
+    memory::vm::{ioremap, CacheMode},
+    time::pit,
+};
 
-const KBD_CONTROLLER_REBOOT: (usize, u8) = (0x64, 0xFE);
+/// Ports emulators power off on without ACPI, with the value to write.
+const QEMU_SHUTDOWN: (u16, u16) = (0x604, 0x2000);
+const VIRTUAL_BOX_SHUTDOWN: (u16, u16) = (0x4004, 0x3400);
+const LEGACY_QEMU_SHUTDOWN: (u16, u16) = (0xb004, 0x2000);
 
-// pub unsafe fn shutdown() {
-//     SerialPort::new(QEMU_SHUTDOWN.0).send(QEMU_SHUTDOWN.1);
-//     SerialPort::new(VIRTUAL_BOX_SHUTDOWN.0).send(VIRTUAL_BOX_SHUTDOWN.1);
-//     SerialPort::new(LEGACY_QEMU_SHUTDOWN.0).send(LEGACY_QEMU_SHUTDOWN.1);
-// }
+const KBD_CONTROLLER_STATUS: u16 = 0x64;
+const KBD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+const KBD_CONTROLLER_RESET: u8 = 0xFE;
 
-// pub unsafe fn reboot() {
-//     SerialPort::new(KBD_CONTROLLER_REBOOT.0).send(KBD_CONTROLLER_REBOOT.1);
+const PCI_CONFIG_ADDRESS: u16 = 0xCF8;
+const PCI_CONFIG_DATA: u16 = 0xCFC;
 
-//     shutdown();
-// }
+/// PM1 control bits.
+const SCI_ENABLE: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+/// AML opcodes needed to read the `\_S5` package.
+const AML_NAME: u8 = 0x08;
+const AML_PACKAGE: u8 = 0x12;
+const AML_ZERO: u8 = 0x00;
+const AML_ONE: u8 = 0x01;
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+/// How long the firmware gets to switch to ACPI mode or act on a request.
+const FIRMWARE_TIMEOUT_MILLIS: u64 = 300;
+const PIT_CYCLES_PER_MILLI: u16 = (pit::PIT_FREQUENCY / 1000) as u16;
+
+/// Finds the `\_S5` object in AML and returns its SLP_TYPa and SLP_TYPb
+/// values. This is a byte scan rather than an interpreter, which is enough
+/// for the plain `Name (_S5, Package () {...})` every firmware uses.
+pub fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let position = aml.windows(4).position(|window| window == b"_S5_")?;
+
+    // `Name` directly before the name, maybe with a root prefix in between
+    let named = match position {
+        0 => false,
+        1 => aml[0] == AML_NAME,
+        _ => aml[position - 1] == AML_NAME || aml[position - 2..position] == [AML_NAME, b'\\'],
+    };
+    if !named {
+        return None;
+    }
+
+    let mut bytes = aml.get(position + 4..)?.iter().copied();
+    if bytes.next()? != AML_PACKAGE {
+        return None;
+    }
+    // the top two bits of the package length say how many more length bytes follow
+    let length_bytes = bytes.next()? >> 6;
+    for _ in 0..length_bytes {
+        bytes.next()?;
+    }
+    let _elements = bytes.next()?;
+
+    let mut integer = || match bytes.next()? {
+        AML_ZERO => Some(0),
+        AML_ONE => Some(1),
+        AML_BYTE_PREFIX => bytes.next(),
+        // some compilers store small values without a prefix
+        value if value < 0x0A => Some(value),
+        _ => None,
+    };
+    let sleep_type_a = integer()?;
+    let sleep_type_b = integer()?;
+    Some((sleep_type_a, sleep_type_b))
+}
+
+/// S5 sleep types from the DSDT.
+fn s5_sleep_types(fadt: &Fadt) -> Option<(u8, u8)> {
+    let dsdt = super::table_bytes(fadt.dsdt?)?;
+    parse_s5(&dsdt[core::mem::size_of::<super::SdtHeader>()..])
+}
+
+fn read_pm1_control(fadt: &Fadt) -> u16 {
+    unsafe { Port::<u16>::new(fadt.pm1a_control_block as u16).read() }
+}
+
+/// Asks the firmware to hand over the power management registers, most
+/// firmware already did when there is no SMI command port.
+fn enable_acpi_mode(fadt: &Fadt) -> bool {
+    if read_pm1_control(fadt) & SCI_ENABLE != 0 {
+        return true;
+    }
+    if fadt.smi_command_port == 0 || fadt.acpi_enable == 0 {
+        return false;
+    }
+
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    poll_firmware(|| read_pm1_control(fadt) & SCI_ENABLE != 0)
+}
+
+/// Checks `done` every millisecond until it holds or the firmware timed out.
+/// Interrupts are off and `Instant` may be counting timer interrupts, so the
+/// time is kept with PIT channel 2.
+fn poll_firmware(mut done: impl FnMut() -> bool) -> bool {
+    for _ in 0..FIRMWARE_TIMEOUT_MILLIS {
+        if done() {
+            return true;
+        }
+        pit::busy_wait(PIT_CYCLES_PER_MILLI, || {});
+    }
+    done()
+}
+
+fn wait_for_firmware() {
+    poll_firmware(|| false);
+}
+
+/// Enters S5 through the PM1 control blocks.
+fn acpi_shutdown() {
+    let Some(fadt) = super::get()
+        .and_then(|acpi| acpi.fadt)
+        .or_else(super::fadt::parse)
+    else {
+        log::warn!("No FADT, cannot power off through ACPI");
+        return;
+    };
+    if fadt.pm1a_control_block == 0 {
+        log::warn!("FADT has no PM1a control block");
+        return;
+    }
+    let Some((sleep_type_a, sleep_type_b)) = s5_sleep_types(&fadt) else {
+        log::warn!("DSDT has no \\_S5 object");
+        return;
+    };
+    if !enable_acpi_mode(&fadt) {
+        log::warn!("Firmware did not switch to ACPI mode");
+        return;
+    }
+
+    log::logger().flush();
+    let write = |port: u32, sleep_type: u8| unsafe {
+        let mut control = Port::<u16>::new(port as u16);
+        let value = control.read() & !(0b111 << SLEEP_TYPE_SHIFT);
+        control.write(value | (sleep_type as u16) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+    };
+    write(fadt.pm1a_control_block, sleep_type_a);
+    if fadt.pm1b_control_block != 0 {
+        write(fadt.pm1b_control_block, sleep_type_b);
+    }
+    wait_for_firmware();
+}
+
+/// Writes `value` to a register described by a generic address structure.
+fn write_generic_address(register: GenericAddress, value: u8) {
+    match register.space {
+        AddressSpace::SystemIo => unsafe { Port::<u8>::new(register.address as u16).write(value) },
+        AddressSpace::SystemMemory => {
+            match ioremap(PhysAddr::new(register.address), 1, CacheMode::Uncached) {
+                Ok(mmio) => mmio.write(0, value),
+                Err(err) => log::error!("Failed to map the reset register: {}", err),
+            }
+        }
+        AddressSpace::PciConfig => {
+            // device and function of bus 0, then the register offset
+            let device = (register.address >> 32) as u32 & 0x1F;
+            let function = (register.address >> 16) as u32 & 0x7;
+            let offset = register.address as u32 & 0xFF;
+            let address = 1 << 31 | device << 11 | function << 8 | offset & 0xFC;
+            unsafe {
+                Port::<u32>::new(PCI_CONFIG_ADDRESS).write(address);
+                Port::<u8>::new(PCI_CONFIG_DATA + (offset & 3) as u16).write(value);
+            }
+        }
+        AddressSpace::Other(space) => {
+            log::warn!("Reset register in unsupported address space {}", space)
+        }
+    }
+}
+
+fn acpi_reset() {
+    let Some(fadt) = super::get()
+        .and_then(|acpi| acpi.fadt)
+        .or_else(super::fadt::parse)
+    else {
+        return;
+    };
+    let Some(register) = fadt.reset_register else {
+        return;
+    };
+
+    log::logger().flush();
+    write_generic_address(register, fadt.reset_value);
+    wait_for_firmware();
+}
+
+/// Pulses the CPU reset line through the 8042.
+fn keyboard_controller_reset() {
+    let mut status = Port::<u8>::new(KBD_CONTROLLER_STATUS);
+    poll_firmware(|| unsafe { status.read() } & KBD_CONTROLLER_INPUT_FULL == 0);
+
+    unsafe { status.write(KBD_CONTROLLER_RESET) };
+    wait_for_firmware();
+}
+
+/// Loads an empty IDT and raises an exception, which cannot be delivered
+/// and escalates to a triple fault, resetting the CPU.
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe {
+        instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
+
+/// Powers the machine off, through ACPI S5 if the firmware supports it and
+/// through emulator specific ports otherwise. Halts if nothing worked.
+pub fn shutdown() -> ! {
+    log::info!("Powering off");
+    log::logger().flush();
+    interrupts::disable();
+
+    acpi_shutdown();
+
+    log::logger().flush();
+    for (port, value) in [QEMU_SHUTDOWN, LEGACY_QEMU_SHUTDOWN, VIRTUAL_BOX_SHUTDOWN] {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+    wait_for_firmware();
+
+    log::error!("Failed to power off, halting");
+    log::logger().flush();
+    crate::hlt_loop()
+}
+
+/// Resets the machine through the ACPI reset register, the keyboard controller
+/// and finally a triple fault.
+pub fn reboot() -> ! {
+    log::info!("Rebooting");
+    log::logger().flush();
+    interrupts::disable();
+
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault()
+}
+
+#[test_case]
+fn parses_s5_package() {
+    // Name (\_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+    let aml = [
+        0x10,
+        0x08,
+        AML_NAME,
+        b'\\',
+        b'_',
+        b'S',
+        b'5',
+        b'_',
+        AML_PACKAGE,
+        0x0A,
+        0x04,
+        AML_BYTE_PREFIX,
+        0x05,
+        AML_BYTE_PREFIX,
+        0x05,
+        AML_ZERO,
+        AML_ZERO,
+    ];
+    assert_eq!(parse_s5(&aml), Some((5, 5)));
+
+    // QEMU's DSDT, Name (_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+    let aml = [
+        AML_NAME,
+        b'_',
+        b'S',
+        b'5',
+        b'_',
+        AML_PACKAGE,
+        0x06,
+        0x04,
+        0,
+        0,
+        0,
+        0,
+    ];
+    assert_eq!(parse_s5(&aml), Some((0, 0)));
+
+    assert_eq!(parse_s5(b"_S5_"), None);
+}
@@ -1,18 +1,31 @@
+pub mod debug;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod slab;
 
 extern crate alloc;
 extern crate core;
 
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use linked_list_allocator::LockedHeap;
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
-        PhysFrame, Size4KiB,
+    instructions::interrupts,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    memory::{
+        self,
+        frame::GlobalFrameAllocator,
+        map_anonymous_region,
+        vm::{self, VmError},
     },
-    PhysAddr, VirtAddr,
+    println, serial_println,
 };
 
 use self::{
@@ -22,54 +35,248 @@ use self::{
 
 // #[global_allocator]
 // static ALLOCATOR: LockedHeap = LockedHeap::empty();
-#[global_allocator]
+// only the kernel itself runs on this allocator, host builds of the crate
+// (e.g. for allocator tests) keep the system allocator
+#[cfg_attr(all(target_os = "none", not(feature = "debug-heap")), global_allocator)]
 static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+/// With the `debug-heap` feature every allocation goes through red zone and
+/// poison checks before reaching `ALLOCATOR`.
+#[cfg(feature = "debug-heap")]
+#[cfg_attr(target_os = "none", global_allocator)]
+static DEBUG_ALLOCATOR: debug::DebugHeap<Locked<FixedSizeBlockAllocator>> =
+    debug::DebugHeap::new(&ALLOCATOR);
 // #[global_allocator]
 // static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 1024 * 1024;
+/// Usage counters kept by the kernel heap allocators.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub allocations: usize,
+    pub frees: usize,
+    /// bytes currently handed out to callers
+    pub in_use: usize,
+    pub peak_in_use: usize,
+}
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
+impl HeapStats {
+    pub const fn new() -> Self {
+        Self {
+            allocations: 0,
+            frees: 0,
+            in_use: 0,
+            peak_in_use: 0,
+        }
+    }
 
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+    fn record_alloc(&mut self, size: usize) {
+        self.allocations += 1;
+        self.in_use += size;
+        self.peak_in_use = self.peak_in_use.max(self.in_use);
+    }
 
-        let heap_start_page = Page::containing_address(heap_start);
+    fn record_free(&mut self, size: usize) {
+        self.frees += 1;
+        self.in_use -= size;
+    }
 
-        let heap_end_page = Page::containing_address(heap_end);
+    fn record_resize(&mut self, old_size: usize, new_size: usize) {
+        self.in_use = self.in_use - old_size + new_size;
+        self.peak_in_use = self.peak_in_use.max(self.in_use);
+    }
+}
 
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
+pub const HEAP_SIZE: usize = 1024 * 1024;
+/// Address space reserved for the heap to grow into.
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024;
+/// The heap grows by at least this many bytes at a time.
+const HEAP_GROW_STEP: usize = 256 * 1024;
 
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
+/// Start of the heap area, picked by the virtual address allocator in `init_heap`.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::Relaxed)
+}
 
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+/// Sets how large the heap may grow, clamped to `HEAP_SIZE..=HEAP_MAX_SIZE`.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.clamp(HEAP_SIZE, HEAP_MAX_SIZE), Ordering::Relaxed);
+}
+
+pub fn heap_limit() -> usize {
+    HEAP_LIMIT.load(Ordering::Relaxed)
+}
+
+pub fn init_heap() -> Result<(), VmError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
 
+    let heap_start = vm::reserve(
+        HEAP_MAX_SIZE as u64,
+        Size2MiB::SIZE,
+        vm::AreaKind::Heap,
+        "heap",
+    )?;
+    memory::with_mapper(|mapper| {
+        map_anonymous_region(
+            heap_start,
+            HEAP_SIZE as u64,
+            flags,
+            mapper,
+            &mut GlobalFrameAllocator,
+        )
+    })?;
+
+    let heap_start = heap_start.as_u64() as usize;
+    HEAP_START.store(heap_start, Ordering::Relaxed);
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(heap_start, HEAP_SIZE);
         // ALLOCATOR.lock().init(HEAP_START as *mut u8, HEAP_SIZE);
     }
 
     log::info!(
         "Heap Initialized from {}-{}",
-        HEAP_START,
-        HEAP_SIZE + HEAP_START
+        heap_start,
+        HEAP_SIZE + heap_start
     );
     Ok(())
 }
 
-fn align_up(addr: usize, align: usize) -> usize {
+/// Prints heap and physical memory usage, used by the `meminfo` command.
+pub fn print_meminfo() {
+    // copy everything out first, printing allocates and the allocator is locked
+    let (stats, size_classes, (heap_size, heap_used, heap_free)) =
+        interrupts::without_interrupts(|| {
+            let allocator = ALLOCATOR.lock();
+            (
+                allocator.stats(),
+                allocator.size_classes(),
+                allocator.fallback_usage(),
+            )
+        });
+    let frames = memory::frame::stats();
+
+    println!(
+        "heap: {} KiB mapped (limit {} KiB), {} KiB used, {} KiB free",
+        heap_size / 1024,
+        heap_limit() / 1024,
+        heap_used / 1024,
+        heap_free / 1024
+    );
+    println!(
+        "in use: {} bytes, peak: {} bytes, allocs: {}, frees: {}",
+        stats.in_use, stats.peak_in_use, stats.allocations, stats.frees
+    );
+    println!(
+        "{:>6} {:>10} {:>10} {:>6}",
+        "size", "allocs", "frees", "free"
+    );
+    for class in size_classes {
+        println!(
+            "{:>6} {:>10} {:>10} {:>6}",
+            class.block_size, class.allocations, class.frees, class.free_blocks
+        );
+    }
+    for cache in slab::caches() {
+        let cache = cache.stats();
+        println!(
+            "slab {}: {} bytes x {} in use, {} free, {} slabs of {} pages",
+            cache.name, cache.object_size, cache.in_use, cache.free, cache.slabs, cache.slab_pages
+        );
+    }
+    for area in vm::areas().iter().flatten() {
+        println!(
+            "vm {} ({:?}): {:?}-{:?}",
+            area.name,
+            area.kind,
+            area.start,
+            area.end()
+        );
+    }
+    for region in memory::fault::lazy_regions().iter().flatten() {
+        println!(
+            "lazy {}: {:?}-{:?}, {} pages resident",
+            region.name, region.start, region.end, region.resident
+        );
+    }
+    println!(
+        "frames: {} used, {} free, {} total",
+        frames.used, frames.free, frames.total
+    );
+}
+
+/// Lists live heap allocations with an id of at least `first_id` together
+/// with where they were allocated, used by the `allocs` command.
+pub fn print_allocations(first_id: u64) {
+    #[cfg(feature = "debug-heap")]
+    DEBUG_ALLOCATOR.print_outstanding(first_id);
+    #[cfg(not(feature = "debug-heap"))]
+    println!("allocation tracking needs a kernel built with the debug-heap feature");
+}
+
+/// Maps fresh frames starting at `heap_top` so the heap can be extended by at
+/// least `min_size` bytes. Returns how many bytes were mapped, which can be
+/// less than requested when the limit is hit or frames run out.
+///
+/// Called with the allocator locked, so nothing in here may allocate.
+fn grow_heap(heap_top: usize, min_size: usize) -> Option<usize> {
+    let heap_end = heap_start() + heap_limit();
+    let size = align_up(min_size.max(HEAP_GROW_STEP), Size4KiB::SIZE as usize)
+        .min(heap_end.saturating_sub(heap_top));
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapped = 0;
+
+    // this CPU can already hold the page tables further up the stack, in
+    // which case growing is not possible and the allocation fails
+    memory::try_with_mapper(|mapper| {
+        while mapped < size {
+            let start = VirtAddr::new((heap_top + mapped) as u64);
+            let chunk =
+                if start.is_aligned(Size2MiB::SIZE) && size - mapped >= Size2MiB::SIZE as usize {
+                    Size2MiB::SIZE
+                } else {
+                    Size4KiB::SIZE
+                };
+
+            if map_anonymous_region(start, chunk, flags, mapper, &mut GlobalFrameAllocator).is_err()
+            {
+                break;
+            }
+            mapped += chunk as usize;
+        }
+    })?;
+
+    (mapped > 0).then_some(mapped)
+}
+
+/// Prints the state of the heap before an allocation failure turns into a panic.
+fn report_heap_exhausted(layout: Layout, heap: &linked_list_allocator::Heap) {
+    serial_println!(
+        "HEAP EXHAUSTED: failed to allocate {} bytes (align {})",
+        layout.size(),
+        layout.align()
+    );
+    serial_println!(
+        "heap size: {} bytes, used: {} bytes, free: {} bytes, limit: {} bytes",
+        heap.size(),
+        heap.used(),
+        heap.free(),
+        heap_limit()
+    );
+    if let Some(frames) = memory::frame::FRAME_ALLOCATOR
+        .try_lock()
+        .and_then(|allocator| allocator.as_ref().map(|a| a.stats()))
+    {
+        serial_println!(
+            "physical frames used: {}, free: {}",
+            frames.used,
+            frames.free
+        );
+    }
+}
+
+const fn align_up(addr: usize, align: usize) -> usize {
     // let remainder = addr % align;
     // if remainder == 0 {
     //     addr // addr alreadyaddr aligned
@@ -82,36 +289,3 @@ fn align_up(addr: usize, align: usize) -> usize {
     // let offset = (addr as *const u8).align_offset(align);
     // addr + offset
 }
-
-pub fn map_physical_to_virtual_address(
-    physical_address: u64,
-    virtual_address: u64,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-    mapper: &mut impl Mapper<Size4KiB>,
-) -> u64 {
-    use x86_64::structures::paging::PageTableFlags as Flags;
-    let virt_addr = VirtAddr::new(virtual_address);
-    let page = Page::containing_address(virt_addr);
-    let frame = PhysFrame::containing_address(PhysAddr::new(physical_address));
-    let offset = physical_address - frame.start_address().as_u64();
-
-    log::info!(
-        "mapping phys: {:x} to {:x}, physcial frame start address: {:?}, virtual frame start address: {:?}",
-        physical_address,
-        virtual_address,
-        frame.start_address(),
-        page.start_address(),
-    );
-
-    let flags = Flags::PRESENT | Flags::WRITABLE;
-    let map_to_result = unsafe { mapper.map_to(page, frame, flags, frame_allocator) };
-    map_to_result.expect("map_to failed").flush();
-
-    offset
-}
-
-pub fn unmap_virtual_address(virtual_address: u64, mapper: &mut impl Mapper<Size4KiB>) {
-    mapper
-        .unmap(Page::containing_address(VirtAddr::new(virtual_address)))
-        .expect("unmap failed");
-}
@@ -0,0 +1,425 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    arch::asm,
+    fmt, mem, ptr,
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::align_up;
+use crate::{backtrace::Symbol, println};
+
+/// Bytes of red zone in front of and behind every allocation.
+const RED_ZONE_SIZE: usize = 32;
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Fresh allocations are filled with this, so reads of uninitialized memory stand out.
+const FRESH_BYTE: u8 = 0xcd;
+/// Freed memory is filled with this before going back to the heap.
+const POISON_BYTE: u8 = 0x6b;
+
+const LIVE_MAGIC: u64 = 0x4845_4150_4c49_5645;
+const FREED_MAGIC: u64 = 0x4845_4150_4652_4545;
+
+/// Return addresses recorded per allocation, enough to get past the alloc
+/// crate frames to the code that actually allocated.
+const SITE_DEPTH: usize = 8;
+/// A saved frame pointer further up than this is not trusted.
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Sits right in front of the leading red zone of every allocation. The
+/// magic is not at the front, the inner allocator keeps its free list links
+/// in the first bytes of a freed block.
+#[repr(C)]
+struct Header {
+    id: u64,
+    size: usize,
+    magic: u64,
+    align: usize,
+    sites: [usize; SITE_DEPTH],
+    prev: *mut Header,
+    next: *mut Header,
+}
+
+const HEADER_SIZE: usize = mem::size_of::<Header>();
+
+/// Every live allocation, oldest first.
+struct LiveList {
+    head: *mut Header,
+    tail: *mut Header,
+    count: usize,
+    bytes: usize,
+    next_id: u64,
+}
+
+// the headers are only touched with the list locked
+unsafe impl Send for LiveList {}
+
+#[derive(Clone, Copy)]
+struct Allocation {
+    id: u64,
+    addr: usize,
+    size: usize,
+    sites: [usize; SITE_DEPTH],
+}
+
+/// Wraps the kernel allocator to catch heap corruption: every allocation gets
+/// red zones on both sides that are checked on `dealloc`, freed memory is
+/// poisoned and each live allocation remembers where it was allocated.
+///
+/// Allocation sites are taken from the frame pointer chain and printed
+/// through the backtrace symbol table.
+pub struct DebugHeap<A: 'static> {
+    inner: &'static A,
+    live: Mutex<LiveList>,
+}
+
+impl<A: GlobalAlloc> DebugHeap<A> {
+    pub const fn new(inner: &'static A) -> Self {
+        Self {
+            inner,
+            live: Mutex::new(LiveList {
+                head: ptr::null_mut(),
+                tail: ptr::null_mut(),
+                count: 0,
+                bytes: 0,
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Offset of the caller's pointer into the block taken from the inner allocator.
+    fn prefix(layout: Layout) -> usize {
+        align_up(HEADER_SIZE + RED_ZONE_SIZE, Self::align(layout))
+    }
+
+    fn align(layout: Layout) -> usize {
+        layout.align().max(mem::align_of::<Header>())
+    }
+
+    fn inner_layout(layout: Layout) -> Option<Layout> {
+        let size = Self::prefix(layout)
+            .checked_add(layout.size())?
+            .checked_add(RED_ZONE_SIZE)?;
+        Layout::from_size_align(size, Self::align(layout)).ok()
+    }
+
+    unsafe fn header(ptr: *mut u8) -> *mut Header {
+        ptr.sub(RED_ZONE_SIZE + HEADER_SIZE) as *mut Header
+    }
+
+    /// Number of live allocations and the bytes they hold.
+    pub fn outstanding(&self) -> (usize, usize) {
+        interrupts::without_interrupts(|| {
+            let live = self.live.lock();
+            (live.count, live.bytes)
+        })
+    }
+
+    /// Copies up to `out.len()` live allocations with an id of at least
+    /// `first_id` into `out`, nothing may allocate while the list is locked.
+    fn collect(&self, first_id: u64, out: &mut [Allocation]) -> usize {
+        interrupts::without_interrupts(|| {
+            let live = self.live.lock();
+            let mut count = 0;
+            let mut current = live.head;
+
+            while let Some(header) = unsafe { current.as_ref() } {
+                if count == out.len() {
+                    break;
+                }
+                if header.id >= first_id {
+                    out[count] = Allocation {
+                        id: header.id,
+                        addr: current as usize + HEADER_SIZE + RED_ZONE_SIZE,
+                        size: header.size,
+                        sites: header.sites,
+                    };
+                    count += 1;
+                }
+                current = header.next;
+            }
+            count
+        })
+    }
+
+    /// Prints every live allocation with an id of at least `first_id`.
+    pub fn print_outstanding(&self, first_id: u64) {
+        let (count, bytes) = self.outstanding();
+        println!("{} live allocations, {} bytes", count, bytes);
+
+        let mut chunk = [Allocation {
+            id: 0,
+            addr: 0,
+            size: 0,
+            sites: [0; SITE_DEPTH],
+        }; 16];
+        let mut next_id = first_id;
+
+        loop {
+            let collected = self.collect(next_id, &mut chunk);
+            for allocation in &chunk[..collected] {
+                print_allocation(allocation);
+            }
+            if collected < chunk.len() {
+                break;
+            }
+            next_id = chunk[collected - 1].id + 1;
+        }
+    }
+
+    /// Checks the header and red zones of a block about to be freed, `dealloc`
+    /// panics with the returned report.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` has to come from `alloc` of this heap, it may already be freed as
+    /// long as the inner allocator has not reused the block.
+    pub unsafe fn verify(&self, ptr: *mut u8, layout: Layout) -> Result<(), Corruption> {
+        let header = &*Self::header(ptr);
+        let addr = ptr as usize;
+        match header.magic {
+            LIVE_MAGIC => {}
+            FREED_MAGIC => {
+                return Err(Corruption::DoubleFree {
+                    addr,
+                    size: layout.size(),
+                })
+            }
+            _ => return Err(Corruption::NotAllocated { addr }),
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            return Err(Corruption::LayoutMismatch {
+                addr,
+                allocated: (header.size, header.align),
+                freed: (layout.size(), layout.align()),
+                sites: header.sites,
+            });
+        }
+
+        let front = ptr.sub(RED_ZONE_SIZE);
+        let back = ptr.add(layout.size());
+        for (zone, before) in [(front, true), (back, false)] {
+            let zone = core::slice::from_raw_parts(zone, RED_ZONE_SIZE);
+            if let Some(offset) = zone.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+                return Err(Corruption::RedZone {
+                    addr,
+                    size: header.size,
+                    id: header.id,
+                    before,
+                    offset,
+                    sites: header.sites,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Heap corruption found when a block is freed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    DoubleFree {
+        addr: usize,
+        size: usize,
+    },
+    /// not a live allocation, or its header was overwritten
+    NotAllocated {
+        addr: usize,
+    },
+    /// freed with a different size or alignment than it was allocated with,
+    /// both as `(size, align)`
+    LayoutMismatch {
+        addr: usize,
+        allocated: (usize, usize),
+        freed: (usize, usize),
+        sites: [usize; SITE_DEPTH],
+    },
+    /// `offset` is the first overwritten byte of the red zone in front of or
+    /// behind the allocation
+    RedZone {
+        addr: usize,
+        size: usize,
+        id: u64,
+        before: bool,
+        offset: usize,
+        sites: [usize; SITE_DEPTH],
+    },
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Corruption::DoubleFree { addr, size } => {
+                write!(f, "double free of {:#x} ({} bytes)", addr, size)
+            }
+            Corruption::NotAllocated { addr } => write!(
+                f,
+                "free of {:#x} which is not a live allocation or has a corrupted header",
+                addr
+            ),
+            Corruption::LayoutMismatch {
+                addr,
+                allocated,
+                freed,
+                sites,
+            } => write!(
+                f,
+                "{:#x} allocated as {} bytes (align {}) but freed as {} bytes (align {}), allocated at {}",
+                addr,
+                allocated.0,
+                allocated.1,
+                freed.0,
+                freed.1,
+                Sites(sites)
+            ),
+            Corruption::RedZone {
+                addr,
+                size,
+                id,
+                before,
+                offset,
+                sites,
+            } => write!(
+                f,
+                "red zone {} {:#x} ({} bytes, #{}) overwritten at byte {}, allocated at {}",
+                if *before { "before" } else { "after" },
+                addr,
+                size,
+                id,
+                offset,
+                Sites(sites)
+            ),
+        }
+    }
+}
+
+/// Recorded allocation sites, symbolized and innermost first.
+struct Sites<'a>(&'a [usize; SITE_DEPTH]);
+
+impl fmt::Display for Sites<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut sites = self.0.iter().take_while(|&&site| site != 0);
+        match sites.next() {
+            Some(&site) => write!(f, "{}", Symbol::return_address(site as u64))?,
+            None => return write!(f, "<unknown>"),
+        }
+        for &site in sites {
+            write!(f, " <- {}", Symbol::return_address(site as u64))?;
+        }
+        Ok(())
+    }
+}
+
+fn print_allocation(allocation: &Allocation) {
+    println!(
+        "#{} {:#x} {} bytes",
+        allocation.id, allocation.addr, allocation.size
+    );
+    for &site in allocation.sites.iter().take_while(|&&site| site != 0) {
+        println!("    {}", Symbol::return_address(site as u64));
+    }
+}
+
+/// Walks the frame pointer chain of the caller and returns the first
+/// `SITE_DEPTH` return addresses. Requires frame pointers, which the kernel
+/// target enables.
+#[inline(always)]
+fn allocation_sites() -> [usize; SITE_DEPTH] {
+    let mut sites = [0; SITE_DEPTH];
+    let mut rbp: usize;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    for site in sites.iter_mut() {
+        if rbp == 0 || !rbp.is_multiple_of(mem::align_of::<usize>()) {
+            break;
+        }
+        let (next, return_address) =
+            unsafe { (*(rbp as *const usize), *((rbp + 8) as *const usize)) };
+        *site = return_address;
+
+        // stacks grow down, so the caller's frame has to be above this one
+        if next <= rbp || next - rbp > MAX_FRAME_SIZE {
+            break;
+        }
+        rbp = next;
+    }
+    sites
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(inner_layout) = Self::inner_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let base = self.inner.alloc(inner_layout);
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = base.add(Self::prefix(layout));
+        ptr.sub(RED_ZONE_SIZE)
+            .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr.add(layout.size())
+            .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr.write_bytes(FRESH_BYTE, layout.size());
+
+        let header = Self::header(ptr);
+        let sites = allocation_sites();
+
+        interrupts::without_interrupts(|| {
+            let mut live = self.live.lock();
+            header.write(Header {
+                magic: LIVE_MAGIC,
+                id: live.next_id,
+                size: layout.size(),
+                align: layout.align(),
+                sites,
+                prev: live.tail,
+                next: ptr::null_mut(),
+            });
+
+            match live.tail.as_mut() {
+                Some(tail) => tail.next = header,
+                None => live.head = header,
+            }
+            live.tail = header;
+            live.next_id += 1;
+            live.count += 1;
+            live.bytes += layout.size();
+        });
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Err(corruption) = self.verify(ptr, layout) {
+            panic!("heap: {}", corruption);
+        }
+        let header = Self::header(ptr);
+
+        interrupts::without_interrupts(|| {
+            let mut live = self.live.lock();
+            let (prev, next) = ((*header).prev, (*header).next);
+
+            match prev.as_mut() {
+                Some(prev) => prev.next = next,
+                None => live.head = next,
+            }
+            match next.as_mut() {
+                Some(next) => next.prev = prev,
+                None => live.tail = prev,
+            }
+            live.count -= 1;
+            live.bytes -= layout.size();
+            (*header).magic = FREED_MAGIC;
+        });
+
+        ptr.write_bytes(POISON_BYTE, layout.size());
+
+        let inner_layout = Self::inner_layout(layout).unwrap();
+        self.inner
+            .dealloc(ptr.sub(Self::prefix(layout)), inner_layout);
+    }
+}
@@ -6,10 +6,21 @@ use core::{
     ptr::{self, NonNull},
 };
 
-use super::linked_list::Locked;
+use x86_64::instructions::interrupts;
+
+use super::{linked_list::Locked, HeapStats};
 
 const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    /// blocks currently parked on the free list of this class
+    pub free_blocks: usize,
+}
+
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
@@ -17,27 +28,76 @@ struct ListNode {
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    stats: HeapStats,
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
 
+        let mut size_classes = [SizeClassStats {
+            block_size: 0,
+            allocations: 0,
+            frees: 0,
+            free_blocks: 0,
+        }; BLOCK_SIZES.len()];
+        let mut index = 0;
+        while index < BLOCK_SIZES.len() {
+            size_classes[index].block_size = BLOCK_SIZES[index];
+            index += 1;
+        }
+
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            size_classes,
+            stats: HeapStats::new(),
         }
     }
 
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    pub fn size_classes(&self) -> [SizeClassStats; BLOCK_SIZES.len()] {
+        self.size_classes
+    }
+
+    /// Bytes of the fallback heap as `(size, used, free)`, blocks sitting on
+    /// the size class free lists count as used.
+    pub fn fallback_usage(&self) -> (usize, usize, usize) {
+        (
+            self.fallback_allocator.size(),
+            self.fallback_allocator.used(),
+            self.fallback_allocator.free(),
+        )
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.fallback_allocator
             .init(heap_start as *mut u8, heap_size);
     }
 
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => ptr::null_mut(),
+        loop {
+            if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+
+            let heap_top = self.fallback_allocator.top() as usize;
+            // leave room for alignment padding in front of the allocation
+            let needed = layout.size().saturating_add(layout.align());
+
+            match super::grow_heap(heap_top, needed) {
+                Some(grown) => unsafe { self.fallback_allocator.extend(grown) },
+                // empty slabs hold on to frames the heap could use
+                None if super::slab::shrink_caches() > 0 => continue,
+                None => {
+                    super::report_heap_exhausted(layout, &self.fallback_allocator);
+                    return ptr::null_mut();
+                }
+            }
         }
     }
 }
@@ -48,30 +108,56 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
 }
 
+// Interrupts stay disabled while the heap is locked. Threads are preempted by
+// the timer, one preempted in here would leave every other thread spinning.
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.deallocate(ptr, layout))
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
         match list_index(&layout) {
-            Some(index) => match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut ListNode as *mut u8
+            Some(index) => {
+                let ptr = match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        allocator.list_heads[index] = node.next.take();
+                        allocator.size_classes[index].free_blocks -= 1;
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        let block_size = BLOCK_SIZES[index];
+                        let block_align = block_size;
+
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+
+                        allocator.fallback_alloc(layout)
+                    }
+                };
+                if !ptr.is_null() {
+                    allocator.size_classes[index].allocations += 1;
+                    allocator.stats.record_alloc(BLOCK_SIZES[index]);
                 }
-                None => {
-                    let block_size = BLOCK_SIZES[index];
-                    let block_align = block_size;
-
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-
-                    allocator.fallback_alloc(layout)
+                ptr
+            }
+            None => {
+                let ptr = allocator.fallback_alloc(layout);
+                if !ptr.is_null() {
+                    allocator.stats.record_alloc(layout.size());
                 }
-            },
-            None => allocator.fallback_alloc(layout),
+                ptr
+            }
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
 
         match list_index(&layout) {
@@ -85,10 +171,18 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
 
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
+
+                let class = &mut allocator.size_classes[index];
+                class.frees += 1;
+                class.free_blocks += 1;
+                allocator.stats.record_free(BLOCK_SIZES[index]);
+            }
+            None => {
+                allocator
+                    .fallback_allocator
+                    .deallocate(NonNull::new(ptr).unwrap(), layout);
+                allocator.stats.record_free(layout.size());
             }
-            None => allocator
-                .fallback_allocator
-                .deallocate(NonNull::new(ptr).unwrap(), layout),
         }
     }
 }
@@ -1,6 +1,9 @@
 use spin::{Mutex, MutexGuard};
 
-use crate::{allocator::align_up, println};
+use crate::{
+    allocator::{align_up, HeapStats},
+    println,
+};
 use core::{
     alloc::{GlobalAlloc, Layout},
     mem, ptr,
@@ -25,48 +28,109 @@ impl ListNode {
     }
 }
 
+const NODE_SIZE: usize = mem::size_of::<ListNode>();
+const NODE_ALIGN: usize = mem::align_of::<ListNode>();
+
+/// First fit allocator over a free list sorted by address. Freed regions are
+/// merged with the free regions directly before and after them, so the heap
+/// does not fragment into pieces that are too small for larger allocations.
 pub struct LinkedListAllocator {
     head: ListNode,
+    stats: HeapStats,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            stats: HeapStats::new(),
         }
     }
 
+    pub fn stats(&self) -> HeapStats {
+        self.stats
+    }
+
+    /// Total size of all regions on the free list.
+    pub fn free_bytes(&self) -> usize {
+        self.regions().map(|region| region.size).sum()
+    }
+
+    /// Size of the largest region on the free list, i.e. the largest
+    /// allocation with minimal alignment that can currently succeed.
+    pub fn largest_free_region(&self) -> usize {
+        self.regions().map(|region| region.size).max().unwrap_or(0)
+    }
+
+    fn regions(&self) -> impl Iterator<Item = &ListNode> {
+        let mut current = self.head.next.as_deref();
+        core::iter::from_fn(move || {
+            let region = current?;
+            current = region.next.as_deref();
+            Some(region)
+        })
+    }
+
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.add_free_region(heap_start, heap_size);
     }
 
+    /// Inserts a region into the address ordered free list, merging it with
+    /// its neighbours when they touch.
     pub unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
-        let aligned_addr = align_up(addr, mem::size_of::<ListNode>());
-        let mut aligned_size = size;
-        if aligned_addr != addr {
-            if size > aligned_addr - addr {
-                aligned_size = size - (aligned_addr - addr);
-            } else {
-                return;
-            }
+        let aligned_addr = align_up(addr, NODE_ALIGN);
+        if aligned_addr - addr >= size {
+            return;
         }
-
-        if !(aligned_size >= mem::size_of::<ListNode>()) {
+        let size = (size - (aligned_addr - addr)) & !(NODE_ALIGN - 1);
+        if size < NODE_SIZE {
             return;
         }
-        // assert_eq!(align_up(addr, mem::size_of::<ListNode>()), addr);
-        // assert!(size >= mem::size_of::<ListNode>());
 
-        // let mut node = ListNode::new(size);
-        let mut node = ListNode::new(aligned_size);
+        let head: *mut ListNode = &mut self.head;
 
-        node.next = self.head.next.take();
+        // find the last region starting before the new one
+        let mut prev = head;
+        while let Some(next) = (*prev).next.as_deref_mut() {
+            if next.start_addr() > aligned_addr {
+                break;
+            }
+            prev = next;
+        }
 
-        let node_ptr = aligned_addr as *mut ListNode;
+        debug_assert!(
+            prev == head || (*prev).end_addr() <= aligned_addr,
+            "freed region 0x{:x} overlaps a free region",
+            aligned_addr
+        );
 
-        node_ptr.write(node);
+        let node_ptr = aligned_addr as *mut ListNode;
+        node_ptr.write(ListNode {
+            size,
+            next: (*prev).next.take(),
+        });
+        let node = &mut *node_ptr;
+
+        if let Some(next) = node.next.take() {
+            debug_assert!(
+                node.end_addr() <= next.start_addr(),
+                "freed region 0x{:x} overlaps a free region",
+                aligned_addr
+            );
+            if node.end_addr() == next.start_addr() {
+                node.size += next.size;
+                node.next = next.next.take();
+            } else {
+                node.next = Some(next);
+            }
+        }
 
-        self.head.next = Some(&mut *node_ptr)
+        if prev != head && (*prev).end_addr() == aligned_addr {
+            (*prev).size += node.size;
+            (*prev).next = node.next.take();
+        } else {
+            (*prev).next = Some(node);
+        }
     }
 
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
@@ -86,8 +150,36 @@ impl LinkedListAllocator {
         None
     }
 
+    /// Unlinks the free region starting exactly at `addr`, if there is one.
+    fn take_region_at(&mut self, addr: usize) -> Option<&'static mut ListNode> {
+        let mut current = &mut self.head;
+
+        while let Some(ref mut region) = current.next {
+            if region.start_addr() == addr {
+                let next = region.next.take();
+                let region = current.next.take();
+                current.next = next;
+                return region;
+            } else if region.start_addr() > addr {
+                return None;
+            } else {
+                current = current.next.as_mut().unwrap();
+            }
+        }
+
+        None
+    }
+
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+
+        // the padding in front of the allocation goes back on the free list,
+        // so it has to be able to hold a node
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < NODE_SIZE {
+            alloc_start = align_up(region.start_addr() + NODE_SIZE, align);
+        }
+
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -95,13 +187,58 @@ impl LinkedListAllocator {
         }
 
         let excess_size = region.end_addr() - alloc_end;
-        if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+        if excess_size > 0 && excess_size < NODE_SIZE {
             return Err(());
         }
 
         Ok(alloc_start)
     }
 
+    /// Grows the allocation `[addr, addr + old_size)` to `new_size` bytes by
+    /// taking memory from the free region right after it.
+    unsafe fn grow_in_place(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let old_end = addr + old_size;
+        let Some(region) = self.take_region_at(old_end) else {
+            return false;
+        };
+
+        let needed = new_size - old_size;
+        let (region_start, region_size) = (region.start_addr(), region.size);
+        let excess_size = region_size.wrapping_sub(needed);
+
+        if region_size < needed || (excess_size > 0 && excess_size < NODE_SIZE) {
+            self.add_free_region(region_start, region_size);
+            return false;
+        }
+
+        if excess_size > 0 {
+            self.add_free_region(addr + new_size, excess_size);
+        }
+        true
+    }
+
+    /// Shrinks the allocation `[addr, addr + old_size)` to `new_size` bytes and
+    /// returns the tail to the free list.
+    unsafe fn shrink_in_place(&mut self, addr: usize, old_size: usize, new_size: usize) -> bool {
+        let tail_size = old_size - new_size;
+        if tail_size == 0 {
+            return true;
+        }
+
+        if tail_size < NODE_SIZE {
+            // too small for a node of its own, but it can join a free region
+            // directly after the allocation
+            let Some(region) = self.take_region_at(addr + old_size) else {
+                return false;
+            };
+            let region_size = region.size;
+            self.add_free_region(addr + new_size, tail_size + region_size);
+        } else {
+            self.add_free_region(addr + new_size, tail_size);
+        }
+        true
+    }
+
     fn size_align(layout: Layout) -> (usize, usize) {
         let layout = layout
             .align_to(mem::align_of::<ListNode>())
@@ -125,7 +262,7 @@ impl<A> Locked<A> {
         }
     }
 
-    pub fn lock(&self) -> MutexGuard<A> {
+    pub fn lock(&self) -> MutexGuard<'_, A> {
         self.inner.lock()
     }
 }
@@ -142,11 +279,15 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
             // println!("alloc_start: {alloc_start}, alloc_end: {alloc_end}");
 
-            let excess_size = region.end_addr() - alloc_end;
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
 
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
+            if alloc_start > region_start {
+                allocator.add_free_region(region_start, alloc_start - region_start);
+            }
+            if region_end > alloc_end {
+                allocator.add_free_region(alloc_end, region_end - alloc_end);
             }
+            allocator.stats.record_alloc(size);
 
             alloc_start as *mut u8
         } else {
@@ -157,6 +298,37 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
         let (size, _) = LinkedListAllocator::size_align(layout);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.add_free_region(ptr as usize, size);
+        allocator.stats.record_free(size);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+
+        {
+            let mut allocator = self.lock();
+            let addr = ptr as usize;
+
+            let resized = if new_size > old_size {
+                allocator.grow_in_place(addr, old_size, new_size)
+            } else {
+                allocator.shrink_in_place(addr, old_size, new_size)
+            };
+
+            if resized {
+                allocator.stats.record_resize(old_size, new_size);
+                return ptr;
+            }
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_layout.size()));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
     }
 }
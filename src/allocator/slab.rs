@@ -0,0 +1,442 @@
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{PageSize, PhysFrame, Size4KiB},
+};
+
+use super::align_up;
+use crate::memory::{self, frame};
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+/// Slabs grow (in powers of two pages) until they hold at least this many objects.
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+const MAX_SLAB_PAGES: usize = 64;
+/// Completely free slabs kept around for reuse, any more go back to the frame allocator.
+const MAX_EMPTY_SLABS: usize = 2;
+
+/// Head of the list of every cache that has allocated at least one slab.
+static CACHES: AtomicPtr<RawSlabCache> = AtomicPtr::new(ptr::null_mut());
+
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Header at the start of every slab, the slab itself is aligned to its size
+/// so an object can find its slab by masking its address.
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    in_use: usize,
+    frame: PhysFrame,
+}
+
+/// Intrusive doubly linked list of slabs, removal is constant time.
+struct SlabList {
+    head: *mut Slab,
+    len: usize,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self {
+            head: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    unsafe fn push(&mut self, slab: *mut Slab) {
+        (*slab).prev = ptr::null_mut();
+        (*slab).next = self.head;
+        if !self.head.is_null() {
+            (*self.head).prev = slab;
+        }
+        self.head = slab;
+        self.len += 1;
+    }
+
+    unsafe fn remove(&mut self, slab: *mut Slab) {
+        if (*slab).prev.is_null() {
+            self.head = (*slab).next;
+        } else {
+            (*(*slab).prev).next = (*slab).next;
+        }
+        if !(*slab).next.is_null() {
+            (*(*slab).next).prev = (*slab).prev;
+        }
+        self.len -= 1;
+    }
+
+    unsafe fn pop(&mut self) -> Option<*mut Slab> {
+        let slab = self.head;
+        if slab.is_null() {
+            return None;
+        }
+        self.remove(slab);
+        Some(slab)
+    }
+}
+
+struct Slabs {
+    /// slabs with at least one free and one used object
+    partial: SlabList,
+    empty: SlabList,
+    /// full slabs are not linked anywhere, they come back on free
+    full: usize,
+    in_use: usize,
+    allocations: usize,
+    frees: usize,
+}
+
+// the slab pointers are only touched with the cache locked
+unsafe impl Send for Slabs {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slab_pages: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    /// free objects in slabs that are currently allocated
+    pub free: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+/// Untyped slab cache for objects of one size and alignment.
+pub struct RawSlabCache {
+    name: &'static str,
+    object_size: usize,
+    first_object: usize,
+    objects_per_slab: usize,
+    slab_pages: usize,
+    slabs: Mutex<Slabs>,
+    registered: AtomicBool,
+    next: AtomicPtr<RawSlabCache>,
+}
+
+impl RawSlabCache {
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align > mem::align_of::<FreeObject>() {
+            align
+        } else {
+            mem::align_of::<FreeObject>()
+        };
+        assert!(
+            align <= PAGE_SIZE,
+            "slab objects can be at most page aligned"
+        );
+
+        let size = if size > mem::size_of::<FreeObject>() {
+            size
+        } else {
+            mem::size_of::<FreeObject>()
+        };
+        let object_size = align_up(size, align);
+        let first_object = align_up(mem::size_of::<Slab>(), align);
+
+        let mut slab_pages = 1;
+        while (slab_pages * PAGE_SIZE - first_object) / object_size < MIN_OBJECTS_PER_SLAB
+            && slab_pages < MAX_SLAB_PAGES
+        {
+            slab_pages *= 2;
+        }
+        let objects_per_slab = (slab_pages * PAGE_SIZE - first_object) / object_size;
+        assert!(objects_per_slab > 0, "object too large for a slab");
+
+        Self {
+            name,
+            object_size,
+            first_object,
+            objects_per_slab,
+            slab_pages,
+            slabs: Mutex::new(Slabs {
+                partial: SlabList::new(),
+                empty: SlabList::new(),
+                full: 0,
+                in_use: 0,
+                allocations: 0,
+                frees: 0,
+            }),
+            registered: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn slab_bytes(&self) -> usize {
+        self.slab_pages * PAGE_SIZE
+    }
+
+    /// Returns an uninitialized object, or `None` when no frames are left.
+    pub fn alloc(&'static self) -> Option<NonNull<u8>> {
+        interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+
+            let slab = unsafe {
+                if !slabs.partial.head.is_null() {
+                    slabs.partial.head
+                } else {
+                    let slab = match slabs.empty.pop() {
+                        Some(slab) => slab,
+                        None => self.new_slab()?,
+                    };
+                    slabs.partial.push(slab);
+                    slab
+                }
+            };
+
+            unsafe {
+                let object = (*slab).free;
+                (*slab).free = (*object).next;
+                (*slab).in_use += 1;
+                if (*slab).free.is_null() {
+                    slabs.partial.remove(slab);
+                    slabs.full += 1;
+                }
+
+                slabs.in_use += 1;
+                slabs.allocations += 1;
+                NonNull::new(object as *mut u8)
+            }
+        })
+    }
+
+    /// Returns an object to its slab.
+    ///
+    /// # Safety
+    ///
+    /// `object` must have been returned by `alloc` of this cache and must not
+    /// be used or freed again afterwards.
+    pub unsafe fn free(&self, object: NonNull<u8>) {
+        let object = object.as_ptr() as *mut FreeObject;
+        let slab = (object as usize & !(self.slab_bytes() - 1)) as *mut Slab;
+
+        interrupts::without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+
+            let was_full = (*slab).free.is_null();
+            (*object).next = (*slab).free;
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+
+            if was_full {
+                slabs.full -= 1;
+                if (*slab).in_use == 0 {
+                    slabs.empty.push(slab);
+                } else {
+                    slabs.partial.push(slab);
+                }
+            } else if (*slab).in_use == 0 {
+                slabs.partial.remove(slab);
+                slabs.empty.push(slab);
+            }
+
+            slabs.in_use -= 1;
+            slabs.frees += 1;
+
+            while slabs.empty.len > MAX_EMPTY_SLABS {
+                let slab = slabs.empty.pop().unwrap();
+                self.release_slab(slab);
+            }
+        })
+    }
+
+    /// Gives every empty slab back to the frame allocator, returns the number
+    /// of pages freed.
+    pub fn shrink(&self) -> usize {
+        interrupts::without_interrupts(|| self.shrink_locked(&mut self.slabs.lock()))
+    }
+
+    fn shrink_locked(&self, slabs: &mut Slabs) -> usize {
+        let mut pages = 0;
+        while let Some(slab) = unsafe { slabs.empty.pop() } {
+            unsafe { self.release_slab(slab) };
+            pages += self.slab_pages;
+        }
+        pages
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let slabs = interrupts::without_interrupts(|| {
+            let slabs = self.slabs.lock();
+            (
+                slabs.partial.len + slabs.empty.len + slabs.full,
+                slabs.in_use,
+                slabs.allocations,
+                slabs.frees,
+            )
+        });
+        let (count, in_use, allocations, frees) = slabs;
+
+        SlabStats {
+            name: self.name,
+            object_size: self.object_size,
+            slab_pages: self.slab_pages,
+            slabs: count,
+            in_use,
+            free: count * self.objects_per_slab - in_use,
+            allocations,
+            frees,
+        }
+    }
+
+    /// Takes a fresh slab from the frame allocator and threads all of its
+    /// objects onto the slab free list.
+    unsafe fn new_slab(&'static self) -> Option<*mut Slab> {
+        let frame = frame::allocate_frames_aligned(self.slab_pages, self.slab_pages)?;
+        let start = memory::phys_to_virt(frame.start_address());
+        debug_assert!(
+            start.is_aligned(self.slab_bytes() as u64),
+            "physical memory offset is not aligned to the slab size"
+        );
+
+        let mut free = ptr::null_mut();
+        for index in (0..self.objects_per_slab).rev() {
+            let object = (start.as_u64() as usize + self.first_object + index * self.object_size)
+                as *mut FreeObject;
+            object.write(FreeObject { next: free });
+            free = object;
+        }
+
+        let slab = start.as_mut_ptr::<Slab>();
+        slab.write(Slab {
+            prev: ptr::null_mut(),
+            next: ptr::null_mut(),
+            free,
+            in_use: 0,
+            frame,
+        });
+
+        self.register();
+        Some(slab)
+    }
+
+    unsafe fn release_slab(&self, slab: *mut Slab) {
+        frame::deallocate_frames((*slab).frame, self.slab_pages);
+    }
+
+    /// Adds the cache to `CACHES` so it shows up in `caches()`.
+    fn register(&'static self) {
+        if self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let this = self as *const Self as *mut Self;
+        let mut head = CACHES.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match CACHES.compare_exchange_weak(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+}
+
+/// Every slab cache that is in use.
+pub fn caches() -> impl Iterator<Item = &'static RawSlabCache> {
+    let mut current = CACHES.load(Ordering::Acquire);
+    core::iter::from_fn(move || {
+        let cache = unsafe { current.as_ref()? };
+        current = cache.next.load(Ordering::Acquire);
+        Some(cache)
+    })
+}
+
+/// Shrinks every cache that is not locked right now, returns the number of
+/// pages freed. Safe to call from allocator paths since nothing here allocates.
+pub fn shrink_caches() -> usize {
+    caches()
+        .map(|cache| {
+            interrupts::without_interrupts(|| match cache.slabs.try_lock() {
+                Some(mut slabs) => cache.shrink_locked(&mut slabs),
+                None => 0,
+            })
+        })
+        .sum()
+}
+
+/// Slab cache for values of type `T`, meant to live in a `static`:
+///
+/// ```ignore
+/// static TASK_CACHE: SlabCache<Task> = SlabCache::new("task");
+/// let task = TASK_CACHE.alloc(task);
+/// ```
+pub struct SlabCache<T> {
+    raw: RawSlabCache,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> SlabCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            raw: RawSlabCache::new(name, mem::size_of::<T>(), mem::align_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Moves `value` into the cache, handing it back when out of memory.
+    pub fn alloc(&'static self, value: T) -> Result<SlabBox<T>, T> {
+        match self.raw.alloc() {
+            Some(object) => {
+                let object = object.cast::<T>();
+                unsafe { object.as_ptr().write(value) };
+                Ok(SlabBox {
+                    object,
+                    cache: self,
+                })
+            }
+            None => Err(value),
+        }
+    }
+
+    pub fn shrink(&self) -> usize {
+        self.raw.shrink()
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        self.raw.stats()
+    }
+}
+
+/// Owning pointer to an object in a `SlabCache`, like `Box` it drops the
+/// value and frees the object when dropped.
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.raw.free(self.object.cast());
+        }
+    }
+}
@@ -0,0 +1,144 @@
+pub mod symbols;
+
+use core::{arch::asm, fmt};
+
+use x86_64::VirtAddr;
+
+use crate::{memory, serial_println};
+
+/// Deepest backtrace printed, also stops loops in a corrupted frame chain.
+const MAX_FRAMES: usize = 32;
+
+/// Frame pointer of the calling function.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Frame pointer of the code an `extern "x86-interrupt"` handler interrupted.
+///
+/// # Safety
+///
+/// Must be called from the handler itself, its prologue is what saved the
+/// interrupted frame pointer.
+#[inline(always)]
+pub unsafe fn interrupted_frame_pointer() -> u64 {
+    let rbp = frame_pointer();
+    if readable(rbp) {
+        *(rbp as *const u64)
+    } else {
+        0
+    }
+}
+
+fn readable(address: u64) -> bool {
+    address != 0
+        && address.is_multiple_of(8)
+        && VirtAddr::try_new(address).is_ok()
+        && VirtAddr::try_new(address + 15).is_ok()
+        && memory::fault::is_mapped(VirtAddr::new(address))
+        && memory::fault::is_mapped(VirtAddr::new(address + 15))
+}
+
+/// Calls `f` with the return address of every frame in the chain starting at
+/// `rbp`, innermost first.
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    for _ in 0..MAX_FRAMES {
+        if !readable(rbp) {
+            return;
+        }
+
+        let (next, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (*frame, *frame.add(1))
+        };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+
+        // the stack grows down, callers always have higher frames
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+/// A code address, printed as `function+offset` when the symbol table has it
+/// and as plain hex otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    address: u64,
+    return_address: bool,
+}
+
+impl Symbol {
+    pub fn at(address: u64) -> Self {
+        Self {
+            address,
+            return_address: false,
+        }
+    }
+
+    /// For return addresses, which point after the call and so may already
+    /// be in the next function.
+    pub fn return_address(address: u64) -> Self {
+        Self {
+            address,
+            return_address: true,
+        }
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lookup_address = if self.return_address {
+            self.address.saturating_sub(1)
+        } else {
+            self.address
+        };
+
+        match symbols::lookup(lookup_address) {
+            Some((name, offset)) => {
+                write!(
+                    f,
+                    "{}+{:#x}",
+                    name,
+                    offset + (self.address - lookup_address)
+                )
+            }
+            None => write!(f, "{:#x}", self.address),
+        }
+    }
+}
+
+fn print_frame(index: usize, symbol: Symbol) {
+    serial_println!("#{} {}", index, symbol);
+}
+
+/// Prints the backtrace of code stopped at `rip` with frame pointer `rbp`,
+/// e.g. when an exception interrupted it.
+pub fn print_from(rip: u64, rbp: u64) {
+    serial_println!("Backtrace:");
+    print_frame(0, Symbol::at(rip));
+
+    let mut index = 1;
+    walk(rbp, |address| {
+        print_frame(index, Symbol::return_address(address));
+        index += 1;
+    });
+}
+
+/// Prints the backtrace of the caller.
+#[inline(never)]
+pub fn print() {
+    serial_println!("Backtrace:");
+    let mut index = 0;
+    walk(frame_pointer(), |address| {
+        print_frame(index, Symbol::return_address(address));
+        index += 1;
+    });
+}
@@ -0,0 +1,81 @@
+use core::str;
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+// Bounds of the `.ksyms` section `scripts/link.py` generates while linking
+// the kernel, sized to the table it holds.
+#[cfg(target_os = "none")]
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(target_os = "none")]
+fn table() -> Option<&'static [u8]> {
+    let table = unsafe {
+        let start = core::ptr::addr_of!(__ksyms_start);
+        let len = core::ptr::addr_of!(__ksyms_end) as usize - start as usize;
+        core::slice::from_raw_parts(start, len)
+    };
+    table.starts_with(MAGIC).then_some(table)
+}
+
+#[cfg(not(target_os = "none"))]
+fn table() -> Option<&'static [u8]> {
+    None
+}
+
+/// Whether the kernel image carries a symbol table.
+pub fn available() -> bool {
+    table().is_some()
+}
+
+struct Entry {
+    address: u64,
+    size: u64,
+    name: &'static str,
+}
+
+fn entry(table: &'static [u8], index: usize) -> Entry {
+    let strings = read_u32(table, 8) as usize;
+    let offset = HEADER_SIZE + index * ENTRY_SIZE;
+    let name_offset = strings + read_u32(table, offset + 12) as usize;
+    let name_len = read_u32(table, offset + 16) as usize;
+
+    Entry {
+        address: read_u64(table, offset),
+        size: read_u32(table, offset + 8) as u64,
+        name: str::from_utf8(&table[name_offset..name_offset + name_len]).unwrap_or("<invalid>"),
+    }
+}
+
+/// Function containing `address` and the offset of `address` into it.
+pub fn lookup(address: u64) -> Option<(&'static str, u64)> {
+    let table = table()?;
+    let count = read_u32(table, 4) as usize;
+
+    // entries are sorted by address, find the last one starting at or below
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(table, middle).address <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    let entry = entry(table, low.checked_sub(1)?);
+    let offset = address - entry.address;
+    (entry.size == 0 || offset < entry.size).then_some((entry.name, offset))
+}
@@ -1,4 +1,103 @@
+use core::{
+    arch::asm,
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+
 use futures_util::Future;
-use x86_64::instructions::port::Port;
+use x86_64::{instructions::port::Port, registers::model_specific::GsBase, VirtAddr};
 
 const IO_WAIT_PORT: u16 = 0x80;
+
+/// Locks a CPU can hold at once before the debug bookkeeping gives up.
+const MAX_HELD_LOCKS: usize = 16;
+
+static BOOT_CPU: PerCpu = PerCpu::new(0, 0);
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// A lock the CPU holds, for the lock order checks of `sync::IrqMutex`.
+#[derive(Clone, Copy)]
+pub(crate) struct HeldLock {
+    pub address: usize,
+    pub name: &'static str,
+    pub level: u8,
+}
+
+/// Data every CPU has its own copy of, reached through its GS base.
+#[repr(C)]
+pub struct PerCpu {
+    /// the address of this struct, so `current` is a single GS relative load
+    this: AtomicUsize,
+    /// index into `smp::cpus`
+    index: AtomicUsize,
+    apic_id: AtomicU8,
+    /// only touched by the owning CPU with interrupts disabled
+    held_locks: UnsafeCell<[Option<HeldLock>; MAX_HELD_LOCKS]>,
+}
+
+// every field is atomic or only used by the CPU the struct belongs to
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub const fn new(index: usize, apic_id: u8) -> Self {
+        Self {
+            this: AtomicUsize::new(0),
+            index: AtomicUsize::new(index),
+            apic_id: AtomicU8::new(apic_id),
+            held_locks: UnsafeCell::new([None; MAX_HELD_LOCKS]),
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        self.index.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u8 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    /// Updates what the BSP learns about itself once the MADT is read.
+    pub(crate) fn set_identity(&self, index: usize, apic_id: u8) {
+        self.index.store(index, Ordering::Relaxed);
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    /// Runs `f` on the locks this CPU holds. Interrupts have to be disabled.
+    pub(crate) fn with_held_locks<R>(&self, f: impl FnOnce(&mut [Option<HeldLock>]) -> R) -> R {
+        f(unsafe { &mut *self.held_locks.get() })
+    }
+}
+
+/// Points the GS base of the running CPU at `per_cpu`. Readiness is tracked
+/// for all CPUs at once, so an application processor has to call this before
+/// it takes a lock or allocates.
+pub fn install(per_cpu: &'static PerCpu) {
+    per_cpu
+        .this
+        .store(per_cpu as *const PerCpu as usize, Ordering::Relaxed);
+    GsBase::write(VirtAddr::from_ptr(per_cpu));
+    READY.store(true, Ordering::Release);
+}
+
+/// Sets up the per CPU area of the boot processor.
+pub fn init_bsp() {
+    install(&BOOT_CPU);
+}
+
+/// Per CPU data of the running CPU. Panics before `init_bsp`.
+pub fn current() -> &'static PerCpu {
+    try_current().expect("per CPU data is not set up")
+}
+
+/// Like `current` but `None` before `init_bsp`, for code that runs that early.
+pub fn try_current() -> Option<&'static PerCpu> {
+    if !READY.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let this: usize;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) this, options(nostack, preserves_flags, readonly));
+    }
+    Some(unsafe { &*(this as *const PerCpu) })
+}
@@ -1,13 +1,11 @@
-use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 use volatile::Volatile;
-use x86_64::{
-    instructions::port::Port,
-    structures::paging::{FrameAllocator, Mapper, Size4KiB},
-};
+use x86_64::{instructions::port::Port, PhysAddr};
 
 use crate::{
-    allocator::{map_physical_to_virtual_address, unmap_virtual_address},
+    interrupts::irq::{self, IrqError, IrqReturn},
+    memory::vm::{ioremap, CacheMode},
     println,
 };
 
@@ -22,6 +20,10 @@ const RTL_NETWORK_INTERFACE: (u16, u16) = (0x8139, 0x10EC);
 
 const VIRTIO_NET_VENDOR_ID_AND_DEVICE_ID: (u16, u16) = (0x1af4, 0x1000);
 
+/// ISR status register of a legacy virtio device, relative to its I/O BAR.
+/// Reading it acknowledges the interrupt.
+const VIRTIO_LEGACY_ISR_STATUS: u16 = 0x13;
+
 // pub fn get_network_device() -> Option<Box<PhyNetDevType>> {
 //     let (device_id, vendor_id) = RTL_NETWORK_INTERFACE;
 // }
@@ -33,59 +35,67 @@ pub fn get_virtio_network_device() -> Option<VirtioBlockDevice> {
     Some(VirtioBlockDevice::new(device))
 }
 
+/// Claims the interrupt line of the network device, acknowledging it through
+/// the legacy ISR status register.
+pub fn register_interrupt(device: &VirtioBlockDevice) -> Result<(), IrqError> {
+    let bar0 = device.pci_device().bars[0];
+    if bar0 & 1 == 0 {
+        log::warn!("network device has no I/O BAR, leaving its interrupt masked");
+        return Ok(());
+    }
+    let isr_status = (bar0 & !0x3) as u16 + VIRTIO_LEGACY_ISR_STATUS;
+
+    let handle = irq::register_pci_irq(device.pci_device(), "virtio-net", move |_| {
+        let status: u8 = unsafe { Port::new(isr_status).read() };
+        if status == 0 {
+            IrqReturn::NotMine
+        } else {
+            IrqReturn::Handled
+        }
+    })?;
+
+    NETWORK_INTERRUPT_NO.store(handle.vector() as usize, Ordering::Relaxed);
+    log::info!("Network interrupt on vector {}", handle.vector());
+    Ok(())
+}
+
 pub struct VirtioNetworkDevice {
     pci_device: PCIDevice,
 }
 
 impl VirtioNetworkDevice {
-    pub fn find_mmio_bar(
-        &self,
-        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-        mapper: &mut impl Mapper<Size4KiB>,
-    ) {
+    pub fn find_mmio_bar(&self) {
         for bar in self.pci_device.bars {
             if bar != 0 && bar & 6 == 0 {
-                let virtual_address: u64 = 0x_4444_4440_0000;
-
-                let offset = map_physical_to_virtual_address(
-                    bar as u64,
-                    virtual_address,
-                    frame_allocator,
-                    mapper,
-                );
-
-                let base_address = virtual_address + offset;
-                unsafe {
-                    // let ptr = (virtual_address + offset) as *mut u32;
-                    // let initial_value = *ptr;
-
-                    // *ptr = 0xFFFFFFFF;
-                    // let value = unsafe { *ptr };
-                    // log::info!(
-                    //     "inital value from bar={} {}, final value {}",
-                    //     bar,
-                    //     initial_value,
-                    //     value
-                    // );
-
-                    let magic_address = (base_address + 0x0) as *const u32;
-                    let magic = core::ptr::read_volatile(magic_address);
-
-                    // let magic = *((base_address + 0x0) as *const u32);
-
-                    let control_address = (base_address + 0x14) as *mut u32;
-                    let device_status = core::ptr::read_volatile(control_address);
-                    log::info!("Device Status: {}, magic: {}", device_status, magic);
-                    if device_status == 0 {
-                        core::ptr::write_volatile(control_address, 0x0);
-                        core::ptr::write_volatile(control_address, 0x1);
-                        core::ptr::write_volatile(control_address, 0x3);
+                let mmio = match ioremap(PhysAddr::new(bar as u64), 0x1000, CacheMode::Uncached) {
+                    Ok(mmio) => mmio,
+                    Err(err) => {
+                        log::error!("failed to map bar 0x{:x}: {}", bar, err);
+                        continue;
                     }
-                    let device_status = *((base_address + 0x14) as *const u32);
-                    log::info!("Device Status: {}", device_status);
+                };
+
+                // let initial_value = mmio.read::<u32>(0);
+                // mmio.write::<u32>(0, 0xFFFFFFFF);
+                // let value = mmio.read::<u32>(0);
+                // log::info!(
+                //     "inital value from bar={} {}, final value {}",
+                //     bar,
+                //     initial_value,
+                //     value
+                // );
+
+                let magic = mmio.read::<u32>(0x0);
+
+                let device_status = mmio.read::<u32>(0x14);
+                log::info!("Device Status: {}, magic: {}", device_status, magic);
+                if device_status == 0 {
+                    mmio.write::<u32>(0x14, 0x0);
+                    mmio.write::<u32>(0x14, 0x1);
+                    mmio.write::<u32>(0x14, 0x3);
                 }
-
-                unmap_virtual_address(virtual_address, mapper);
+                let device_status = mmio.read::<u32>(0x14);
+                log::info!("Device Status: {}", device_status);
             }
         }
     }
@@ -1,12 +1,13 @@
-use spin::Mutex;
-
 extern crate alloc;
 use alloc::vec::Vec;
 use x86_64::instructions::port::Port;
 
 use bit_field::BitField;
 
-use crate::{println, serial_println};
+use crate::{
+    println, serial_println,
+    sync::{level, IrqMutex},
+};
 
 /// Refers to the address of PCI data port in PCI config space.
 const PCI_DATA_PORT: u16 = 0xCFC;
@@ -74,6 +75,8 @@ pub enum PCIDeviceQuery {
     VendorID,
     HeaderType,
     MMIO,
+    InterruptLine,
+    InterruptPin,
 }
 
 impl PCIDeviceQuery {
@@ -92,6 +95,12 @@ impl PCIDeviceQuery {
             Self::MMIO => PCIConfigRegister::new(bus, dev, func, 0x04)
                 .read_config()
                 .get_bits(0..16) as u16,
+            Self::InterruptLine => PCIConfigRegister::new(bus, dev, func, 0x3C)
+                .read_config()
+                .get_bits(0..8) as u16,
+            Self::InterruptPin => PCIConfigRegister::new(bus, dev, func, 0x3C)
+                .read_config()
+                .get_bits(8..16) as u16,
         }
     }
 }
@@ -104,6 +113,10 @@ pub struct PCIDevice {
     pub vendor_id: u16,
     pub device_id: u16,
     pub bars: [u32; 6],
+    /// legacy IRQ the firmware routed the device to, 0xFF if none
+    pub interrupt_line: u8,
+    /// INTA# to INTD# as 1 to 4, 0 if the device uses no interrupt pin
+    pub interrupt_pin: u8,
 }
 
 impl PCIDevice {
@@ -119,6 +132,9 @@ impl PCIDevice {
             bars[idx] = config_reg.read_config();
         }
 
+        let interrupt_line = PCIDeviceQuery::InterruptLine.query(bus, dev, func) as u8;
+        let interrupt_pin = PCIDeviceQuery::InterruptPin.query(bus, dev, func) as u8;
+
         Self {
             bus,
             dev,
@@ -126,6 +142,8 @@ impl PCIDevice {
             vendor_id,
             device_id,
             bars,
+            interrupt_line,
+            interrupt_pin,
         }
     }
 
@@ -134,7 +152,8 @@ impl PCIDevice {
     }
 }
 
-pub static PCI_DEVICES: Mutex<Vec<PCIDevice>> = Mutex::new(Vec::new());
+pub static PCI_DEVICES: IrqMutex<Vec<PCIDevice>> =
+    IrqMutex::ordered("pci devices", level::PCI_DEVICES, Vec::new());
 
 pub fn search_device(vendor_id: u16, device_id: u16) -> Option<PCIDevice> {
     PCI_DEVICES
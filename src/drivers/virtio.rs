@@ -1,20 +1,29 @@
-use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, Page, PhysFrame, Translate},
-    PhysAddr, VirtAddr,
-};
+use x86_64::PhysAddr;
 
-use crate::memory::{create_mapping, BootInfoFrameAllocator};
+use crate::memory::vm::{ioremap, CacheMode, IoMapping};
 
 use super::pci::PCIDevice;
 
+/// Registers behind the first BAR that are looked at during setup.
+const BAR_MAPPING_SIZE: u64 = 0x1000;
+
 pub struct VirtioBlockDevice {
     device: PCIDevice,
     bar: u64,
+    mmio: Option<IoMapping>,
 }
 
 impl VirtioBlockDevice {
     pub fn new(device: PCIDevice) -> Self {
-        Self { device, bar: 0 }
+        Self {
+            device,
+            bar: 0,
+            mmio: None,
+        }
+    }
+
+    pub fn pci_device(&self) -> &PCIDevice {
+        &self.device
     }
 
     pub fn setup(&self) {
@@ -51,12 +60,10 @@ impl VirtioBlockDevice {
         } else {
             log::error!("unsupported type {}", typ);
         }
-        if bar != 0 {
-            unsafe {
-                log::info!("Device Status reading from bar 0x{:x}", bar);
-                let value = *(bar as *const u32);
-                log::info!("Device Status from bar 0x{:x}: 0x{:x}", bar, value);
-            }
+        if let Some(mmio) = &self.mmio {
+            log::info!("Device Status reading from bar 0x{:x}", bar);
+            let value = mmio.read::<u32>(0);
+            log::info!("Device Status from bar 0x{:x}: 0x{:x}", bar, value);
         }
 
         // unsafe {
@@ -72,32 +79,10 @@ impl VirtioBlockDevice {
         // }
     }
 
-    pub fn map_bars_to_virtual_addresses(
-        &self,
-        mapper: &mut OffsetPageTable<'static>,
-        frame_allocator: &mut BootInfoFrameAllocator,
-    ) {
-        for bar in self.device.bars {
-            if bar != 0 {
-                let virt_addr = VirtAddr::new(bar as u64);
-                let page = Page::containing_address(virt_addr);
-                if mapper.translate_addr(page.start_address()).is_some() {
-                    log::error!(
-                        "virtual address is already in use 0x{:x}",
-                        page.start_address(),
-                    );
-                    continue;
-                }
-
-                let phys_frame = PhysFrame::containing_address(PhysAddr::new(bar as u64));
-
-                create_mapping(page, phys_frame, mapper, frame_allocator);
-            }
-        }
-
+    /// Maps the memory BAR the device registers live in, see `setup`.
+    pub fn map_bars_to_virtual_addresses(&mut self) {
         let bar0 = self.device.bars[0];
         let bar1 = self.device.bars[1];
-        let bar4 = self.device.bars[4];
 
         let typ = (bar0 >> 1) & 0x3;
 
@@ -115,24 +100,13 @@ impl VirtioBlockDevice {
             log::error!("unsupported type {}", typ);
         }
         if bar != 0 {
-            let virt_addr = VirtAddr::new(bar as u64);
-            let page = Page::containing_address(virt_addr);
-            if mapper.translate_addr(page.start_address()).is_some() {
-                log::error!(
-                    "virtual address is already in use 0x{:x}",
-                    page.start_address(),
-                );
-                return;
-            }
-
-            let phys_frame = PhysFrame::containing_address(PhysAddr::new(bar as u64));
-
-            create_mapping(page, phys_frame, mapper, frame_allocator);
-
-            unsafe {
-                log::info!("Device Status reading from bar 0x{:x}", bar);
-                let value = *(bar as *const u32);
-                log::info!("Device Status from bar 0x{:x}: 0x{:x}", bar, value);
+            match ioremap(PhysAddr::new(bar), BAR_MAPPING_SIZE, CacheMode::Uncached) {
+                Ok(mmio) => {
+                    log::info!("Mapped bar 0x{:x} to {:?}", bar, mmio.addr());
+                    self.bar = bar;
+                    self.mmio = Some(mmio);
+                }
+                Err(err) => log::error!("failed to map bar 0x{:x}: {}", bar, err),
             }
         }
     }
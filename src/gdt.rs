@@ -1,8 +1,11 @@
-use core::ptr::addr_of;
+extern crate alloc;
+
+use alloc::boxed::Box;
+use core::ptr::{addr_of, addr_of_mut};
 
 use lazy_static::lazy_static;
 use x86_64::{
-    instructions::tables::load_tss,
+    instructions::{interrupts, tables::load_tss},
     registers::segmentation::{Segment, CS},
     structures::{
         gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
@@ -11,25 +14,32 @@ use x86_64::{
     VirtAddr,
 };
 
+use crate::memory::{stack::KernelStack, vm::VmError};
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+pub const NMI_IST_INDEX: u16 = 2;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 3;
+
+/// Size of the guard paged IST stacks set up by `init_stacks`.
+const IST_STACK_SIZE: u64 = 4096 * 5;
+/// Size of the static stacks used until `init_stacks` runs.
+const BOOT_IST_STACK_SIZE: usize = 4096 * 5;
+
+/// Static IST stacks so exceptions can be taken before the kernel address
+/// space exists. The double fault keeps its static stack for good, it must
+/// work even when the memory code is what broke.
+static mut BOOT_IST_STACKS: [[u8; BOOT_IST_STACK_SIZE]; 4] = [[0; BOOT_IST_STACK_SIZE]; 4];
+
+/// Mutable so IST entries can be swapped once stacks can be allocated, the CPU
+/// reads the entries from memory on every interrupt.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { addr_of!(STACK) });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
 
         (
             gdt,
@@ -47,9 +57,71 @@ struct Selectors {
 }
 
 pub fn init() {
+    let stacks = unsafe { &*addr_of!(BOOT_IST_STACKS) };
+    for (index, stack) in stacks.iter().enumerate() {
+        let stack_end = VirtAddr::from_ptr(stack) + BOOT_IST_STACK_SIZE;
+        unsafe { set_ist_entry(index as u16, stack_end) };
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// Moves the page fault, NMI and machine check handlers onto guard paged
+/// stacks, needs `memory::init` and the frame allocator.
+pub fn init_stacks() -> Result<(), VmError> {
+    let stacks = [
+        (PAGE_FAULT_IST_INDEX, "page fault stack"),
+        (NMI_IST_INDEX, "nmi stack"),
+        (MACHINE_CHECK_IST_INDEX, "machine check stack"),
+    ];
+
+    for (index, name) in stacks {
+        let stack = KernelStack::new(IST_STACK_SIZE, name)?;
+        unsafe { set_ist_entry(index, stack.leak()) };
+    }
+    Ok(())
+}
+
+/// Loads a GDT and TSS of its own on an application processor, with guard
+/// paged stacks for every IST entry. They live as long as the kernel.
+pub fn init_ap() -> Result<(), VmError> {
+    let stacks = [
+        (DOUBLE_FAULT_IST_INDEX, "ap double fault stack"),
+        (PAGE_FAULT_IST_INDEX, "ap page fault stack"),
+        (NMI_IST_INDEX, "ap nmi stack"),
+        (MACHINE_CHECK_IST_INDEX, "ap machine check stack"),
+    ];
+
+    let mut tss = TaskStateSegment::new();
+    for (index, name) in stacks {
+        tss.interrupt_stack_table[index as usize] = KernelStack::new(IST_STACK_SIZE, name)?.leak();
+    }
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let gdt = Box::leak(Box::new(GlobalDescriptorTable::new()));
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    gdt.load();
+    unsafe {
+        CS::set_reg(code_selector);
+        load_tss(tss_selector);
+    }
+    Ok(())
+}
+
+/// Points IST entry `index` of the boot processor at `stack_top`.
+///
+/// # Safety
+///
+/// The stack must stay mapped for as long as the entry refers to it, and no
+/// interrupt may be running on the stack the entry pointed at before.
+pub unsafe fn set_ist_entry(index: u16, stack_top: VirtAddr) {
+    interrupts::without_interrupts(|| {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = stack_top;
+    });
+}
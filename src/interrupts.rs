@@ -1,12 +1,21 @@
+pub mod apic;
+pub mod exceptions;
+pub mod irq;
+
 use lazy_static::lazy_static;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use x86_64::{
     instructions::port::Port,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
 use crate::{
-    gdt, hlt_loop, print, println, serial_println,
+    backtrace,
+    drivers::pci::PCIDevice,
+    gdt, hlt_loop,
+    memory::{self, fault::FaultError, vm::AreasLocked},
+    print, println, serial_println,
     vga_buffer::{console_backspace, WRITER},
 };
 use pic8259::ChainedPics;
@@ -23,9 +32,21 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Points vectors `16 * high + low` at `irq_stub`, which hands them to the
+/// handlers registered in `irq`.
+macro_rules! install_irq_stubs {
+    ($idt:ident; $($high:literal)*) => {
+        $(install_irq_stubs!(@row $idt, $high; 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15);)*
+    };
+    (@row $idt:ident, $high:literal; $($low:literal)*) => {
+        $($idt[$high * 16 + $low].set_handler_fn(irq_stub::<{ $high * 16 + $low }>);)*
+    };
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        exceptions::install(&mut idt);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         unsafe {
             idt.double_fault
@@ -33,9 +54,17 @@ lazy_static! {
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
 
+        install_irq_stubs!(idt; 2 3 4 5 6 7 8 9 10 11 12 13 14 15);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+        }
 
         idt
     };
@@ -50,12 +79,74 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Moves interrupt delivery over to the APICs when the machine has them, the
+/// 8259 PICs set up by `init` stay in use otherwise. Needs the heap.
+pub fn init_apic() {
+    // other lines are routed when a driver registers a handler for them
+    let isa_irqs = [(0, InterruptIndex::Timer.as_u8())];
+
+    if !apic::init(&isa_irqs) {
+        log::info!("Using the 8259 PICs for interrupts");
+    }
+}
+
+/// Acknowledges interrupt `vector` at whichever controller delivered it.
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
+
+/// Routes ISA `irq` to vector `PIC_1_OFFSET + irq` and unmasks it.
+pub fn enable_isa_irq(irq: u8) {
+    if apic::is_enabled() {
+        apic::route_isa_irq(irq, PIC_1_OFFSET + irq);
+    } else {
+        unmask_pic_line(irq);
+    }
+}
+
+/// Routes the legacy interrupt line of `device` and returns the vector it
+/// arrives on, the same vector with or without APICs. `None` if the firmware
+/// gave the device no line.
+pub fn enable_pci_irq(device: &PCIDevice) -> Option<u8> {
+    let line = device.interrupt_line;
+    if device.interrupt_pin == 0 || line >= 16 {
+        return None;
+    }
+    let vector = PIC_1_OFFSET + line;
+
+    if apic::is_enabled() {
+        apic::route_pci_irq(line, vector);
+    } else {
+        unmask_pic_line(line);
+    }
+    Some(vector)
+}
+
+fn unmask_pic_line(line: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = pics.read_masks();
+        if line < 8 {
+            primary &= !(1 << line);
+        } else {
+            // the secondary PIC cascades through line 2 of the primary
+            primary &= !(1 << 2);
+            secondary &= !(1 << (line - 8));
+        }
+        pics.write_masks(primary, secondary);
+    });
+}
+
 #[derive(Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
-    // Ethernet,
+    Serial = PIC_1_OFFSET + 4,
 }
 
 impl InterruptIndex {
@@ -71,58 +162,102 @@ impl InterruptIndex {
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     // println!("TIMER INTERRUPTION\n{:#?}", stack_frame);
     // print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    irq::account(InterruptIndex::Timer.as_u8());
+    crate::time::tick();
+    end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // may switch to another thread, so it comes after the end of interrupt
+    crate::thread::preempt();
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
-// extern "x86-interrupt" fn ethernet_interrupt_handler(stack_frame: InterruptStackFrame) {
-//     println!("EXCEPTION: ETHERNET\n{:#?}", stack_frame);
-//     unsafe {
-//         PICS.lock()
-//             .notify_end_of_interrupt(InterruptIndex::Ethernet.as_u8());
-//     }
-// }
 
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    use x86_64::registers::control::Cr2;
+
+    // a page fault that could not push its frame because the stack overflowed
+    report_stack_overflow(memory::stack::guard_page_owner(Cr2::read()));
+    backtrace::print_from(stack_frame.instruction_pointer.as_u64(), unsafe {
+        backtrace::interrupted_frame_pointer()
+    });
     panic!(
         "EXCEPTION: DOUBLE FAULT, ERROR CODE: {}\n{:#?}",
         error_code, stack_frame
     );
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let mut port = Port::new(0x60);
-    let mut keyboard = KEYBOARD.lock();
-    let scancode: u8 = unsafe { port.read() };
-
-    crate::task::keyboard::add_scancode(scancode);
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    serial_println!("EXCEPTION: NON MASKABLE INTERRUPT\n{:#?}", stack_frame);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+extern "x86-interrupt" fn irq_stub<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    irq::dispatch(VECTOR);
 }
 
+/// The local APIC does not expect an EOI for spurious interrupts.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     use x86_64::registers::control::Cr2;
 
+    let addr = Cr2::read();
+    match memory::fault::handle_page_fault(addr, error_code) {
+        Ok(()) => {}
+        Err(reason) => {
+            report_stack_overflow(memory::stack::overflowed_stack(
+                addr,
+                stack_frame.stack_pointer,
+            ));
+            page_fault_report(addr, error_code, reason, &stack_frame);
+            backtrace::print_from(stack_frame.instruction_pointer.as_u64(), unsafe {
+                backtrace::interrupted_frame_pointer()
+            });
+            hlt_loop();
+        }
+    }
+}
+
+fn report_stack_overflow(stack: Result<Option<&'static str>, AreasLocked>) {
+    match stack {
+        Ok(Some(stack)) => {
+            serial_println!("EXCEPTION: stack overflow in {}", stack);
+        }
+        Ok(None) => {}
+        Err(err) => {
+            serial_println!("stack overflow check skipped: {}", err);
+        }
+    }
+}
+
+/// Prints everything known about a page fault that could not be resolved.
+fn page_fault_report(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+    reason: FaultError,
+    stack_frame: &InterruptStackFrame,
+) {
+    use x86_64::registers::control::Cr3;
+
+    let (l4_frame, _) = Cr3::read();
+    let level_names = ["P4", "P3", "P2", "P1"];
+
     serial_println!("EXCEPTION: PAGE FAULT");
-    serial_println!("Accessed Address: {:?}", Cr2::read());
+    serial_println!("Accessed Address: {:?} ({})", addr, reason);
     serial_println!("Error Code: {:?}", error_code);
+    serial_println!("CR3: {:?}", l4_frame.start_address());
+    for (name, entry) in level_names.iter().zip(memory::fault::page_walk(addr)) {
+        if let Some((phys, flags)) = entry {
+            serial_println!("  {} entry: {:#x} {:?}", name, phys, flags);
+        }
+    }
     serial_println!("{:#?}", stack_frame);
-
-    hlt_loop();
 }
 
 #[test_case]
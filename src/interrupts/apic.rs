@@ -0,0 +1,278 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
+};
+
+use spin::{Mutex, Once};
+use x86_64::{instructions::interrupts, registers::model_specific::Msr};
+
+use crate::{
+    acpi::{
+        self,
+        madt::{self, Madt, Polarity, TriggerMode},
+    },
+    memory::vm::{ioremap, CacheMode, IoMapping, VmError},
+};
+
+use super::PICS;
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TASK_PRIORITY: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xB0;
+const LAPIC_SPURIOUS: u64 = 0xF0;
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Vector the local APIC delivers spurious interrupts to, its low four bits
+/// have to be set on older APICs.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// APIC id of the CPU that ran `init`, every IRQ is delivered to it.
+static BSP_APIC_ID: AtomicU8 = AtomicU8::new(0);
+static LOCAL_APIC: Once<IoMapping> = Once::new();
+static IO_APICS: Once<Vec<Mutex<IoApic>>> = Once::new();
+static MADT: Once<Madt> = Once::new();
+
+struct IoApic {
+    mmio: IoMapping,
+    gsi_base: u32,
+    redirection_entries: u32,
+}
+
+impl IoApic {
+    fn read(&mut self, register: u32) -> u32 {
+        self.mmio.write(IOAPIC_REGSEL, register);
+        self.mmio.read(IOAPIC_WINDOW)
+    }
+
+    fn write(&mut self, register: u32, value: u32) {
+        self.mmio.write(IOAPIC_REGSEL, register);
+        self.mmio.write(IOAPIC_WINDOW, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.redirection_entries
+    }
+
+    fn set_redirection(&mut self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // mask first so a half written entry never fires
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// Whether interrupts are delivered through the APICs instead of the 8259s.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn cpu_has_apic() -> bool {
+    unsafe { __cpuid(1) }.edx & (1 << 9) != 0
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the local APIC and the
+/// IOAPICs described in the ACPI MADT, routing the ISA IRQs `isa_irqs` lists
+/// as `(irq, vector)`. Leaves the PICs in charge and returns `false` when the
+/// machine has no APIC. Needs the heap.
+pub fn init(isa_irqs: &[(u8, u8)]) -> bool {
+    if !cpu_has_apic() {
+        log::info!("No local APIC, staying on the 8259 PICs");
+        return false;
+    }
+    let madt = acpi::get()
+        .and_then(|acpi| acpi.madt.clone())
+        .or_else(madt::parse);
+    let Some(madt) = madt else {
+        log::info!("No MADT, staying on the 8259 PICs");
+        return false;
+    };
+    if madt.io_apics.is_empty() {
+        log::info!("No IOAPIC, staying on the 8259 PICs");
+        return false;
+    }
+
+    if let Err(err) = map_controllers(&madt) {
+        log::error!("Failed to map the APICs, staying on the 8259 PICs: {}", err);
+        return false;
+    }
+    let madt = MADT.call_once(|| madt);
+
+    interrupts::without_interrupts(|| {
+        unsafe { PICS.lock().disable() };
+        enable_local_apic();
+        BSP_APIC_ID.store(local_apic_id(), Ordering::Relaxed);
+
+        for &(irq, vector) in isa_irqs {
+            route_isa_irq(irq, vector);
+        }
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+
+    log::info!(
+        "APIC mode enabled, local APIC id {}, {} IOAPIC(s), {} CPU(s)",
+        local_apic_id(),
+        madt.io_apics.len(),
+        madt.local_apics.len()
+    );
+    true
+}
+
+fn map_controllers(madt: &Madt) -> Result<(), VmError> {
+    let local_apic = ioremap(madt.local_apic_address, 0x1000, CacheMode::Uncached)?;
+
+    let mut io_apics = Vec::new();
+    for entry in &madt.io_apics {
+        let mut io_apic = IoApic {
+            mmio: ioremap(entry.address, 0x20, CacheMode::Uncached)?,
+            gsi_base: entry.gsi_base,
+            redirection_entries: 0,
+        };
+        io_apic.redirection_entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xFF) + 1;
+
+        for index in 0..io_apic.redirection_entries {
+            io_apic.set_redirection(entry.gsi_base + index, REDIRECTION_MASKED);
+        }
+        io_apics.push(Mutex::new(io_apic));
+    }
+
+    LOCAL_APIC.call_once(|| local_apic);
+    IO_APICS.call_once(|| io_apics);
+    Ok(())
+}
+
+fn local_apic() -> &'static IoMapping {
+    LOCAL_APIC.get().expect("local APIC is not mapped")
+}
+
+fn enable_local_apic() {
+    let mut base = Msr::new(IA32_APIC_BASE);
+    unsafe { base.write(base.read() | APIC_GLOBAL_ENABLE) };
+
+    let lapic = local_apic();
+    lapic.write::<u32>(LAPIC_TASK_PRIORITY, 0);
+    lapic.write::<u32>(
+        LAPIC_SPURIOUS,
+        LAPIC_SOFTWARE_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+}
+
+/// Enables the local APIC of an application processor, the BSP has to have
+/// run `init` already.
+pub fn init_ap() {
+    enable_local_apic();
+}
+
+pub fn local_apic_id() -> u8 {
+    (local_apic().read::<u32>(LAPIC_ID) >> 24) as u8
+}
+
+/// Signals the end of the current interrupt to the local APIC.
+pub fn end_of_interrupt() {
+    local_apic().write::<u32>(LAPIC_EOI, 0);
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    let lapic = local_apic();
+    interrupts::without_interrupts(|| {
+        lapic.write::<u32>(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+        // writing the low half sends the interrupt
+        lapic.write::<u32>(LAPIC_ICR_LOW, command);
+        while lapic.read::<u32>(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Interrupts the CPU with local APIC id `apic_id` on `vector`.
+pub fn send_fixed_ipi(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, ICR_ASSERT | vector as u32);
+}
+
+/// Puts the CPU into its wait for startup state.
+pub fn send_init_ipi(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Starts a CPU waiting for startup in real mode at `page * 4096`.
+pub fn send_startup_ipi(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/// Routes ISA `irq` to `vector` on this CPU, applying the firmware's interrupt
+/// source overrides.
+pub fn route_isa_irq(irq: u8, vector: u8) {
+    let (gsi, polarity, trigger) = match MADT.get().and_then(|madt| madt.isa_override(irq)) {
+        Some(entry) => (entry.gsi, entry.polarity, entry.trigger),
+        None => (irq as u32, Polarity::Conforming, TriggerMode::Conforming),
+    };
+
+    // ISA interrupts conform to active high, edge triggered
+    let active_low = polarity == Polarity::ActiveLow;
+    let level = trigger == TriggerMode::Level;
+    route_gsi(gsi, vector, active_low, level);
+}
+
+/// Routes a PCI interrupt line, as the firmware stored it in the device's
+/// config space, to `vector`. PCI interrupts are level triggered and active
+/// low unless an override says otherwise.
+pub fn route_pci_irq(line: u8, vector: u8) {
+    let (gsi, polarity, trigger) = match MADT.get().and_then(|madt| madt.isa_override(line)) {
+        Some(entry) => (entry.gsi, entry.polarity, entry.trigger),
+        None => (line as u32, Polarity::Conforming, TriggerMode::Conforming),
+    };
+
+    let active_low = polarity != Polarity::ActiveHigh;
+    let level = trigger != TriggerMode::Edge;
+    route_gsi(gsi, vector, active_low, level);
+}
+
+/// Points global system interrupt `gsi` at `vector` on the boot CPU and unmasks it.
+pub fn route_gsi(gsi: u32, vector: u8, active_low: bool, level: bool) {
+    let Some(io_apic) = IO_APICS
+        .get()
+        .and_then(|io_apics| io_apics.iter().find(|io_apic| io_apic.lock().handles(gsi)))
+    else {
+        log::error!("No IOAPIC handles GSI {}", gsi);
+        return;
+    };
+
+    // not the running CPU, drivers are set up from tasks on any of them
+    let mut entry = vector as u64 | (BSP_APIC_ID.load(Ordering::Relaxed) as u64) << 56;
+    if active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level {
+        entry |= REDIRECTION_LEVEL;
+    }
+
+    interrupts::without_interrupts(|| io_apic.lock().set_redirection(gsi, entry));
+}
+
+/// MADT the APICs were set up from, e.g. to list the CPUs.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.get()
+}
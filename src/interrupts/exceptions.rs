@@ -0,0 +1,403 @@
+use core::{arch::global_asm, fmt};
+
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        debug::{BreakpointCondition, DebugAddressRegisterNumber, Dr6, Dr6Flags, Dr7},
+        model_specific::{Efer, Msr},
+        mxcsr,
+        rflags::RFlags,
+    },
+    structures::idt::{InterruptDescriptorTable, SelectorErrorCode},
+    VirtAddr,
+};
+
+use crate::{
+    backtrace, gdt, print, serial::SERIAL1, serial_print, serial_println, sync, vga_buffer::WRITER,
+};
+
+const IA32_MCG_STATUS: u32 = 0x17A;
+
+/// Registers saved by `exception_common`, lowest address first. The CPU pushed
+/// everything from `rip` on, the stubs the vector and a zero error code for
+/// exceptions that have none.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+global_asm!(
+    ".global exception_common",
+    "exception_common:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // the frame is 16 byte aligned here whether or not the CPU pushed an error code
+    "mov rdi, rsp",
+    "cld",
+    "call {handler}",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // vector and error code
+    "add rsp, 16",
+    "iretq",
+    handler = sym exception_handler,
+);
+
+/// Defines an entry stub per exception that pushes what `ExceptionContext`
+/// expects and jumps to `exception_common`.
+macro_rules! exception_stubs {
+    ($($name:ident = $vector:literal, $error_code:ident;)*) => {
+        $(
+            global_asm!(
+                concat!(".global ", stringify!($name)),
+                concat!(stringify!($name), ":"),
+                exception_stubs!(@error_code $error_code),
+                concat!("push ", $vector),
+                "jmp exception_common",
+            );
+        )*
+
+        extern "C" {
+            $(fn $name();)*
+        }
+    };
+    (@error_code with_error_code) => { "" };
+    (@error_code no_error_code) => { "push 0" };
+}
+
+exception_stubs! {
+    exception_divide_error = 0, no_error_code;
+    exception_debug = 1, no_error_code;
+    exception_overflow = 4, no_error_code;
+    exception_bound_range = 5, no_error_code;
+    exception_invalid_opcode = 6, no_error_code;
+    exception_device_not_available = 7, no_error_code;
+    exception_invalid_tss = 10, with_error_code;
+    exception_segment_not_present = 11, with_error_code;
+    exception_stack_segment = 12, with_error_code;
+    exception_general_protection = 13, with_error_code;
+    exception_x87_floating_point = 16, no_error_code;
+    exception_alignment_check = 17, with_error_code;
+    exception_machine_check = 18, no_error_code;
+    exception_simd_floating_point = 19, no_error_code;
+    exception_virtualization = 20, no_error_code;
+    exception_control_protection = 21, with_error_code;
+    exception_hypervisor_injection = 28, no_error_code;
+    exception_vmm_communication = 29, with_error_code;
+    exception_security = 30, with_error_code;
+}
+
+fn stub(entry: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
+}
+
+/// Points every exception that has no handler of its own at the stubs above.
+/// Breakpoint, NMI, double fault and page fault keep their own handlers.
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(stub(exception_divide_error));
+        idt.debug.set_handler_addr(stub(exception_debug));
+        idt.overflow.set_handler_addr(stub(exception_overflow));
+        idt.bound_range_exceeded
+            .set_handler_addr(stub(exception_bound_range));
+        idt.invalid_opcode
+            .set_handler_addr(stub(exception_invalid_opcode));
+        idt.device_not_available
+            .set_handler_addr(stub(exception_device_not_available));
+        idt.invalid_tss
+            .set_handler_addr(stub(exception_invalid_tss));
+        idt.segment_not_present
+            .set_handler_addr(stub(exception_segment_not_present));
+        idt.stack_segment_fault
+            .set_handler_addr(stub(exception_stack_segment));
+        idt.general_protection_fault
+            .set_handler_addr(stub(exception_general_protection));
+        idt.x87_floating_point
+            .set_handler_addr(stub(exception_x87_floating_point));
+        idt.alignment_check
+            .set_handler_addr(stub(exception_alignment_check));
+        idt.machine_check
+            .set_handler_addr(stub(exception_machine_check))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point
+            .set_handler_addr(stub(exception_simd_floating_point));
+        idt.virtualization
+            .set_handler_addr(stub(exception_virtualization));
+        idt.cp_protection_exception
+            .set_handler_addr(stub(exception_control_protection));
+        idt.hv_injection_exception
+            .set_handler_addr(stub(exception_hypervisor_injection));
+        idt.vmm_communication_exception
+            .set_handler_addr(stub(exception_vmm_communication));
+        idt.security_exception
+            .set_handler_addr(stub(exception_security));
+    }
+}
+
+/// Mnemonic and name of exception `vector`.
+pub fn exception_name(vector: u64) -> (&'static str, &'static str) {
+    match vector {
+        0 => ("#DE", "DIVIDE ERROR"),
+        1 => ("#DB", "DEBUG"),
+        2 => ("NMI", "NON MASKABLE INTERRUPT"),
+        3 => ("#BP", "BREAKPOINT"),
+        4 => ("#OF", "OVERFLOW"),
+        5 => ("#BR", "BOUND RANGE EXCEEDED"),
+        6 => ("#UD", "INVALID OPCODE"),
+        7 => ("#NM", "DEVICE NOT AVAILABLE"),
+        8 => ("#DF", "DOUBLE FAULT"),
+        10 => ("#TS", "INVALID TSS"),
+        11 => ("#NP", "SEGMENT NOT PRESENT"),
+        12 => ("#SS", "STACK SEGMENT FAULT"),
+        13 => ("#GP", "GENERAL PROTECTION FAULT"),
+        14 => ("#PF", "PAGE FAULT"),
+        16 => ("#MF", "X87 FLOATING POINT"),
+        17 => ("#AC", "ALIGNMENT CHECK"),
+        18 => ("#MC", "MACHINE CHECK"),
+        19 => ("#XM", "SIMD FLOATING POINT"),
+        20 => ("#VE", "VIRTUALIZATION"),
+        21 => ("#CP", "CONTROL PROTECTION"),
+        28 => ("#HV", "HYPERVISOR INJECTION"),
+        29 => ("#VC", "VMM COMMUNICATION"),
+        30 => ("#SX", "SECURITY"),
+        _ => ("#??", "RESERVED"),
+    }
+}
+
+/// Error code of exception `vector` split into its fields.
+struct DecodedErrorCode {
+    vector: u64,
+    error_code: u64,
+}
+
+impl fmt::Display for DecodedErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = self.error_code;
+        write!(f, "{:#x}", code)?;
+
+        match self.vector {
+            10..=13 if code == 0 => write!(f, " (no selector)"),
+            10..=13 => {
+                let selector = SelectorErrorCode::new_truncate(code);
+                write!(
+                    f,
+                    " (selector index {} in {:?}{})",
+                    selector.index(),
+                    selector.descriptor_table(),
+                    if selector.external() {
+                        ", external"
+                    } else {
+                        ""
+                    }
+                )
+            }
+            21 => {
+                let cause = match code & 0x7FFF {
+                    1 => "near ret",
+                    2 => "far ret or iret",
+                    3 => "missing endbranch",
+                    4 => "rstorssp",
+                    5 => "setssbsy",
+                    _ => "unknown",
+                };
+                write!(
+                    f,
+                    " ({}{})",
+                    cause,
+                    if code & 1 << 15 != 0 { ", enclave" } else { "" }
+                )
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn has_error_code(vector: u64) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Writes to serial and the VGA console.
+///
+/// The exception may have hit while this CPU had either console locked. The
+/// holder is never resumed, so those locks are broken rather than waited on.
+/// Locks other CPUs hold are waited for as usual. Any other lock the CPU
+/// holds is forgotten, taking the consoles inside it would count as a lock
+/// order violation.
+fn emit(args: fmt::Arguments) {
+    sync::forget_held_locks();
+    unsafe {
+        if SERIAL1.is_held_by_current_cpu() {
+            SERIAL1.force_unlock();
+        }
+        if WRITER.is_held_by_current_cpu() {
+            WRITER.force_unlock();
+        }
+    }
+    serial_print!("{}", args);
+    print!("{}", args);
+}
+
+macro_rules! emitln {
+    () => (emit(format_args!("\n")));
+    ($($arg:tt)*) => (emit(format_args!("{}\n", format_args!($($arg)*))));
+}
+
+/// Dumps the vector, decoded error code, general purpose and control registers.
+pub fn report(context: &ExceptionContext) {
+    let (mnemonic, name) = exception_name(context.vector);
+    emitln!(
+        "EXCEPTION: {} ({}, vector {})",
+        name,
+        mnemonic,
+        context.vector
+    );
+    if has_error_code(context.vector) {
+        let error_code = DecodedErrorCode {
+            vector: context.vector,
+            error_code: context.error_code,
+        };
+        emitln!("Error Code: {}", error_code);
+    }
+
+    emitln!(
+        "RIP {:04x}:{:016x} RSP {:04x}:{:016x}",
+        context.cs,
+        context.rip,
+        context.ss,
+        context.rsp
+    );
+    emitln!(
+        "RFLAGS {:#x} {:?}",
+        context.rflags,
+        RFlags::from_bits_truncate(context.rflags)
+    );
+
+    let registers = [
+        ("RAX", context.rax),
+        ("RBX", context.rbx),
+        ("RCX", context.rcx),
+        ("RDX", context.rdx),
+        ("RSI", context.rsi),
+        ("RDI", context.rdi),
+        ("RBP", context.rbp),
+        ("R8 ", context.r8),
+        ("R9 ", context.r9),
+        ("R10", context.r10),
+        ("R11", context.r11),
+        ("R12", context.r12),
+        ("R13", context.r13),
+        ("R14", context.r14),
+        ("R15", context.r15),
+    ];
+    for row in registers.chunks(3) {
+        for (name, value) in row {
+            emit(format_args!("{} {:016x}  ", name, value));
+        }
+        emitln!();
+    }
+
+    let (l4_frame, _) = Cr3::read();
+    emitln!(
+        "CR0 {:016x} CR2 {:016x}",
+        Cr0::read_raw(),
+        Cr2::read().as_u64()
+    );
+    emitln!(
+        "CR3 {:016x} CR4 {:016x}",
+        l4_frame.start_address().as_u64(),
+        Cr4::read_raw()
+    );
+    emitln!("EFER {:?}", Efer::read());
+
+    match context.vector {
+        1 => emitln!("DR6 {:?}", Dr6::read()),
+        18 => emitln!("MCG_STATUS {:#x}", unsafe {
+            Msr::new(IA32_MCG_STATUS).read()
+        }),
+        19 => emitln!("MXCSR {:?}", mxcsr::read()),
+        _ => {}
+    }
+
+    backtrace::print_from(context.rip, context.rbp);
+}
+
+/// Whether a `#DB` came from an instruction breakpoint, which unlike the
+/// other debug exceptions is a fault raised before the instruction runs.
+fn is_instruction_breakpoint() -> bool {
+    let (dr6, dr7) = (Dr6::read(), Dr7::read());
+    (0..4).filter_map(DebugAddressRegisterNumber::new).any(|n| {
+        dr6.contains(Dr6Flags::trap(n))
+            && dr7.condition(n) == BreakpointCondition::InstructionExecution
+    })
+}
+
+extern "C" fn exception_handler(context: &mut ExceptionContext) {
+    match context.vector {
+        // hardware breakpoints and single stepping resume where they left off
+        1 => {
+            serial_println!(
+                "EXCEPTION: DEBUG at {:#x}, DR6 {:?}",
+                context.rip,
+                Dr6::read()
+            );
+            // the resume flag lets the instruction run once instead of
+            // hitting the breakpoint again
+            if is_instruction_breakpoint() {
+                context.rflags |= RFlags::RESUME_FLAG.bits();
+            }
+        }
+        vector => {
+            report(context);
+            panic!("EXCEPTION: {}", exception_name(vector).1);
+        }
+    }
+}
@@ -0,0 +1,224 @@
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::drivers::pci::PCIDevice;
+
+use super::{apic, InterruptIndex, PIC_1_OFFSET};
+
+/// First vector that is not a CPU exception.
+pub const FIRST_VECTOR: u8 = 32;
+const VECTORS: usize = 256;
+
+/// What a handler tells the dispatcher, shared lines ask every handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    /// the device of this handler did not raise the interrupt
+    NotMine,
+}
+
+/// Interrupt handler a driver registers at runtime. Runs with interrupts
+/// disabled, so it must not block or take locks that are held with
+/// interrupts enabled.
+pub trait IrqHandler: Send + Sync {
+    fn handle(&self, vector: u8) -> IrqReturn;
+}
+
+impl<F> IrqHandler for F
+where
+    F: Fn(u8) -> IrqReturn + Send + Sync,
+{
+    fn handle(&self, vector: u8) -> IrqReturn {
+        self(vector)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// the vector belongs to an exception or a handler wired into the IDT
+    Reserved,
+    /// the vector has a handler that does not share it
+    Busy,
+    /// the device has no legacy interrupt line
+    NoLine,
+}
+
+impl fmt::Display for IrqError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IrqError::Reserved => write!(f, "vector is reserved"),
+            IrqError::Busy => write!(f, "vector is in use and not shared"),
+            IrqError::NoLine => write!(f, "device has no interrupt line"),
+        }
+    }
+}
+
+/// Identifies a registration so it can be removed with `unregister`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    vector: u8,
+    id: u64,
+}
+
+impl IrqHandle {
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+}
+
+struct Registration {
+    id: u64,
+    name: &'static str,
+    shared: bool,
+    handler: Box<dyn IrqHandler>,
+}
+
+static HANDLERS: [Mutex<Vec<Registration>>; VECTORS] = [const { Mutex::new(Vec::new()) }; VECTORS];
+static COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+static UNHANDLED: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Counters and handler names of one vector, see `stats`.
+#[derive(Debug, Clone)]
+pub struct VectorStats {
+    pub vector: u8,
+    pub count: u64,
+    /// interrupts no handler claimed
+    pub unhandled: u64,
+    pub handlers: Vec<&'static str>,
+}
+
+fn is_reserved(vector: u8) -> bool {
+    vector < FIRST_VECTOR
+        || vector == InterruptIndex::Timer.as_u8()
+        || vector == apic::SPURIOUS_VECTOR
+}
+
+/// Adds `handler` to `vector`. Handlers registered with `shared` are chained
+/// and all run on every interrupt, which is what PCI INTx lines need.
+pub fn register_vector(
+    vector: u8,
+    name: &'static str,
+    shared: bool,
+    handler: impl IrqHandler + 'static,
+) -> Result<IrqHandle, IrqError> {
+    if is_reserved(vector) {
+        return Err(IrqError::Reserved);
+    }
+
+    let registration = Registration {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        name,
+        shared,
+        handler: Box::new(handler),
+    };
+    let handle = IrqHandle {
+        vector,
+        id: registration.id,
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS[vector as usize].lock();
+        if handlers.iter().any(|existing| !(existing.shared && shared)) {
+            return Err(IrqError::Busy);
+        }
+        handlers.push(registration);
+        Ok(handle)
+    })
+}
+
+/// Registers `handler` for ISA `irq` and unmasks the line.
+pub fn register_irq(
+    irq: u8,
+    name: &'static str,
+    handler: impl IrqHandler + 'static,
+) -> Result<IrqHandle, IrqError> {
+    let vector = PIC_1_OFFSET + irq;
+    let handle = register_vector(vector, name, false, handler)?;
+    super::enable_isa_irq(irq);
+    Ok(handle)
+}
+
+/// Registers a shared `handler` for the legacy interrupt line of `device`.
+pub fn register_pci_irq(
+    device: &PCIDevice,
+    name: &'static str,
+    handler: impl IrqHandler + 'static,
+) -> Result<IrqHandle, IrqError> {
+    if device.interrupt_pin == 0 || device.interrupt_line >= 16 {
+        return Err(IrqError::NoLine);
+    }
+
+    let vector = PIC_1_OFFSET + device.interrupt_line;
+    let handle = register_vector(vector, name, true, handler)?;
+    super::enable_pci_irq(device);
+    Ok(handle)
+}
+
+/// Removes a handler, returns `false` if it was already gone. The line stays
+/// unmasked, interrupts nobody claims are only counted.
+pub fn unregister(handle: IrqHandle) -> bool {
+    let removed = interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS[handle.vector as usize].lock();
+        let index = handlers.iter().position(|entry| entry.id == handle.id)?;
+        Some(handlers.remove(index))
+    });
+
+    // the handler may own resources, drop it with interrupts enabled again
+    removed.is_some()
+}
+
+/// Counts an interrupt on `vector` for handlers wired into the IDT directly.
+pub(super) fn account(vector: u8) {
+    COUNTS[vector as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Runs every handler registered for `vector` and acknowledges the interrupt.
+pub(super) fn dispatch(vector: u8) {
+    account(vector);
+
+    // no short circuit, every device sharing the line gets to look
+    let mut handled = false;
+    for entry in HANDLERS[vector as usize].lock().iter() {
+        handled |= entry.handler.handle(vector) == IrqReturn::Handled;
+    }
+    if !handled {
+        UNHANDLED[vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    super::end_of_interrupt(vector);
+}
+
+/// Vectors that fired or have handlers, in vector order.
+pub fn stats() -> Vec<VectorStats> {
+    (FIRST_VECTOR..=u8::MAX)
+        .filter_map(|vector| {
+            let handlers: Vec<&'static str> = interrupts::without_interrupts(|| {
+                HANDLERS[vector as usize]
+                    .lock()
+                    .iter()
+                    .map(|entry| entry.name)
+                    .collect()
+            });
+            let count = COUNTS[vector as usize].load(Ordering::Relaxed);
+            if count == 0 && handlers.is_empty() {
+                return None;
+            }
+
+            Some(VectorStats {
+                vector,
+                count,
+                unhandled: UNHANDLED[vector as usize].load(Ordering::Relaxed),
+                handlers,
+            })
+        })
+        .collect()
+}
@@ -14,6 +14,7 @@ use x86_64::instructions;
 
 pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
 pub mod cpu;
 pub mod drivers;
 pub mod gdt;
@@ -22,14 +23,20 @@ pub mod logging;
 pub mod memory;
 pub mod pcie;
 pub mod serial;
+pub mod smp;
+pub mod sync;
 pub mod system;
 pub mod task;
+pub mod thread;
+pub mod time;
 pub mod vga_buffer;
 
 pub fn init() {
+    cpu::init_bsp();
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
     instructions::interrupts::enable();
 }
 
@@ -79,7 +86,9 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    unsafe { serial::unlock_for_panic() };
     serial_println!("Error: {}\n", info);
+    backtrace::print();
     exit_qemu(QemuExitCode::Failed);
     hlt_loop()
 }
@@ -1,18 +1,18 @@
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
 use log::{LevelFilter, Metadata, Record};
-use spin::Mutex;
 
-use crate::serial_println;
-use lazy_static::lazy_static;
+use crate::{
+    serial, serial_println,
+    sync::{level, IrqMutex},
+    time,
+};
 extern crate alloc;
 use alloc::string::String;
 
 pub struct KernelLogger;
 
-lazy_static! {
-    pub static ref LOGS: Mutex<String> = Mutex::new(String::new());
-}
+pub static LOGS: IrqMutex<String> = IrqMutex::ordered("logs", level::LOGS, String::new());
 
 impl log::Log for KernelLogger {
     #[inline]
@@ -25,8 +25,10 @@ impl log::Log for KernelLogger {
         // let level = record.level();
 
         // if level <= LevelFilter::Trace {
+        let timestamp = Timestamp;
         serial_println!(
-            "{:8} {:5} {}",
+            "[{}] {:8} {:5} {}",
+            timestamp,
             record.target(),
             record.level(),
             record.args()
@@ -35,7 +37,8 @@ impl log::Log for KernelLogger {
         {
             let mut s = LOGS.lock();
             s.write_fmt(format_args!(
-                "{:8} {:5} {}\n",
+                "[{}] {:8} {:5} {}\n",
+                timestamp,
                 record.target(),
                 record.level(),
                 record.args()
@@ -55,7 +58,22 @@ impl log::Log for KernelLogger {
     }
 
     fn flush(&self) {
-        // TODO: Will be used in future for dmesg
+        // LOGS lives in memory, only the serial port can still be sending
+        serial::flush();
+    }
+}
+
+/// Wall clock time once the RTC has been read, followed by the precise time
+/// since boot.
+struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(now) = time::rtc::now() {
+            write!(f, "{} ", now)?;
+        }
+        let uptime = time::Instant::now().since_boot();
+        write!(f, "{:>5}.{:06}", uptime.as_secs(), uptime.subsec_micros())
     }
 }
 
@@ -20,6 +20,7 @@ use samanthi::task::executor::Executor;
 use samanthi::task::keyboard::init_memory_fs;
 use samanthi::task::simple_executor::SimpleExecutor;
 use samanthi::task::{keyboard, Task};
+use samanthi::thread::{self, Priority};
 use samanthi::vga_buffer::{
     Buffer, Color, ColorCode, ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER,
 };
@@ -44,10 +45,18 @@ fn kernal_main(boot_info: &'static BootInfo) -> ! {
     logging::init();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator =
-        unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    samanthi::smp::reserve_trampoline();
+    samanthi::gdt::init_stacks().expect("failed to allocate interrupt stacks");
+    allocator::init_heap().expect("heap initialization failed");
+    samanthi::acpi::init();
+    samanthi::interrupts::init_apic();
+    samanthi::time::init_hpet();
+    samanthi::smp::init();
+    samanthi::thread::init().expect("failed to start threads");
+    samanthi::serial::init_interrupt().expect("failed to claim the serial interrupt");
+    samanthi::time::rtc::init().expect("failed to claim the RTC interrupt");
 
     log::info!("Booted Into Samanthi");
 
@@ -71,12 +80,16 @@ fn kernal_main(boot_info: &'static BootInfo) -> ! {
     let mut executor = Executor::new();
     // executor.spawn(Task::new(example_task()));
 
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    executor.spawn(Task::new(keyboard::print_keypresses(executor.spawner())));
+    keyboard::init_interrupt().expect("failed to claim the keyboard interrupt");
     log::info!("Keyboard handler initialized");
 
-    let device = get_virtio_network_device().unwrap();
-    device.map_bars_to_virtual_addresses(&mut mapper, &mut frame_allocator);
+    let mut device = get_virtio_network_device().unwrap();
+    device.map_bars_to_virtual_addresses();
     device.setup();
+    if let Err(err) = samanthi::drivers::network::register_interrupt(&device) {
+        log::error!("failed to claim the network interrupt: {}", err);
+    }
 
     // {
     //     {
@@ -112,10 +125,14 @@ fn kernal_main(boot_info: &'static BootInfo) -> ! {
     //         }
     //     }
     // }
-    // device.find_mmio_bar(&mut frame_allocator, &mut mapper);
+    // device.find_mmio_bar();
 
     // device.is_mmio_enabled();
-    executor.run();
+
+    // the executor shares the boot CPU with kernel threads
+    thread::spawn_named("executor", Priority::Normal, move || executor.run())
+        .expect("failed to start the executor thread");
+    thread::exit();
 
     // #[cfg(test)]
     // test_main();
@@ -135,7 +152,9 @@ async fn example_task() {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    unsafe { samanthi::serial::unlock_for_panic() };
     serial_println!("Panic: {}", info);
+    samanthi::backtrace::print();
     hlt_loop()
 }
 
@@ -1,12 +1,40 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+pub mod fault;
+pub mod frame;
+pub mod stack;
+pub mod vm;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
 use x86_64::{
     structures::paging::{
-        mapper::PageTableFrameMapping, page_table::FrameError, FrameAllocator, Mapper,
-        OffsetPageTable, Page, PageTable, PhysFrame, RecursivePageTable, Size4KiB,
+        mapper::{MapToError, PageTableFrameMapping},
+        page_table::FrameError,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, RecursivePageTable, Size1GiB, Size2MiB, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+use crate::sync::{level, IrqMutex};
+
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Tracks the CPU holding it, so `try_with_mapper` can tell recursion on
+/// this CPU from another CPU mapping pages.
+static MAPPER: IrqMutex<Option<OffsetPageTable<'static>>> =
+    IrqMutex::ordered("page tables", level::UNORDERED, None);
+
+/// Returns the virtual address through which `addr` is reachable in the
+/// bootloader's complete physical memory mapping.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+/// Whether `init` has run, before that physical memory is not reachable.
+pub fn is_initialized() -> bool {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) != 0
+}
+
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     use x86_64::registers::control::Cr3;
 
@@ -38,7 +66,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 
     let mut frame = l4_frame;
 
-    for &index in &table_indices {
+    for (level, &index) in table_indices.iter().enumerate() {
         let virt = physical_memory_offset + frame.start_address().as_u64();
         let table_ptr: *const PageTable = virt.as_ptr();
 
@@ -50,17 +78,51 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
             Ok(frame) => frame,
             Err(FrameError::FrameNotPresent) => return None,
 
-            Err(FrameError::HugeFrame) => panic!("Huge pages not supported"),
+            Err(FrameError::HugeFrame) => {
+                // a huge entry in the level 3 table maps 1 GiB, in the level 2
+                // table 2 MiB. In those bit 12 is the PAT bit, and in the
+                // level 1 table bit 7 is, while the level 4 table reserves it.
+                let page_size = match level {
+                    1 => Size1GiB::SIZE,
+                    2 => Size2MiB::SIZE,
+                    3 => Size4KiB::SIZE,
+                    _ => return None,
+                };
+                return Some(
+                    entry.addr().align_down(page_size) + (addr.as_u64() & (page_size - 1)),
+                );
+            }
         }
     }
 
     Some(frame.start_address() + u64::from(addr.page_offset()))
 }
 
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+/// Sets up the kernel page table mapper, must be called before `with_mapper`.
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let l4_table = active_level_4_table(physical_memory_offset);
+    vm::init(l4_table);
+
+    *MAPPER.lock() = Some(OffsetPageTable::new(l4_table, physical_memory_offset));
+}
 
-    OffsetPageTable::new(l4_table, physical_memory_offset)
+/// Runs `f` with exclusive access to the kernel page tables.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mut mapper = MAPPER.lock();
+    f(mapper.as_mut().expect("memory::init has not been called"))
+}
+
+/// Like `with_mapper` but returns `None` instead of deadlocking when this CPU
+/// already holds the page tables further up the stack, e.g. when the heap has
+/// to grow while a mapping is being created. Waits while another CPU holds
+/// them.
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    if MAPPER.is_held_by_current_cpu() {
+        return None;
+    }
+    let mut mapper = MAPPER.lock();
+    Some(f(mapper.as_mut()?))
 }
 
 pub fn create_mapping(
@@ -85,46 +147,178 @@ pub fn create_mapping(
     );
 }
 
-pub struct EmptyFrameAllocator;
-
-unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        None
+/// Maps `size` bytes of physical memory starting at `phys_start` (e.g. a large
+/// MMIO region) to `virt_start`. 2 MiB pages are used wherever both addresses are
+/// 2 MiB aligned, the rest is mapped with 4 KiB pages.
+pub fn map_physical_region<M, A>(
+    virt_start: VirtAddr,
+    phys_start: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB>,
+{
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt_start + offset;
+        let phys = phys_start + offset;
+
+        if can_use_huge_page(virt, size - offset) && phys.is_aligned(Size2MiB::SIZE) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+                .map_err(huge_map_error)?
+                .flush();
+            offset += Size2MiB::SIZE;
+        } else {
+            let page = Page::<Size4KiB>::containing_address(virt);
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator) }?.flush();
+            offset += Size4KiB::SIZE;
+        }
     }
-}
 
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+    Ok(())
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        Self {
-            memory_map,
-            next: 0,
+/// Backs `size` bytes starting at `virt_start` with freshly allocated frames.
+/// Uses 2 MiB pages when the address is aligned and the frame allocator can hand
+/// out a 2 MiB frame, falling back to 4 KiB pages otherwise. On failure
+/// everything mapped so far is unmapped and its frames are freed again.
+pub fn map_anonymous_region<M, A>(
+    virt_start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<(), MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt_start + offset;
+        match map_anonymous_page(virt, size - offset, flags, mapper, frame_allocator) {
+            Ok(page_size) => offset += page_size,
+            Err(err) => {
+                unmap_anonymous_region(virt_start, offset, mapper, frame_allocator);
+                return Err(err);
+            }
         }
     }
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
+    Ok(())
+}
 
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+/// Maps one fresh page at `virt`, 2 MiB if possible, and returns its size. The
+/// frame goes back to the allocator if it could not be mapped.
+fn map_anonymous_page<M, A>(
+    virt: VirtAddr,
+    remaining: u64,
+    flags: PageTableFlags,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameAllocator<Size4KiB>
+        + FrameAllocator<Size2MiB>
+        + FrameDeallocator<Size4KiB>
+        + FrameDeallocator<Size2MiB>,
+{
+    if can_use_huge_page(virt, remaining) {
+        let huge_frame: Option<PhysFrame<Size2MiB>> = frame_allocator.allocate_frame();
+        if let Some(frame) = huge_frame {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            return match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(Size2MiB::SIZE)
+                }
+                Err(err) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(huge_map_error(err))
+                }
+            };
+        }
+    }
 
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let frame: PhysFrame<Size4KiB> = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(Size4KiB::SIZE)
+        }
+        Err(err) => {
+            unsafe { frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
 
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+/// Unmaps the first `size` bytes `map_anonymous_region` mapped and frees their
+/// frames. Where a 2 MiB page could have been used one is tried first, a 2 MiB
+/// unmap fails on a range mapped with 4 KiB pages.
+fn unmap_anonymous_region<M, A>(
+    virt_start: VirtAddr,
+    size: u64,
+    mapper: &mut M,
+    frame_allocator: &mut A,
+) where
+    M: Mapper<Size4KiB> + Mapper<Size2MiB>,
+    A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB>,
+{
+    let mut offset = 0;
+    while offset < size {
+        let virt = virt_start + offset;
+        if can_use_huge_page(virt, size - offset) {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            if let Ok((frame, flush)) = Mapper::<Size2MiB>::unmap(mapper, page) {
+                flush.flush();
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                offset += Size2MiB::SIZE;
+                continue;
+            }
+        }
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        let page = Page::<Size4KiB>::containing_address(virt);
+        if let Ok((frame, flush)) = Mapper::<Size4KiB>::unmap(mapper, page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+        offset += Size4KiB::SIZE;
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
+fn can_use_huge_page(virt: VirtAddr, remaining: u64) -> bool {
+    virt.is_aligned(Size2MiB::SIZE) && remaining >= Size2MiB::SIZE
+}
+
+fn huge_map_error(err: MapToError<Size2MiB>) -> MapToError<Size4KiB> {
+    match err {
+        MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+        MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+        MapToError::PageAlreadyMapped(frame) => {
+            MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+        }
+    }
+}
 
-        self.next += 1;
+pub struct EmptyFrameAllocator;
 
-        frame
+unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        None
     }
 }
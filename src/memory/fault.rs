@@ -0,0 +1,269 @@
+use core::fmt;
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{MapToError, UnmapError},
+            page_table::FrameError,
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTable, PageTableFlags,
+            PhysFrame, Size4KiB,
+        },
+    },
+    VirtAddr,
+};
+
+use super::{frame::GlobalFrameAllocator, phys_to_virt};
+
+const MAX_LAZY_REGIONS: usize = 32;
+
+static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+/// Virtual address range that is backed by zeroed frames on first touch
+/// instead of up front.
+#[derive(Debug, Clone, Copy)]
+pub struct LazyRegion {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    pub flags: PageTableFlags,
+    /// pages that have been faulted in so far
+    pub resident: usize,
+}
+
+impl LazyRegion {
+    fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    Unaligned,
+    Overlapping,
+    TooManyRegions,
+    NotFound,
+}
+
+/// Why a page fault could not be resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    /// the address is not inside any lazily backed region
+    NotLazy,
+    /// the page is present, so this is an access rights violation
+    ProtectionViolation,
+    /// write to a region that is mapped read only
+    ReadOnly(&'static str),
+    /// instruction fetch from a region that is not executable
+    NoExecute(&'static str),
+    OutOfMemory(&'static str),
+    /// the page tables were locked by the code that faulted, on this CPU
+    MapperBusy,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::NotLazy => write!(f, "address is not mapped"),
+            FaultError::ProtectionViolation => write!(f, "protection violation"),
+            FaultError::ReadOnly(name) => write!(f, "write to read only region {}", name),
+            FaultError::NoExecute(name) => write!(f, "execution in no-execute region {}", name),
+            FaultError::OutOfMemory(name) => write!(f, "no free frame to back region {}", name),
+            FaultError::MapperBusy => write!(f, "page tables locked while faulting"),
+        }
+    }
+}
+
+/// Registers `size` bytes at `start` as lazily backed: nothing is mapped now,
+/// the page fault handler maps a zeroed frame with `flags` on first access.
+pub fn register_lazy_region(
+    name: &'static str,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), RegionError> {
+    if !start.is_aligned(Size4KiB::SIZE) || !size.is_multiple_of(Size4KiB::SIZE) {
+        return Err(RegionError::Unaligned);
+    }
+    let region = LazyRegion {
+        name,
+        start,
+        end: start + size,
+        flags: flags | PageTableFlags::PRESENT,
+        resident: 0,
+    };
+
+    interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        if regions
+            .iter()
+            .flatten()
+            .any(|other| region.start < other.end && other.start < region.end)
+        {
+            return Err(RegionError::Overlapping);
+        }
+
+        let slot = regions
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(RegionError::TooManyRegions)?;
+        *slot = Some(region);
+        Ok(())
+    })
+}
+
+/// Removes the lazy region starting at `start`, unmapping and freeing every
+/// page that was faulted in.
+pub fn unregister_lazy_region(start: VirtAddr) -> Result<(), RegionError> {
+    let region = interrupts::without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        regions
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|region| region.start == start))
+            .and_then(|slot| slot.take())
+            .ok_or(RegionError::NotFound)
+    })?;
+
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(region.start),
+        Page::containing_address(region.end),
+    );
+    super::with_mapper(|mapper| {
+        for page in pages {
+            match mapper.unmap(page) {
+                Ok((frame, flush)) => {
+                    flush.flush();
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                }
+                Err(UnmapError::PageNotMapped) => {}
+                Err(err) => panic!("failed to unmap lazy page {:?}: {:?}", page, err),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Copies out the registered lazy regions, e.g. to print them.
+pub fn lazy_regions() -> [Option<LazyRegion>; MAX_LAZY_REGIONS] {
+    interrupts::without_interrupts(|| *LAZY_REGIONS.lock())
+}
+
+/// Tries to resolve a page fault at `addr` by backing it with a zeroed frame.
+///
+/// Called from the page fault handler, so nothing in here may allocate.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    let mut regions = LAZY_REGIONS.lock();
+    let region = regions
+        .iter_mut()
+        .flatten()
+        .find(|region| region.contains(addr))
+        .ok_or(FaultError::NotLazy)?;
+
+    if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+        && !region.flags.contains(PageTableFlags::WRITABLE)
+    {
+        return Err(FaultError::ReadOnly(region.name));
+    }
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH)
+        && region.flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        return Err(FaultError::NoExecute(region.name));
+    }
+
+    let frame: PhysFrame<Size4KiB> = GlobalFrameAllocator
+        .allocate_frame()
+        .ok_or(FaultError::OutOfMemory(region.name))?;
+    unsafe {
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, Size4KiB::SIZE as usize)
+    };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let flags = region.flags;
+    let result = super::try_with_mapper(|mapper| unsafe {
+        mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)
+    });
+
+    match result {
+        Some(Ok(flush)) => {
+            flush.flush();
+            region.resident += 1;
+            Ok(())
+        }
+        // another CPU faulted on the same page first
+        Some(Err(MapToError::PageAlreadyMapped(_))) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Ok(())
+        }
+        Some(Err(MapToError::FrameAllocationFailed)) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Err(FaultError::OutOfMemory(region.name))
+        }
+        Some(Err(MapToError::ParentEntryHugePage)) => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Err(FaultError::ProtectionViolation)
+        }
+        // only when this CPU faulted while mapping, another CPU holding the
+        // page tables is waited for
+        None => {
+            unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+            Err(FaultError::MapperBusy)
+        }
+    }
+}
+
+/// Page table entries used to translate `addr`, from the level 4 table down
+/// to the entry that maps it or the first one that is not present.
+pub fn page_walk(addr: VirtAddr) -> [Option<(u64, PageTableFlags)>; 4] {
+    let mut entries = [None; 4];
+    let indices = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let (mut frame, _) = Cr3::read();
+
+    for (level, &index) in indices.iter().enumerate() {
+        let table = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<PageTable>() };
+        let entry = &table[index];
+        entries[level] = Some((entry.addr().as_u64(), entry.flags()));
+
+        frame = match entry.frame() {
+            Ok(frame) => frame,
+            Err(FrameError::FrameNotPresent) | Err(FrameError::HugeFrame) => break,
+        };
+    }
+
+    entries
+}
+
+/// Whether `addr` is mapped right now, without taking the mapper lock so it
+/// works from fault handlers. Addresses in lazy regions only count once touched.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    if !super::is_initialized() {
+        return false;
+    }
+
+    for (level, entry) in page_walk(addr).iter().enumerate() {
+        match entry {
+            Some((_, flags)) if !flags.contains(PageTableFlags::PRESENT) => return false,
+            Some((_, flags)) if level == 3 || flags.contains(PageTableFlags::HUGE_PAGE) => {
+                return true
+            }
+            Some(_) => {}
+            None => return false,
+        }
+    }
+    false
+}
@@ -0,0 +1,362 @@
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+const FRAMES_PER_HUGE_FRAME: usize = (Size2MiB::SIZE / FRAME_SIZE) as usize;
+
+pub static FRAME_ALLOCATOR: Mutex<Option<BitmapFrameAllocator>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Physical memory manager with one bit per 4 KiB frame, a set bit means the
+/// frame is either allocated or not usable RAM at all.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    usable_frames: usize,
+    free_frames: usize,
+    /// word index to start searching from, everything below it is in use
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the bitmap from the bootloader memory map. The bitmap itself is
+    /// placed in the first usable region large enough to hold it.
+    ///
+    /// # Safety
+    ///
+    /// The memory map has to be correct and all of physical memory has to be
+    /// mapped at `physical_memory_offset`. The usable regions must not be in
+    /// use by anything else.
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        let max_frame = usable_regions()
+            .map(|r| r.range.end_addr() / FRAME_SIZE)
+            .max()
+            .unwrap_or(0) as usize;
+
+        let bitmap_words = max_frame.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (bitmap_words * core::mem::size_of::<u64>()) as u64;
+
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_bytes)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, bitmap_words);
+
+        let mut allocator = Self::from_bitmap(bitmap);
+
+        for region in usable_regions() {
+            let start = (region.range.start_addr() / FRAME_SIZE) as usize;
+            let end = (region.range.end_addr() / FRAME_SIZE) as usize;
+            allocator.mark_free(start, end - start);
+        }
+
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE) as usize;
+        allocator.mark_used((bitmap_start / FRAME_SIZE) as usize, bitmap_frames);
+        allocator.usable_frames -= bitmap_frames;
+
+        allocator
+    }
+
+    /// Creates an allocator over `bitmap` with every frame marked as in use.
+    unsafe fn from_bitmap(bitmap: &'static mut [u64]) -> Self {
+        bitmap.fill(!0);
+
+        Self {
+            bitmap,
+            usable_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        }
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        let word = &mut self.bitmap[index / BITS_PER_WORD];
+        let bit = 1 << (index % BITS_PER_WORD);
+        if used {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+
+    fn mark_free(&mut self, start: usize, count: usize) {
+        for index in start..start + count {
+            if self.is_used(index) {
+                self.set_used(index, false);
+                self.usable_frames += 1;
+                self.free_frames += 1;
+            }
+        }
+        self.next_word = self.next_word.min(start / BITS_PER_WORD);
+    }
+
+    fn mark_used(&mut self, start: usize, count: usize) {
+        for index in start..start + count {
+            if !self.is_used(index) {
+                self.set_used(index, true);
+                self.free_frames -= 1;
+            }
+        }
+    }
+
+    fn frame_count(&self) -> usize {
+        self.bitmap.len() * BITS_PER_WORD
+    }
+
+    /// Allocates `count` physically contiguous frames whose first frame number
+    /// is a multiple of `align` frames, e.g. for DMA rings.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrame> {
+        if count == 0 || self.free_frames < count {
+            return None;
+        }
+        let align = align.max(1);
+
+        let mut start = self.next_word * BITS_PER_WORD;
+        start = start.div_ceil(align) * align;
+
+        while start + count <= self.frame_count() {
+            match (start..start + count).rev().find(|&i| self.is_used(i)) {
+                Some(used) => start = (used + 1).div_ceil(align) * align,
+                None => {
+                    self.mark_used(start, count);
+                    let addr = PhysAddr::new(start as u64 * FRAME_SIZE);
+                    return Some(PhysFrame::containing_address(addr));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Allocates a single frame that ends below `limit`, e.g. for code that
+    /// runs in real mode. Frame 0 is never handed out.
+    pub fn allocate_below(&mut self, limit: PhysAddr) -> Option<PhysFrame> {
+        let end = ((limit.as_u64() / FRAME_SIZE) as usize).min(self.frame_count());
+        let index = (1..end).find(|&index| !self.is_used(index))?;
+        self.mark_used(index, 1);
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index as u64 * FRAME_SIZE,
+        )))
+    }
+
+    /// Returns `count` frames starting at `start` to the allocator.
+    ///
+    /// Panics on double frees.
+    ///
+    /// # Safety
+    ///
+    /// The frames must have been handed out by this allocator and nothing may
+    /// use them afterwards.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, count: usize) {
+        let first = (start.start_address().as_u64() / FRAME_SIZE) as usize;
+
+        for index in first..first + count {
+            assert!(
+                index < self.frame_count() && self.is_used(index),
+                "double free of physical frame 0x{:x}",
+                index as u64 * FRAME_SIZE
+            );
+        }
+        self.mark_free(first, count);
+        // mark_free counts frames as newly usable, they already were
+        self.usable_frames -= count;
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.usable_frames,
+            used: self.usable_frames - self.free_frames,
+            free: self.free_frames,
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let word_index = (self.next_word..self.bitmap.len()).find(|&i| self.bitmap[i] != !0)?;
+        self.next_word = word_index;
+
+        let bit = self.bitmap[word_index].trailing_ones() as usize;
+        let index = word_index * BITS_PER_WORD + bit;
+        self.mark_used(index, 1);
+
+        Some(PhysFrame::containing_address(PhysAddr::new(
+            index as u64 * FRAME_SIZE,
+        )))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate_contiguous(frame, 1);
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_contiguous(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(start, FRAMES_PER_HUGE_FRAME);
+    }
+}
+
+/// Handle to the kernel wide `FRAME_ALLOCATOR`, usable wherever a
+/// `FrameAllocator` is expected.
+#[derive(Clone, Copy)]
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        with_frame_allocator(|allocator| allocator.allocate_frame())
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        with_frame_allocator(|allocator| allocator.deallocate_frame(frame))
+    }
+}
+
+fn with_frame_allocator<R>(f: impl FnOnce(&mut BitmapFrameAllocator) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        f(allocator
+            .as_mut()
+            .expect("frame allocator is not initialized"))
+    })
+}
+
+/// Sets up the kernel frame allocator, must be called once before any other
+/// function in this module.
+///
+/// # Safety
+///
+/// Same as `BitmapFrameAllocator::init`, and it may only be called once.
+pub unsafe fn init(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+) -> GlobalFrameAllocator {
+    let allocator = BitmapFrameAllocator::init(memory_map, physical_memory_offset);
+    let stats = allocator.stats();
+
+    interrupts::without_interrupts(|| {
+        *FRAME_ALLOCATOR.lock() = Some(allocator);
+    });
+
+    log::info!(
+        "Frame allocator initialized, {} of {} frames free",
+        stats.free,
+        stats.total
+    );
+
+    GlobalFrameAllocator
+}
+
+pub fn allocate_frames(count: usize) -> Option<PhysFrame> {
+    with_frame_allocator(|allocator| allocator.allocate_contiguous(count, 1))
+}
+
+pub fn allocate_frames_aligned(count: usize, align: usize) -> Option<PhysFrame> {
+    with_frame_allocator(|allocator| allocator.allocate_contiguous(count, align))
+}
+
+pub fn allocate_frame_below(limit: PhysAddr) -> Option<PhysFrame> {
+    with_frame_allocator(|allocator| allocator.allocate_below(limit))
+}
+
+/// Returns frames to the kernel frame allocator.
+///
+/// # Safety
+///
+/// Same as `BitmapFrameAllocator::deallocate_contiguous`.
+pub unsafe fn deallocate_frames(start: PhysFrame, count: usize) {
+    with_frame_allocator(|allocator| allocator.deallocate_contiguous(start, count))
+}
+
+pub fn stats() -> FrameStats {
+    with_frame_allocator(|allocator| allocator.stats())
+}
+
+#[test_case]
+fn test_frame_reuse() {
+    static mut BITMAP: [u64; 4] = [0; 4];
+    let mut allocator =
+        unsafe { BitmapFrameAllocator::from_bitmap(&mut *core::ptr::addr_of_mut!(BITMAP)) };
+    allocator.mark_free(10, 100);
+
+    let first: PhysFrame = allocator.allocate_frame().unwrap();
+    assert_eq!(first.start_address().as_u64(), 10 * FRAME_SIZE);
+    assert_eq!(allocator.stats().used, 1);
+
+    unsafe { allocator.deallocate_frame(first) };
+    assert_eq!(allocator.stats().free, 100);
+    assert_eq!(
+        FrameAllocator::<Size4KiB>::allocate_frame(&mut allocator),
+        Some(first)
+    );
+}
+
+#[test_case]
+fn test_contiguous_allocation() {
+    static mut BITMAP: [u64; 4] = [0; 4];
+    let mut allocator =
+        unsafe { BitmapFrameAllocator::from_bitmap(&mut *core::ptr::addr_of_mut!(BITMAP)) };
+    allocator.mark_free(3, 200);
+
+    let single: PhysFrame = allocator.allocate_frame().unwrap();
+    let run = allocator.allocate_contiguous(16, 16).unwrap();
+    assert_eq!(run.start_address().as_u64(), 16 * FRAME_SIZE);
+    assert_eq!(allocator.stats().used, 17);
+
+    assert!(allocator.allocate_contiguous(201, 1).is_none());
+
+    unsafe { allocator.deallocate_contiguous(run, 16) };
+    unsafe { allocator.deallocate_frame(single) };
+    assert_eq!(allocator.stats().free, 200);
+    assert!(allocator.allocate_contiguous(200, 1).is_some());
+}
@@ -0,0 +1,91 @@
+use core::mem;
+
+use x86_64::{
+    structures::paging::{PageSize, Size4KiB},
+    VirtAddr,
+};
+
+use super::vm::{self, AreaKind, AreasLocked, VmError, VmMapping};
+
+/// Unmapped page directly below every kernel stack, `vm` never hands out the
+/// page in front of an area.
+pub const GUARD_PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Stack mapped with fresh frames and an unmapped guard page beneath it, so an
+/// overflow faults in the guard page instead of overwriting other memory.
+pub struct KernelStack {
+    mapping: VmMapping,
+    name: &'static str,
+}
+
+impl KernelStack {
+    pub fn new(size: u64, name: &'static str) -> Result<Self, VmError> {
+        Ok(Self {
+            mapping: vm::alloc_stack(size, name)?,
+            name,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Initial stack pointer, stacks grow down from here.
+    pub fn top(&self) -> VirtAddr {
+        self.mapping.end().align_down(16u64)
+    }
+
+    pub fn bottom(&self) -> VirtAddr {
+        self.mapping.start()
+    }
+
+    /// Keeps the stack mapped forever and returns its top, for stacks that live
+    /// as long as the kernel such as IST stacks.
+    pub fn leak(self) -> VirtAddr {
+        let top = self.top();
+        mem::forget(self);
+        top
+    }
+}
+
+// The lookups below run in exception handlers, so they fail instead of
+// waiting when the areas are locked.
+
+/// Name of the kernel stack whose guard page contains `addr`, if any.
+pub fn guard_page_owner(addr: VirtAddr) -> Result<Option<&'static str>, AreasLocked> {
+    let area = vm::try_find_area(|area| {
+        area.kind == AreaKind::Stack
+            && area.start.as_u64() - GUARD_PAGE_SIZE <= addr.as_u64()
+            && addr < area.start
+    })?;
+    Ok(area.map(|area| area.name))
+}
+
+/// Name of the kernel stack `addr` lies in, if any.
+pub fn stack_containing(addr: VirtAddr) -> Result<Option<&'static str>, AreasLocked> {
+    let area = vm::try_find_area(|area| {
+        area.kind == AreaKind::Stack && area.start <= addr && addr < area.end()
+    })?;
+    Ok(area.map(|area| area.name))
+}
+
+/// Works out whether a fault at `addr` with the stack pointer at `stack_pointer`
+/// is a stack overflow, and if so in which stack. Overflows of the boot stack,
+/// which is not allocated here, are recognized by the fault landing within a
+/// page of the stack pointer.
+pub fn overflowed_stack(
+    addr: VirtAddr,
+    stack_pointer: VirtAddr,
+) -> Result<Option<&'static str>, AreasLocked> {
+    if let Some(name) = guard_page_owner(addr)? {
+        return Ok(Some(name));
+    }
+
+    let (fault, sp) = (addr.as_u64(), stack_pointer.as_u64());
+    if fault.saturating_add(GUARD_PAGE_SIZE) >= sp && fault < sp.saturating_add(GUARD_PAGE_SIZE) {
+        return Ok(Some(
+            stack_containing(stack_pointer)?.unwrap_or("boot stack"),
+        ));
+    }
+    Ok(None)
+}
@@ -0,0 +1,372 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{frame::GlobalFrameAllocator, map_anonymous_region, map_physical_region};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+/// Unmapped gap kept below and above every area, so running off the end of a
+/// stack or buffer faults instead of hitting the neighbouring area.
+const GUARD_SIZE: u64 = PAGE_SIZE;
+/// One level 4 entry worth of address space is handed out.
+const WINDOW_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+const MAX_AREAS: usize = 128;
+
+static WINDOW_START: AtomicU64 = AtomicU64::new(0);
+static AREAS: Mutex<[Option<VirtualArea>; MAX_AREAS]> = Mutex::new([None; MAX_AREAS]);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaKind {
+    Heap,
+    Mmio,
+    Stack,
+    Anonymous,
+}
+
+/// A range of kernel virtual address space handed out by `reserve`.
+#[derive(Debug, Clone, Copy)]
+pub struct VirtualArea {
+    pub name: &'static str,
+    pub kind: AreaKind,
+    pub start: VirtAddr,
+    pub size: u64,
+}
+
+impl VirtualArea {
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    /// no free range of the requested size is left
+    OutOfAddressSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for VmError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        VmError::Map(err)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::OutOfAddressSpace => write!(f, "out of kernel address space"),
+            VmError::Map(err) => write!(f, "mapping failed: {:?}", err),
+        }
+    }
+}
+
+/// The areas are locked, e.g. because a fault hit the code holding them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AreasLocked;
+
+impl fmt::Display for AreasLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "areas unavailable")
+    }
+}
+
+/// Caching behaviour of an `ioremap` mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    /// for device registers, the default for MMIO
+    Uncached,
+}
+
+impl CacheMode {
+    fn flags(self) -> PageTableFlags {
+        match self {
+            CacheMode::WriteBack => PageTableFlags::empty(),
+            CacheMode::WriteThrough => PageTableFlags::WRITE_THROUGH,
+            CacheMode::Uncached => PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Picks the first unused level 4 entry in the lower half as the window
+/// areas are allocated from. Called by `memory::init`.
+pub(super) fn init(l4_table: &PageTable) {
+    let index = (1..256)
+        .find(|&index| l4_table[index].is_unused())
+        .expect("no free level 4 entry for kernel address space");
+
+    WINDOW_START.store(index as u64 * WINDOW_SIZE, Ordering::Relaxed);
+    log::info!(
+        "Kernel address space window at 0x{:x}",
+        index as u64 * WINDOW_SIZE
+    );
+}
+
+/// Reserves `size` bytes of address space aligned to `align`, without mapping
+/// anything. Prefer the handle based `vmalloc`, `ioremap` and `alloc_stack`.
+pub fn reserve(
+    size: u64,
+    align: u64,
+    kind: AreaKind,
+    name: &'static str,
+) -> Result<VirtAddr, VmError> {
+    let window_start = WINDOW_START.load(Ordering::Relaxed);
+    assert!(window_start != 0, "memory::init has not been called");
+
+    let size = align_up(size.max(1), PAGE_SIZE);
+    let align = align.max(PAGE_SIZE);
+    let window_end = window_start + WINDOW_SIZE;
+
+    interrupts::without_interrupts(|| {
+        let mut areas = AREAS.lock();
+        let mut start = align_up(window_start + GUARD_SIZE, align);
+
+        // first fit, move past every area the candidate range collides with
+        while let Some(area) = areas.iter().flatten().find(|area| {
+            start < area.end().as_u64() + GUARD_SIZE
+                && area.start.as_u64() < start + size + GUARD_SIZE
+        }) {
+            start = align_up(area.end().as_u64() + GUARD_SIZE, align);
+        }
+
+        if start + size + GUARD_SIZE > window_end {
+            return Err(VmError::OutOfAddressSpace);
+        }
+
+        let slot = areas
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(VmError::OutOfAddressSpace)?;
+        *slot = Some(VirtualArea {
+            name,
+            kind,
+            start: VirtAddr::new(start),
+            size,
+        });
+        Ok(VirtAddr::new(start))
+    })
+}
+
+/// Gives the address space of the area starting at `start` back. Whatever is
+/// still mapped in it stays mapped, the handles unmap before releasing.
+pub fn release(start: VirtAddr) -> Option<VirtualArea> {
+    interrupts::without_interrupts(|| {
+        AREAS
+            .lock()
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|area| area.start == start))
+            .and_then(|slot| slot.take())
+    })
+}
+
+/// Copies out every reserved area, e.g. to print them.
+pub fn areas() -> [Option<VirtualArea>; MAX_AREAS] {
+    interrupts::without_interrupts(|| *AREAS.lock())
+}
+
+/// First reserved area matching `predicate`, for exception handlers: never
+/// waits for the lock and only copies the area it finds.
+pub fn try_find_area(
+    predicate: impl Fn(&VirtualArea) -> bool,
+) -> Result<Option<VirtualArea>, AreasLocked> {
+    interrupts::without_interrupts(|| {
+        let areas = AREAS.try_lock().ok_or(AreasLocked)?;
+        Ok(areas.iter().flatten().find(|area| predicate(area)).copied())
+    })
+}
+
+/// Maps `len` bytes of physical memory at `phys` (usually device registers)
+/// into kernel address space. The mapping is removed when the handle drops.
+pub fn ioremap(phys: PhysAddr, len: u64, cache: CacheMode) -> Result<IoMapping, VmError> {
+    let phys_start = phys.align_down(PAGE_SIZE);
+    let offset = phys - phys_start;
+    let size = align_up(offset + len, PAGE_SIZE);
+
+    let start = reserve(size, PAGE_SIZE, AreaKind::Mmio, "mmio")?;
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_EXECUTE
+        | cache.flags();
+
+    let result = super::with_mapper(|mapper| {
+        map_physical_region(
+            start,
+            phys_start,
+            size,
+            flags,
+            mapper,
+            &mut GlobalFrameAllocator,
+        )
+    });
+    let mapping = IoMapping {
+        start,
+        size,
+        offset,
+        phys,
+    };
+
+    // dropping the handle cleans up whatever part got mapped
+    result.map(|_| mapping).map_err(VmError::from)
+}
+
+/// Device memory mapped by `ioremap`.
+pub struct IoMapping {
+    start: VirtAddr,
+    size: u64,
+    /// offset of `phys` into the first mapped page
+    offset: u64,
+    phys: PhysAddr,
+}
+
+impl IoMapping {
+    /// Virtual address of the physical address passed to `ioremap`.
+    pub fn addr(&self) -> VirtAddr {
+        self.start + self.offset
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Bytes usable from `addr()` on.
+    pub fn len(&self) -> u64 {
+        self.size - self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Volatile read of a register at `offset` bytes from `addr()`.
+    pub fn read<T: Copy>(&self, offset: u64) -> T {
+        assert!(offset + core::mem::size_of::<T>() as u64 <= self.len());
+        unsafe { core::ptr::read_volatile((self.addr() + offset).as_ptr()) }
+    }
+
+    /// Volatile write of a register at `offset` bytes from `addr()`.
+    pub fn write<T: Copy>(&self, offset: u64, value: T) {
+        assert!(offset + core::mem::size_of::<T>() as u64 <= self.len());
+        unsafe { core::ptr::write_volatile((self.addr() + offset).as_mut_ptr(), value) }
+    }
+}
+
+impl Drop for IoMapping {
+    fn drop(&mut self) {
+        unmap_range(self.start, self.size, false);
+        release(self.start);
+    }
+}
+
+/// Maps `size` bytes of fresh memory into kernel address space, the memory is
+/// unmapped and its frames freed when the handle drops.
+pub fn vmalloc(size: u64, name: &'static str) -> Result<VmMapping, VmError> {
+    map_new(size, AreaKind::Anonymous, name)
+}
+
+/// Allocates a kernel stack. The guard gap below the area stays unmapped, so
+/// an overflow faults instead of corrupting whatever lies below.
+pub fn alloc_stack(size: u64, name: &'static str) -> Result<VmMapping, VmError> {
+    map_new(size, AreaKind::Stack, name)
+}
+
+fn map_new(size: u64, kind: AreaKind, name: &'static str) -> Result<VmMapping, VmError> {
+    let size = align_up(size, PAGE_SIZE);
+    let align = if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        PAGE_SIZE
+    };
+    let start = reserve(size, align, kind, name)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let result = super::with_mapper(|mapper| {
+        map_anonymous_region(start, size, flags, mapper, &mut GlobalFrameAllocator)
+    });
+    let mapping = VmMapping { start, size };
+
+    result.map(|_| mapping).map_err(VmError::from)
+}
+
+/// Memory mapped by `vmalloc` or `alloc_stack`.
+pub struct VmMapping {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VmMapping {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    /// First address past the mapping, the initial stack pointer for stacks.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.start.as_mut_ptr()
+    }
+}
+
+impl Drop for VmMapping {
+    fn drop(&mut self) {
+        unmap_range(self.start, self.size, true);
+        release(self.start);
+    }
+}
+
+/// Unmaps every page in the range, 2 MiB pages included, and optionally gives
+/// the frames back to the frame allocator.
+fn unmap_range(start: VirtAddr, size: u64, free_frames: bool) {
+    super::with_mapper(|mapper| {
+        let mut offset = 0;
+        while offset < size {
+            let addr = start + offset;
+            offset += match mapper.translate(addr) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(_),
+                    ..
+                } => {
+                    let page = Page::<Size2MiB>::containing_address(addr);
+                    let (frame, flush) = mapper.unmap(page).expect("failed to unmap huge page");
+                    flush.flush();
+                    if free_frames {
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                    }
+                    Size2MiB::SIZE
+                }
+                TranslateResult::Mapped { .. } => {
+                    let page = Page::<Size4KiB>::containing_address(addr);
+                    let (frame, flush) = mapper.unmap(page).expect("failed to unmap page");
+                    flush.flush();
+                    if free_frames {
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                    }
+                    PAGE_SIZE
+                }
+                _ => PAGE_SIZE,
+            };
+        }
+    });
+}
+
+const fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
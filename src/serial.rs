@@ -1,25 +1,75 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use crate::{
+    interrupts::irq::{self, IrqError, IrqReturn},
+    sync::{level, IrqMutex},
+};
+
+/// Line status register of COM1, bit 0 is set while received data is waiting.
+const COM1_LINE_STATUS: u16 = 0x3FD;
+const COM1_DATA: u16 = 0x3F8;
+/// Line status bit set once the last byte has left the shift register.
+const TRANSMITTER_EMPTY: u8 = 1 << 6;
+/// Upper bound for `flush`, a missing UART reads as all ones anyway.
+const FLUSH_SPINS: u32 = 1_000_000;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
+    pub static ref SERIAL1: IrqMutex<SerialPort> = {
         let mut serial_port = unsafe { SerialPort::new(0x3F8) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqMutex::ordered("serial", level::SERIAL, serial_port)
     };
 }
 
+/// Claims the COM1 IRQ that `SerialPort::init` enabled for received data.
+pub fn init_interrupt() -> Result<(), IrqError> {
+    irq::register_irq(4, "serial", |_| {
+        let mut line_status: Port<u8> = Port::new(COM1_LINE_STATUS);
+        let mut data: Port<u8> = Port::new(COM1_DATA);
+
+        // nothing reads serial input yet, drain it so the UART deasserts the line
+        while unsafe { line_status.read() } & 1 != 0 {
+            let _byte = unsafe { data.read() };
+        }
+        IrqReturn::Handled
+    })?;
+    Ok(())
+}
+
+/// Waits until everything written to COM1 has been sent, e.g. before the
+/// machine powers off.
+pub fn flush() {
+    let mut line_status: Port<u8> = Port::new(COM1_LINE_STATUS);
+    for _ in 0..FLUSH_SPINS {
+        if unsafe { line_status.read() } & TRANSMITTER_EMPTY != 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+/// Breaks the serial lock if the panicking CPU holds it and forgets the other
+/// locks it holds, so the panic message can still be printed.
+///
+/// # Safety
+///
+/// Only for panic handlers, the interrupted writer never runs again.
+pub unsafe fn unlock_for_panic() {
+    crate::sync::forget_held_locks();
+    if SERIAL1.is_held_by_current_cpu() {
+        SERIAL1.force_unlock();
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
-            .write_fmt(args)
-            .expect("Printing to serial failed")
-    });
+    SERIAL1
+        .lock()
+        .write_fmt(args)
+        .expect("Printing to serial failed");
 }
 
 /// Prints to the host through the serial interface.
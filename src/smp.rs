@@ -0,0 +1,382 @@
+extern crate alloc;
+
+pub mod trampoline;
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::TranslateResult, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::{
+    cpu::{self, PerCpu},
+    gdt,
+    interrupts::{
+        apic,
+        irq::{self, IrqReturn},
+    },
+    memory::{
+        self,
+        frame::{self, GlobalFrameAllocator},
+        phys_to_virt,
+        stack::KernelStack,
+    },
+    time::Instant,
+};
+
+use self::trampoline::TrampolineData;
+
+/// Stack every application processor runs on.
+const AP_STACK_SIZE: u64 = 4096 * 16;
+/// The startup IPI takes the trampoline's page number, so it has to be below 1 MiB.
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+/// Vector used to wake a parked CPU when work was queued for it.
+pub const WAKEUP_VECTOR: u8 = 0xF0;
+
+const INIT_DELAY: Duration = Duration::from_millis(10);
+const STARTUP_DELAY: Duration = Duration::from_micros(200);
+const ONLINE_TIMEOUT: Duration = Duration::from_millis(100);
+
+static TRAMPOLINE_FRAME: Once<Option<PhysFrame>> = Once::new();
+static CPUS: Once<Vec<Cpu>> = Once::new();
+
+pub type Work = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CpuState {
+    /// listed in the MADT but not started
+    Offline,
+    /// startup IPIs sent, waiting for it to check in
+    Starting,
+    /// idle or running queued work
+    Online,
+    /// did not come up, or the firmware marked it disabled
+    Failed,
+}
+
+impl CpuState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => CpuState::Offline,
+            1 => CpuState::Starting,
+            2 => CpuState::Online,
+            _ => CpuState::Failed,
+        }
+    }
+}
+
+impl fmt::Display for CpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // pad so the state lines up in tables
+        f.pad(match self {
+            CpuState::Offline => "offline",
+            CpuState::Starting => "starting",
+            CpuState::Online => "online",
+            CpuState::Failed => "failed",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    /// `init` has not run or found no such CPU
+    NoSuchCpu,
+    NotOnline,
+    /// the boot processor runs its own loop, not queued work
+    BootProcessor,
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::NoSuchCpu => write!(f, "no such CPU"),
+            SmpError::NotOnline => write!(f, "CPU is not online"),
+            SmpError::BootProcessor => write!(f, "CPU is the boot processor"),
+        }
+    }
+}
+
+/// A processor from the MADT, indexed in MADT order.
+pub struct Cpu {
+    pub apic_id: u8,
+    pub processor_id: u8,
+    pub bsp: bool,
+    state: AtomicU8,
+    work: Mutex<VecDeque<Work>>,
+    completed: AtomicU64,
+}
+
+impl Cpu {
+    pub fn state(&self) -> CpuState {
+        CpuState::from_u8(self.state.load(Ordering::Acquire))
+    }
+
+    fn set_state(&self, state: CpuState) {
+        self.state.store(state as u8, Ordering::Release);
+    }
+
+    /// Work items this CPU has finished.
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Work items waiting for this CPU.
+    pub fn queued(&self) -> usize {
+        interrupts::without_interrupts(|| self.work.lock().len())
+    }
+
+    fn pop_work(&self) -> Option<Work> {
+        interrupts::without_interrupts(|| self.work.lock().pop_front())
+    }
+}
+
+/// Sets a frame below 1 MiB aside for the trampoline. Those are handed out
+/// first, so this should run right after the frame allocator is set up.
+pub fn reserve_trampoline() {
+    TRAMPOLINE_FRAME.call_once(|| frame::allocate_frame_below(PhysAddr::new(TRAMPOLINE_LIMIT)));
+}
+
+/// Every CPU `init` found, empty before.
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Number of CPUs that are online, the BSP included.
+pub fn online_count() -> usize {
+    cpus()
+        .iter()
+        .filter(|cpu| cpu.state() == CpuState::Online)
+        .count()
+}
+
+/// Starts every enabled application processor the MADT lists and parks it in
+/// an idle loop that runs work queued with `run_on`. Needs the APICs and the
+/// heap. Returns the number of CPUs online.
+pub fn init() -> usize {
+    let Some(madt) = apic::madt().filter(|_| apic::is_enabled()) else {
+        log::info!("No APIC, running on the boot processor only");
+        return 1;
+    };
+    let bsp_apic_id = apic::local_apic_id();
+
+    let cpus = CPUS.call_once(|| {
+        madt.local_apics
+            .iter()
+            .map(|entry| Cpu {
+                apic_id: entry.apic_id,
+                processor_id: entry.processor_id,
+                bsp: entry.apic_id == bsp_apic_id,
+                state: AtomicU8::new(if entry.apic_id == bsp_apic_id {
+                    CpuState::Online as u8
+                } else if entry.flags & 1 == 0 {
+                    CpuState::Failed as u8
+                } else {
+                    CpuState::Offline as u8
+                }),
+                work: Mutex::new(VecDeque::new()),
+                completed: AtomicU64::new(0),
+            })
+            .collect()
+    });
+    if let Some(index) = cpus.iter().position(|cpu| cpu.bsp) {
+        cpu::current().set_identity(index, bsp_apic_id);
+    }
+
+    if let Err(err) =
+        irq::register_vector(WAKEUP_VECTOR, "smp wakeup", false, |_| IrqReturn::Handled)
+    {
+        log::error!("Failed to claim the wakeup vector: {}", err);
+        return 1;
+    }
+
+    let Some(trampoline) = *TRAMPOLINE_FRAME
+        .call_once(|| frame::allocate_frame_below(PhysAddr::new(TRAMPOLINE_LIMIT)))
+    else {
+        log::error!("No free frame below 1 MiB for the AP trampoline");
+        return 1;
+    };
+    let mapped = match identity_map(trampoline) {
+        Ok(mapped) => mapped,
+        Err(err) => {
+            log::error!("Failed to identity map the AP trampoline: {}", err);
+            return 1;
+        }
+    };
+
+    let (level_4_table, _) = Cr3::read();
+    let page_table = level_4_table.start_address().as_u64();
+    if page_table >= 1 << 32 {
+        log::error!(
+            "Page tables at {:#x} are out of reach of the trampoline",
+            page_table
+        );
+        return 1;
+    }
+
+    let code = trampoline::code();
+    let base = phys_to_virt(trampoline.start_address());
+    unsafe {
+        core::ptr::copy_nonoverlapping(code.as_ptr(), base.as_mut_ptr::<u8>(), code.len());
+    }
+
+    for (index, cpu) in cpus.iter().enumerate() {
+        if cpu.state() != CpuState::Offline {
+            continue;
+        }
+
+        let stack = match KernelStack::new(AP_STACK_SIZE, "ap stack") {
+            Ok(stack) => stack,
+            Err(err) => {
+                log::error!("Failed to allocate a stack for CPU {}: {}", index, err);
+                cpu.set_state(CpuState::Failed);
+                continue;
+            }
+        };
+        // allocated here since the AP cannot use the heap before its GS base
+        // points at its own per CPU data
+        let per_cpu: &'static PerCpu = Box::leak(Box::new(PerCpu::new(index, cpu.apic_id)));
+        let data = TrampolineData {
+            page_table,
+            stack_top: stack.leak().as_u64(),
+            entry: ap_entry,
+            argument: per_cpu as *const PerCpu as u64,
+        };
+        unsafe {
+            let data_ptr = base.as_mut_ptr::<u8>().add(trampoline::data_offset());
+            core::ptr::write_volatile(data_ptr as *mut TrampolineData, data);
+        }
+
+        start_ap(cpu, trampoline);
+    }
+
+    if mapped {
+        unmap_identity(trampoline);
+    }
+
+    let online = online_count();
+    log::info!("{} of {} CPU(s) online", online, cpus.len());
+    online
+}
+
+/// INIT-SIPI-SIPI as the MultiProcessor Specification describes it.
+fn start_ap(cpu: &Cpu, trampoline: PhysFrame) {
+    let page = (trampoline.start_address().as_u64() / 4096) as u8;
+    cpu.set_state(CpuState::Starting);
+
+    apic::send_init_ipi(cpu.apic_id);
+    busy_wait(INIT_DELAY);
+    for _ in 0..2 {
+        apic::send_startup_ipi(cpu.apic_id, page);
+        busy_wait(STARTUP_DELAY);
+        if cpu.state() == CpuState::Online {
+            return;
+        }
+    }
+
+    let start = Instant::now();
+    while start.elapsed() < ONLINE_TIMEOUT {
+        if cpu.state() == CpuState::Online {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+
+    log::error!("CPU with APIC id {} did not come up", cpu.apic_id);
+    cpu.set_state(CpuState::Failed);
+}
+
+fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// Maps the trampoline page to itself, returns whether it had to be mapped.
+fn identity_map(frame: PhysFrame) -> Result<bool, &'static str> {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    memory::with_mapper(|mapper| match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: mapped, .. }
+            if mapped.start_address() == frame.start_address() =>
+        {
+            Ok(false)
+        }
+        TranslateResult::Mapped { .. } => Err("the page is mapped elsewhere"),
+        TranslateResult::InvalidFrameAddress(_) => Err("invalid frame address"),
+        TranslateResult::NotMapped => {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator) }
+                .map_err(|_| "mapping failed")?
+                .flush();
+            Ok(true)
+        }
+    })
+}
+
+fn unmap_identity(frame: PhysFrame) {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    memory::with_mapper(|mapper| {
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    });
+}
+
+/// First Rust code an application processor runs, on its own stack, with the
+/// per CPU data the BSP set up for it.
+extern "C" fn ap_entry(per_cpu: u64) -> ! {
+    let per_cpu = unsafe { &*(per_cpu as *const PerCpu) };
+    cpu::install(per_cpu);
+    let cpu = &cpus()[per_cpu.index()];
+    gdt::init_ap().expect("failed to set up the AP's GDT and TSS");
+    crate::interrupts::init_idt();
+    apic::init_ap();
+
+    cpu.set_state(CpuState::Online);
+    idle(cpu)
+}
+
+/// Runs queued work and halts until the wakeup IPI says there is more.
+fn idle(cpu: &Cpu) -> ! {
+    loop {
+        while let Some(work) = cpu.pop_work() {
+            work();
+            cpu.completed.fetch_add(1, Ordering::Relaxed);
+        }
+
+        interrupts::disable();
+        if cpu.queued() == 0 {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+/// Queues `work` on the application processor with index `cpu` and wakes it.
+/// Work that never returns, like an executor worker, keeps the CPU for good.
+pub fn run_on(cpu: usize, work: impl FnOnce() + Send + 'static) -> Result<(), SmpError> {
+    let target = cpus().get(cpu).ok_or(SmpError::NoSuchCpu)?;
+    if target.bsp {
+        return Err(SmpError::BootProcessor);
+    }
+    if target.state() != CpuState::Online {
+        return Err(SmpError::NotOnline);
+    }
+
+    interrupts::without_interrupts(|| target.work.lock().push_back(Box::new(work)));
+    apic::send_fixed_ipi(target.apic_id, WAKEUP_VECTOR);
+    Ok(())
+}
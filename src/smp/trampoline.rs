@@ -0,0 +1,128 @@
+use core::{arch::global_asm, ptr::addr_of};
+
+// Copied to a page below 1 MiB and entered there in real mode by the startup
+// IPI, with CS set to the page's segment. It switches straight to long mode on
+// the kernel page tables and jumps to `TrampolineData::entry`. The page has to
+// be identity mapped since paging is turned on while running from it.
+global_asm!(
+    ".pushsection .text.smp_trampoline, \"ax\"",
+    ".code16",
+    ".global smp_trampoline_start",
+    "smp_trampoline_start:",
+    "cli",
+    "cld",
+    "mov %cs, %ax",
+    "mov %ax, %ds",
+    // ebx holds the physical address of the trampoline from here on
+    "xor %ebx, %ebx",
+    "mov %cs, %bx",
+    "shl $4, %ebx",
+    // the GDT pointer and far jumps need absolute addresses, patch them in
+    "lea (trampoline_gdt - smp_trampoline_start)(%ebx), %eax",
+    "mov %eax, (trampoline_gdtr - smp_trampoline_start + 2)",
+    "lea (trampoline_protected - smp_trampoline_start)(%ebx), %eax",
+    "mov %eax, (trampoline_protected_jump - smp_trampoline_start)",
+    "lea (trampoline_long - smp_trampoline_start)(%ebx), %eax",
+    "mov %eax, (trampoline_long_jump - smp_trampoline_start)",
+    "lgdtl (trampoline_gdtr - smp_trampoline_start)",
+    "mov %cr0, %eax",
+    "or $1, %eax",
+    "mov %eax, %cr0",
+    "ljmpl *(trampoline_protected_jump - smp_trampoline_start)",
+    ".code32",
+    "trampoline_protected:",
+    "mov $0x10, %ax",
+    "mov %ax, %ds",
+    "mov %ax, %es",
+    "mov %ax, %ss",
+    // physical address extension and global pages
+    "mov %cr4, %eax",
+    "or $0xA0, %eax",
+    "mov %eax, %cr4",
+    "mov (trampoline_data - smp_trampoline_start)(%ebx), %eax",
+    "mov %eax, %cr3",
+    // long mode and no execute, the kernel page tables use the NX bit
+    "mov $0xC0000080, %ecx",
+    "rdmsr",
+    "or $0x900, %eax",
+    "wrmsr",
+    // paging, write protect and protected mode
+    "mov %cr0, %eax",
+    "or $0x80010001, %eax",
+    "mov %eax, %cr0",
+    "ljmpl *(trampoline_long_jump - smp_trampoline_start)(%ebx)",
+    ".code64",
+    "trampoline_long:",
+    "xor %eax, %eax",
+    "mov %ax, %ds",
+    "mov %ax, %es",
+    "mov %ax, %ss",
+    "mov %ax, %fs",
+    "mov %ax, %gs",
+    "mov (trampoline_data - smp_trampoline_start + 8)(%rbx), %rsp",
+    "mov (trampoline_data - smp_trampoline_start + 24)(%rbx), %rdi",
+    "mov (trampoline_data - smp_trampoline_start + 16)(%rbx), %rax",
+    "xor %ebp, %ebp",
+    // a zero return address ends backtraces here
+    "push $0",
+    "jmp *%rax",
+    ".align 8",
+    "trampoline_gdt:",
+    ".quad 0",
+    // 32 bit code, 32 bit data, 64 bit code
+    ".quad 0x00cf9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    ".quad 0x00af9a000000ffff",
+    "trampoline_gdtr:",
+    ".word 4 * 8 - 1",
+    ".long 0",
+    "trampoline_protected_jump:",
+    ".long 0",
+    ".word 0x08",
+    "trampoline_long_jump:",
+    ".long 0",
+    ".word 0x18",
+    ".align 8",
+    ".global smp_trampoline_data",
+    "smp_trampoline_data:",
+    "trampoline_data:",
+    ".fill 4, 8, 0",
+    ".global smp_trampoline_end",
+    "smp_trampoline_end:",
+    ".popsection",
+    options(att_syntax)
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_data: u8;
+    static smp_trampoline_end: u8;
+}
+
+/// Parameters the BSP fills in before every startup IPI, at the end of the
+/// trampoline.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrampolineData {
+    /// physical address of the level 4 page table, has to be below 4 GiB
+    pub page_table: u64,
+    pub stack_top: u64,
+    pub entry: extern "C" fn(u64) -> !,
+    /// passed to `entry`
+    pub argument: u64,
+}
+
+/// Trampoline machine code, position independent as long as it starts on a
+/// page boundary.
+pub fn code() -> &'static [u8] {
+    unsafe {
+        let start = addr_of!(smp_trampoline_start);
+        let end = addr_of!(smp_trampoline_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// Where `TrampolineData` goes in the copied code.
+pub fn data_offset() -> usize {
+    unsafe { addr_of!(smp_trampoline_data) as usize - addr_of!(smp_trampoline_start) as usize }
+}
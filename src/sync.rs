@@ -0,0 +1,335 @@
+use core::{
+    fmt,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+use crate::cpu::{self, HeldLock};
+
+/// Spins before a debug build assumes a lock will never be released.
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: u64 = 1 << 30;
+
+/// Levels of the kernel's global locks. A CPU may only take an ordered lock
+/// whose level is above every ordered lock it already holds, so the order
+/// here is the order they nest in. Debug builds check it on every `lock`.
+pub mod level {
+    /// not part of the ordering, only checked for recursion and deadlocks
+    pub const UNORDERED: u8 = 0;
+    pub const MEMORY_FS: u8 = 10;
+    pub const PCI_DEVICES: u8 = 10;
+    pub const LOGS: u8 = 20;
+    pub const WRITER: u8 = 30;
+    /// the VGA writer mirrors everything to serial
+    pub const SERIAL: u8 = 40;
+    /// state of the primitives in `task::sync`, wakers run after it is released
+    pub const TASK_SYNC: u8 = 45;
+    pub const EXECUTOR_TASKS: u8 = 50;
+    pub const JOIN_HANDLE: u8 = 55;
+    /// tasks are woken from anywhere, so run queues nest inside everything
+    pub const RUN_QUEUE: u8 = 60;
+    /// taken by the timer interrupt to switch threads
+    pub const SCHEDULER: u8 = 65;
+}
+
+/// Panics in debug builds when the running CPU holds an `IrqMutex`, e.g.
+/// before switching threads, which would leave it locked for the next one.
+#[cfg_attr(not(debug_assertions), allow(unused_variables))]
+pub fn assert_no_locks_held(what: &str) {
+    #[cfg(debug_assertions)]
+    if let Some(cpu) = cpu::try_current() {
+        cpu.with_held_locks(|held| {
+            if let Some(lock) = held.iter().flatten().next() {
+                panic!("{} while holding {}", what, lock.name);
+            }
+        });
+    }
+}
+
+/// Drops the running CPU's records of the locks it holds, so a panic or fatal
+/// exception can take the console locks without `check_order` reporting the
+/// locks the failing code held and panicking again. The locks stay held.
+pub fn forget_held_locks() {
+    #[cfg(debug_assertions)]
+    if let Some(cpu) = cpu::try_current() {
+        cpu.with_held_locks(|held| held.fill(None));
+    }
+}
+
+/// A spinlock that disables interrupts while it is held, so interrupt
+/// handlers can take it without deadlocking against the code they
+/// interrupted. The interrupt flag is restored when the guard is dropped.
+pub struct IrqMutex<T> {
+    name: &'static str,
+    level: u8,
+    /// index + 1 of the CPU holding the lock, 0 when free
+    owner: AtomicUsize,
+    inner: Mutex<T>,
+}
+
+pub struct IrqMutexGuard<'a, T> {
+    lock: &'a IrqMutex<T>,
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    enable_interrupts: bool,
+}
+
+/// Identifies the running CPU in `IrqMutex::owner`. Only the boot processor
+/// runs before the per CPU data is set up.
+fn cpu_tag() -> usize {
+    cpu::try_current().map_or(1, |cpu| cpu.index() + 1)
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::ordered("unnamed", level::UNORDERED, value)
+    }
+
+    /// A lock that takes part in the lock order, see `level`.
+    pub const fn ordered(name: &'static str, level: u8, value: T) -> Self {
+        Self {
+            name,
+            level,
+            owner: AtomicUsize::new(0),
+            inner: Mutex::new(value),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Disables interrupts and spins until the lock is free.
+    ///
+    /// Debug builds panic instead of deadlocking when the CPU already holds
+    /// the lock, when taking it would break the lock order, or when it has
+    /// not been released after a very long time.
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+
+        #[cfg(debug_assertions)]
+        self.check_order();
+
+        #[cfg(debug_assertions)]
+        let mut spins = 0u64;
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+
+            #[cfg(debug_assertions)]
+            {
+                spins += 1;
+                if spins == DEADLOCK_SPINS {
+                    panic!(
+                        "possible deadlock on {}, held by CPU {}",
+                        self.name,
+                        self.owner.load(Ordering::Relaxed).wrapping_sub(1)
+                    );
+                }
+            }
+            core::hint::spin_loop();
+        };
+
+        self.acquired(guard, enable_interrupts)
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let enable_interrupts = interrupts::are_enabled();
+        interrupts::disable();
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(self.acquired(guard, enable_interrupts)),
+            None => {
+                if enable_interrupts {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    fn acquired<'a>(
+        &'a self,
+        guard: MutexGuard<'a, T>,
+        enable_interrupts: bool,
+    ) -> IrqMutexGuard<'a, T> {
+        self.owner.store(cpu_tag(), Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        self.record_held();
+
+        IrqMutexGuard {
+            lock: self,
+            guard: ManuallyDrop::new(guard),
+            enable_interrupts,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Whether the running CPU holds the lock, e.g. because it was
+    /// interrupted by an exception while holding it.
+    pub fn is_held_by_current_cpu(&self) -> bool {
+        self.is_locked() && self.owner.load(Ordering::Relaxed) == cpu_tag()
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// # Safety
+    ///
+    /// The current holder must never touch the data again, e.g. because the
+    /// CPU panicked or took an exception it does not return from.
+    pub unsafe fn force_unlock(&self) {
+        self.owner.store(0, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        self.forget_held();
+        self.inner.force_unlock();
+    }
+
+    fn address(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[cfg(debug_assertions)]
+    fn check_order(&self) {
+        let Some(cpu) = cpu::try_current() else {
+            return;
+        };
+        let address = self.address();
+
+        cpu.with_held_locks(|held| {
+            for lock in held.iter().flatten() {
+                if lock.address == address {
+                    panic!("{} locked recursively", self.name);
+                }
+                if self.level != level::UNORDERED
+                    && lock.level != level::UNORDERED
+                    && self.level <= lock.level
+                {
+                    panic!(
+                        "lock order violation: taking {} (level {}) while holding {} (level {})",
+                        self.name, self.level, lock.name, lock.level
+                    );
+                }
+            }
+        });
+    }
+
+    /// Remembers the lock for `check_order`. When too many locks are held the
+    /// record is dropped, which only weakens the checks.
+    #[cfg(debug_assertions)]
+    fn record_held(&self) {
+        let Some(cpu) = cpu::try_current() else {
+            return;
+        };
+        let record = HeldLock {
+            address: self.address(),
+            name: self.name,
+            level: self.level,
+        };
+
+        cpu.with_held_locks(|held| {
+            if let Some(slot) = held.iter_mut().find(|slot| slot.is_none()) {
+                *slot = Some(record);
+            }
+        });
+    }
+
+    #[cfg(debug_assertions)]
+    fn forget_held(&self) {
+        let Some(cpu) = cpu::try_current() else {
+            return;
+        };
+        let address = self.address();
+
+        cpu.with_held_locks(|held| {
+            if let Some(slot) = held
+                .iter_mut()
+                .find(|slot| slot.is_some_and(|lock| lock.address == address))
+            {
+                *slot = None;
+            }
+        });
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrqMutex")
+            .field("name", &self.name)
+            .field("level", &self.level)
+            .field("locked", &self.is_locked())
+            .finish()
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for IrqMutexGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.store(0, Ordering::Relaxed);
+        #[cfg(debug_assertions)]
+        self.lock.forget_held();
+        // the lock has to be free before an interrupt handler may want it
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.enable_interrupts {
+            interrupts::enable();
+        }
+    }
+}
+
+#[test_case]
+fn guard_restores_interrupt_flag() {
+    let lock = IrqMutex::new(0u8);
+
+    assert!(interrupts::are_enabled());
+    {
+        let mut guard = lock.lock();
+        *guard += 1;
+        assert!(!interrupts::are_enabled());
+        assert!(lock.is_held_by_current_cpu());
+        assert!(lock.try_lock().is_none());
+    }
+    assert!(interrupts::are_enabled());
+    assert!(!lock.is_locked());
+
+    interrupts::without_interrupts(|| {
+        drop(lock.lock());
+        assert!(!interrupts::are_enabled());
+    });
+    assert_eq!(*lock.lock(), 1);
+}
+
+#[test_case]
+fn nested_locks_in_order() {
+    static OUTER: IrqMutex<()> = IrqMutex::ordered("outer", 1, ());
+    static INNER: IrqMutex<()> = IrqMutex::ordered("inner", 2, ());
+
+    let _outer = OUTER.lock();
+    let _inner = INNER.lock();
+    assert!(OUTER.is_held_by_current_cpu() && INNER.is_held_by_current_cpu());
+}
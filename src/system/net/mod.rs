@@ -1 +1,53 @@
+use crate::allocator::slab::{SlabBox, SlabCache};
+
+/// Largest ethernet frame without the FCS, 1500 bytes of payload plus header
+/// and a VLAN tag.
+pub const MAX_FRAME_SIZE: usize = 1518;
+
+static PACKET_BUFFERS: SlabCache<PacketBuffer> = SlabCache::new("packet buffer");
+
+/// Buffer for a single ethernet frame, allocated from its own slab cache so
+/// receive and transmit paths never touch the general heap.
+pub struct PacketBuffer {
+    len: usize,
+    data: [u8; MAX_FRAME_SIZE],
+}
+
+impl PacketBuffer {
+    pub fn alloc() -> Option<SlabBox<PacketBuffer>> {
+        PACKET_BUFFERS
+            .alloc(PacketBuffer {
+                len: 0,
+                data: [0; MAX_FRAME_SIZE],
+            })
+            .ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Sets the length of the frame, clamped to `MAX_FRAME_SIZE`.
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(MAX_FRAME_SIZE);
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[..self.len]
+    }
+
+    /// The whole buffer regardless of the current length, for receiving into.
+    pub fn buffer_mut(&mut self) -> &mut [u8; MAX_FRAME_SIZE] {
+        &mut self.data
+    }
+}
+
 pub fn init_networking() {}
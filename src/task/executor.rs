@@ -1,107 +1,405 @@
 extern crate alloc;
 
 use core::{
-    sync::atomic::AtomicBool,
+    future::Future,
+    sync::atomic::{fence, AtomicBool, Ordering},
     task::{Context, Waker},
 };
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use crossbeam::queue::ArrayQueue;
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+    vec,
+    vec::Vec,
+};
 use x86_64::instructions::interrupts;
 
-use crate::exit_qemu;
+use crate::{
+    allocator::slab::SlabBox,
+    cpu, exit_qemu,
+    interrupts::apic,
+    smp::{self, CpuState},
+    sync::{level, IrqMutex},
+    thread,
+};
 
-use super::{Task, TaskId};
+use super::{
+    join::{Completion, JoinHandle, JoinState},
+    type_name_of, Task, TaskId, TASK_CACHE,
+};
 
 pub static EXIT_FLAG: AtomicBool = AtomicBool::new(false);
 
+/// Runs tasks on every online CPU. Each CPU has its own run queue, woken
+/// tasks go to the queue of the CPU that woke them and CPUs that run out of
+/// work steal half of another CPU's queue. Idle CPUs halt and are woken with
+/// an IPI when there is something to steal.
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    tasks: IrqMutex<BTreeMap<TaskId, TaskEntry>>,
+    /// one per CPU, indexed like `smp::cpus`
+    workers: Vec<Worker>,
+}
+
+struct TaskEntry {
+    /// `None` while a CPU is polling the task
+    task: Option<SlabBox<Task>>,
+    name: &'static str,
+    /// woken while it was being polled, has to be polled again
+    woken: bool,
+    /// cancelled while it was being polled, dropped once the poll returns
+    cancelled: bool,
+    polls: u64,
+    waker: Waker,
+}
+
+/// A live task as `Spawner::tasks` lists it.
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: TaskId,
+    pub name: &'static str,
+    /// times the task has been polled
+    pub polls: u64,
+    /// a CPU is polling it right now
+    pub running: bool,
+}
+
+/// Spawns tasks on an executor from anywhere, including other tasks.
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+struct Worker {
+    queue: IrqMutex<VecDeque<TaskId>>,
+    /// halted and waiting for the wakeup IPI
+    idle: AtomicBool,
+    /// APIC id the wakeup IPI goes to, `None` when the CPU runs no worker
+    apic_id: Option<u8>,
 }
 
 impl Executor {
     pub fn new() -> Self {
+        let cpus = smp::cpus();
+        let workers = if cpus.is_empty() {
+            // no APIC, only the boot processor runs tasks
+            vec![Worker::new(None)]
+        } else {
+            cpus.iter()
+                .map(|cpu| Worker::new((cpu.state() == CpuState::Online).then_some(cpu.apic_id)))
+                .collect()
+        };
+
         Self {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
-            waker_cache: BTreeMap::new(),
+            shared: Arc::new(Shared {
+                tasks: IrqMutex::ordered("executor tasks", level::EXECUTOR_TASKS, BTreeMap::new()),
+                workers,
+            }),
         }
     }
 
     pub fn spawn(&mut self, task: Task) {
+        self.shared.spawn(task);
+    }
+
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Starts a worker on every application processor that is online, then
+    /// turns the calling CPU into one as well. The application processors
+    /// never return to the `smp` work queue.
+    pub fn run(&mut self) -> ! {
+        let current = self.shared.current_worker();
+        for (index, worker) in self.shared.workers.iter().enumerate() {
+            if index == current || worker.apic_id.is_none() {
+                continue;
+            }
+
+            let shared = self.shared.clone();
+            if let Err(err) = smp::run_on(index, move || shared.run_worker(index)) {
+                log::warn!("No executor worker on CPU {}: {}", index, err);
+            }
+        }
+
+        self.shared.run_worker(current)
+    }
+}
+
+impl Spawner {
+    /// Spawns `future` as a task named after its type.
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_named(type_name_of::<F>(), future)
+    }
+
+    pub fn spawn_named<F>(&self, name: &'static str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let state = Arc::new(JoinState::new());
+        let completion = Completion::new(state.clone());
+        let task = Task::named(name, async move {
+            let output = future.await;
+            completion.finish(output);
+        });
+
+        let id = self.shared.spawn(task);
+        JoinHandle::new(id, state, self.clone())
+    }
+
+    /// Spawns a task without a handle, like `Executor::spawn`.
+    pub fn spawn_task(&self, task: Task) -> TaskId {
+        self.shared.spawn(task)
+    }
+
+    /// Drops the task's future, so it never runs again. A task that is being
+    /// polled is dropped when the poll returns. Returns whether the task was
+    /// still alive.
+    pub fn cancel(&self, id: TaskId) -> bool {
+        self.shared.cancel(id)
+    }
+
+    /// Every task that has not finished yet, ordered by id.
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        self.shared
+            .tasks
+            .lock()
+            .iter()
+            .map(|(&id, entry)| TaskInfo {
+                id,
+                name: entry.name,
+                polls: entry.polls,
+                running: entry.task.is_none(),
+            })
+            .collect()
+    }
+}
+
+impl Worker {
+    fn new(apic_id: Option<u8>) -> Self {
+        Self {
+            queue: IrqMutex::ordered("run queue", level::RUN_QUEUE, VecDeque::new()),
+            idle: AtomicBool::new(false),
+            apic_id,
+        }
+    }
+}
+
+impl Shared {
+    /// Worker of the running CPU.
+    fn current_worker(&self) -> usize {
+        cpu::try_current()
+            .map(|cpu| cpu.index())
+            .filter(|&index| index < self.workers.len())
+            .unwrap_or(0)
+    }
+
+    fn spawn(self: &Arc<Self>, task: Task) -> TaskId {
         let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
+        let name = task.name;
+        let task = TASK_CACHE
+            .alloc(task)
+            .ok()
+            .expect("out of memory for tasks");
+        let entry = TaskEntry {
+            task: Some(task),
+            name,
+            woken: false,
+            cancelled: false,
+            polls: 0,
+            waker: TaskWaker::waker(task_id, self.clone()),
+        };
+        if self.tasks.lock().insert(task_id, entry).is_some() {
             panic!("Task with Same ID already in tasks")
         }
 
-        self.task_queue.push(task_id).expect("task queue full");
+        self.schedule(task_id);
+        task_id
     }
 
-    fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
+    fn cancel(&self, task_id: TaskId) -> bool {
+        let removed = {
+            let mut tasks = self.tasks.lock();
+            let Some(entry) = tasks.get_mut(&task_id) else {
+                return false;
             };
+            if entry.task.is_none() {
+                entry.cancelled = true;
+                return true;
+            }
+            tasks.remove(&task_id)
+        };
+        // the future's destructors may wake other tasks
+        drop(removed);
+        true
+    }
 
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+    /// Queues the task on the running CPU and gets an idle CPU to steal it.
+    fn schedule(&self, task_id: TaskId) {
+        let current = self.current_worker();
+        self.workers[current].queue.lock().push_back(task_id);
 
-            let mut ctx = Context::from_waker(waker);
-            match task.poll(&mut ctx) {
-                core::task::Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                core::task::Poll::Pending => {}
-            }
+        // pairs with the fence in `sleep_if_idle`, either the idle CPU sees
+        // the task or this sees the CPU is idle
+        fence(Ordering::SeqCst);
+        self.wake_idle_worker(current);
+    }
+
+    fn wake_idle_worker(&self, current: usize) {
+        let idle = self
+            .workers
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| *index != current)
+            .find_map(|(_, worker)| {
+                let apic_id = worker.apic_id?;
+                // only one IPI per idle period
+                worker
+                    .idle
+                    .compare_exchange(true, false, Ordering::SeqCst, Ordering::Relaxed)
+                    .ok()?;
+                Some(apic_id)
+            });
+
+        if let Some(apic_id) = idle {
+            apic::send_fixed_ipi(apic_id, smp::WAKEUP_VECTOR);
         }
     }
 
-    pub fn run(&mut self) -> ! {
+    fn run_worker(&self, index: usize) -> ! {
         loop {
-            self.run_ready_tasks();
-            if EXIT_FLAG.load(core::sync::atomic::Ordering::Relaxed) {
+            crate::time::timer::wake_expired();
+            while let Some(task_id) = self.next_task(index) {
+                self.poll_task(task_id);
+            }
+            if EXIT_FLAG.load(Ordering::Relaxed) {
                 exit_qemu(crate::QemuExitCode::Success);
             }
-            self.sleep_if_idle();
+            self.sleep_if_idle(index);
+        }
+    }
+
+    fn next_task(&self, index: usize) -> Option<TaskId> {
+        let local = self.workers[index].queue.lock().pop_front();
+        local.or_else(|| self.steal(index))
+    }
+
+    /// Takes the back half of the first non-empty queue of another CPU, runs
+    /// the first of those tasks and keeps the rest.
+    fn steal(&self, thief: usize) -> Option<TaskId> {
+        let count = self.workers.len();
+        for victim in (1..count).map(|offset| (thief + offset) % count) {
+            let mut stolen = {
+                let mut queue = self.workers[victim].queue.lock();
+                let keep = queue.len() / 2;
+                queue.split_off(keep)
+            };
+
+            if let Some(task_id) = stolen.pop_front() {
+                if !stolen.is_empty() {
+                    self.workers[thief].queue.lock().append(&mut stolen);
+                }
+                return Some(task_id);
+            }
         }
+        None
     }
 
-    fn sleep_if_idle(&self) {
+    fn poll_task(&self, task_id: TaskId) {
+        let (mut task, waker) = {
+            let mut tasks = self.tasks.lock();
+            // a stale wakeup of a task that already finished
+            let Some(entry) = tasks.get_mut(&task_id) else {
+                return;
+            };
+            match entry.task.take() {
+                Some(task) => {
+                    entry.polls += 1;
+                    (task, entry.waker.clone())
+                }
+                None => {
+                    // another CPU is polling it, let that one poll again
+                    entry.woken = true;
+                    return;
+                }
+            }
+        };
+
+        let mut ctx = Context::from_waker(&waker);
+        match task.poll(&mut ctx) {
+            core::task::Poll::Ready(()) => {
+                let entry = self.tasks.lock().remove(&task_id);
+                // dropped outside the lock, destructors may wake other tasks
+                drop(entry);
+                drop(task);
+            }
+            core::task::Poll::Pending => {
+                let mut tasks = self.tasks.lock();
+                let entry = tasks.get_mut(&task_id).expect("polled task disappeared");
+                if entry.cancelled {
+                    let entry = tasks.remove(&task_id);
+                    drop(tasks);
+                    drop(entry);
+                    drop(task);
+                    return;
+                }
+
+                entry.task = Some(task);
+                let woken = core::mem::take(&mut entry.woken);
+                drop(tasks);
+                if woken {
+                    self.schedule(task_id);
+                }
+            }
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        self.workers
+            .iter()
+            .any(|worker| !worker.queue.lock().is_empty())
+    }
+
+    fn sleep_if_idle(&self, index: usize) {
+        let worker = &self.workers[index];
+
         interrupts::disable();
-        if self.task_queue.is_empty() {
-            interrupts::enable_and_hlt();
-        } else {
+        worker.idle.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+        if self.has_work() {
+            worker.idle.store(false, Ordering::SeqCst);
             interrupts::enable();
+        } else {
+            // lets other threads run when the worker is one
+            thread::idle();
+            worker.idle.store(false, Ordering::SeqCst);
         }
     }
 }
 
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    executor: Arc<Shared>,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+        self.executor.schedule(self.task_id);
     }
 
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+    fn waker(task_id: TaskId, executor: Arc<Shared>) -> Waker {
+        Waker::from(Arc::new(TaskWaker { task_id, executor }))
     }
 }
 impl Wake for TaskWaker {
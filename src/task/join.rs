@@ -0,0 +1,151 @@
+extern crate alloc;
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::sync::Arc;
+
+use crate::sync::{level, IrqMutex};
+
+use super::{executor::Spawner, TaskId};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// the task was cancelled before it finished
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+struct JoinSlot<T> {
+    result: Option<Result<T, JoinError>>,
+    /// the result was handed out already
+    taken: bool,
+    waker: Option<Waker>,
+}
+
+/// Where a spawned task leaves its output for its `JoinHandle`.
+pub(super) struct JoinState<T> {
+    slot: IrqMutex<JoinSlot<T>>,
+}
+
+impl<T> JoinState<T> {
+    pub(super) fn new() -> Self {
+        Self {
+            slot: IrqMutex::ordered(
+                "join handle",
+                level::JOIN_HANDLE,
+                JoinSlot {
+                    result: None,
+                    taken: false,
+                    waker: None,
+                },
+            ),
+        }
+    }
+
+    fn complete(&self, result: Result<T, JoinError>) {
+        let waker = {
+            let mut slot = self.slot.lock();
+            if slot.result.is_some() || slot.taken {
+                return;
+            }
+            slot.result = Some(result);
+            slot.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Reports `JoinError::Cancelled` if dropped before the task finished, which
+/// happens when the executor drops a cancelled task's future.
+pub(super) struct Completion<T> {
+    state: Arc<JoinState<T>>,
+    finished: bool,
+}
+
+impl<T> Completion<T> {
+    pub(super) fn new(state: Arc<JoinState<T>>) -> Self {
+        Self {
+            state,
+            finished: false,
+        }
+    }
+
+    pub(super) fn finish(mut self, output: T) {
+        self.finished = true;
+        self.state.complete(Ok(output));
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.state.complete(Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// Resolves to the output of a spawned task. Dropping the handle detaches
+/// the task, it keeps running.
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<JoinState<T>>,
+    spawner: Spawner,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(id: TaskId, state: Arc<JoinState<T>>, spawner: Spawner) -> Self {
+        Self { id, state, spawner }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Whether the task finished or was cancelled.
+    pub fn is_finished(&self) -> bool {
+        let slot = self.state.slot.lock();
+        slot.result.is_some() || slot.taken
+    }
+
+    /// Cancels the task, awaiting the handle then returns
+    /// `JoinError::Cancelled` unless the task already finished. See
+    /// `Spawner::cancel`.
+    pub fn cancel(&self) -> bool {
+        self.spawner.cancel(self.id)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mut slot = self.state.slot.lock();
+        if let Some(result) = slot.result.take() {
+            slot.taken = true;
+            return Poll::Ready(result);
+        }
+        if slot.taken {
+            panic!("JoinHandle polled after it completed");
+        }
+
+        match &slot.waker {
+            Some(waker) if waker.will_wake(context.waker()) => {}
+            _ => slot.waker = Some(context.waker().clone()),
+        }
+        Poll::Pending
+    }
+}
@@ -1,6 +1,6 @@
 extern crate alloc;
 
-use core::task::Poll;
+use core::{task::Poll, time::Duration};
 
 use alloc::{
     collections::BTreeMap,
@@ -10,9 +10,7 @@ use alloc::{
 use conquer_once::spin::OnceCell;
 use crossbeam::queue::ArrayQueue;
 use futures_util::{task::AtomicWaker, Stream, StreamExt};
-use lazy_static::lazy_static;
 use pc_keyboard::{layouts, HandleControl, KeyCode, Keyboard, ScancodeSet1};
-use spin::Mutex;
 use vga::{
     colors::Color16,
     writers::{
@@ -22,13 +20,18 @@ use vga::{
 };
 
 use crate::{
+    acpi::{self, power},
+    allocator,
+    interrupts::irq::{self, IrqError, IrqReturn},
     logging::LOGS,
-    print, println, serial_println,
+    print, println, serial_println, smp,
+    sync::{level, IrqMutex},
+    task::{executor::Spawner, TaskId},
+    thread::{self, Priority},
+    time,
     vga_buffer::{console_backspace, string_to_color, Color, WRITER},
 };
 
-use super::executor::EXIT_FLAG;
-
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 static WAKER: AtomicWaker = AtomicWaker::new();
 
@@ -47,6 +50,16 @@ pub(crate) fn add_scancode(scancode: u8) {
     }
 }
 
+/// Claims the keyboard IRQ, scancodes are queued for `ScancodeStream`.
+pub fn init_interrupt() -> Result<(), IrqError> {
+    irq::register_irq(1, "keyboard", |_| {
+        let mut port = x86_64::instructions::port::Port::new(0x60);
+        add_scancode(unsafe { port.read() });
+        IrqReturn::Handled
+    })?;
+    Ok(())
+}
+
 pub struct ScancodeStream {
     _private: (),
 }
@@ -88,7 +101,8 @@ impl Stream for ScancodeStream {
     }
 }
 
-pub async fn print_keypresses() {
+/// The shell. `spawner` lets commands run in the background.
+pub async fn print_keypresses(spawner: Spawner) {
     let mut scancodes = ScancodeStream::new();
 
     let mut keyboard = Keyboard::new(
@@ -133,7 +147,7 @@ pub async fn print_keypresses() {
 
                         print!("{}", c);
                         if c == '\n' && !line.is_empty() {
-                            execute_cmd(&mut current_dir, line.as_str());
+                            execute_cmd(&mut current_dir, line.as_str(), &spawner);
                             line.clear();
                             print!("{} $ ", current_dir);
                         } else {
@@ -160,9 +174,8 @@ impl AsRef<[u8]> for MemoryFile {
     }
 }
 
-lazy_static! {
-    static ref MEMORY_FS: Mutex<BTreeMap<String, MemoryFile>> = Mutex::new(BTreeMap::new());
-}
+static MEMORY_FS: IrqMutex<BTreeMap<String, MemoryFile>> =
+    IrqMutex::ordered("memory fs", level::MEMORY_FS, BTreeMap::new());
 
 pub fn init_memory_fs() {
     let mut fs = MEMORY_FS.lock();
@@ -186,7 +199,7 @@ pub fn init_memory_fs() {
 
 const FS_SEP: char = '/';
 
-pub fn execute_cmd(current_dir: &mut String, cmd: &str) {
+pub fn execute_cmd(current_dir: &mut String, cmd: &str, spawner: &Spawner) {
     match cmd {
         "clear" => WRITER.lock().clear_everything(),
         "ls" => {
@@ -302,16 +315,212 @@ pub fn execute_cmd(current_dir: &mut String, cmd: &str) {
             }
         }
 
-        "shutdown now" => {
-            EXIT_FLAG.store(true, core::sync::atomic::Ordering::Relaxed);
-        }
+        "poweroff" | "shutdown now" => power::shutdown(),
+        "reboot" => power::reboot(),
         "logs" => {
-            println!("{}", LOGS.lock());
+            // copied so interrupts stay enabled while the console scrolls
+            let logs = LOGS.lock().clone();
+            println!("{}", logs);
+        }
+        "meminfo" => allocator::print_meminfo(),
+        "allocs" => allocator::print_allocations(0),
+        _ if cmd.starts_with("allocs ") => {
+            if let Ok(first_id) = cmd["allocs ".len()..].trim().parse::<u64>() {
+                allocator::print_allocations(first_id);
+            } else {
+                println!("usage: allocs [first allocation id]");
+            }
+        }
+        "date" => println!("{} UTC", time::rtc::read()),
+        "uptime" => {
+            let uptime = time::uptime();
+            let seconds = uptime.as_secs();
+            println!(
+                "up {}:{:02}:{:02}.{:03}, {} ticks at {} Hz, {} timers pending",
+                seconds / 3600,
+                seconds / 60 % 60,
+                seconds % 60,
+                uptime.subsec_millis(),
+                time::ticks(),
+                time::TICK_FREQUENCY,
+                time::timer::pending()
+            );
+        }
+        "irqs" => {
+            println!(
+                "{:>6} {:>10} {:>10} handlers",
+                "vector", "count", "unhandled"
+            );
+            for stats in irq::stats() {
+                println!(
+                    "{:>6} {:>10} {:>10} {}",
+                    stats.vector,
+                    stats.count,
+                    stats.unhandled,
+                    stats.handlers.join(", ")
+                );
+            }
+        }
+        "acpi" => print_acpi(),
+        "cpus" => {
+            println!(
+                "{:>3} {:>7} {:>9} {:8} {:>6} {:>9}",
+                "cpu", "apic id", "processor", "state", "queued", "completed"
+            );
+            for (index, cpu) in smp::cpus().iter().enumerate() {
+                println!(
+                    "{:>3} {:>7} {:>9} {:8} {:>6} {:>9}{}",
+                    index,
+                    cpu.apic_id,
+                    cpu.processor_id,
+                    cpu.state(),
+                    cpu.queued(),
+                    cpu.completed(),
+                    if cpu.bsp { " bsp" } else { "" }
+                );
+            }
+        }
+        "ps" => {
+            println!("{:>5} {:>8} {:7} name", "id", "polls", "state");
+            for task in spawner.tasks() {
+                println!(
+                    "{:>5} {:>8} {:7} {}",
+                    task.id,
+                    task.polls,
+                    if task.running { "running" } else { "waiting" },
+                    task.name
+                );
+            }
+        }
+        _ if cmd.starts_with("kill ") => match cmd["kill ".len()..].trim().parse::<u64>() {
+            Ok(id) if spawner.cancel(TaskId::from_u64(id)) => println!("cancelled task {}", id),
+            Ok(id) => println!("no task {}", id),
+            Err(_) => println!("usage: kill <task id>"),
+        },
+        _ if cmd.starts_with("sleep ") => {
+            if let Ok(millis) = cmd["sleep ".len()..].trim().parse::<u64>() {
+                // runs in the background, the shell keeps taking input
+                let handle = spawner.spawn_named("sleep", async move {
+                    time::sleep(Duration::from_millis(millis)).await;
+                    println!("\nslept {} ms", millis);
+                });
+                println!("task {}", handle.id());
+            } else {
+                println!("usage: sleep <milliseconds>");
+            }
+        }
+        "threads" => {
+            println!(
+                "{:>5} {:8} {:8} {:>8} name",
+                "id", "priority", "state", "ticks"
+            );
+            for thread in thread::threads() {
+                println!(
+                    "{:>5} {:8} {:8} {:>8} {}",
+                    thread.id, thread.priority, thread.state, thread.ticks, thread.name
+                );
+            }
+        }
+        _ if cmd.starts_with("spin ") => {
+            if let Ok(millis) = cmd["spin ".len()..].trim().parse::<u64>() {
+                // never yields, the timer has to preempt it
+                let spawned = thread::spawn_named("spin", Priority::Low, move || {
+                    let start = time::Instant::now();
+                    while start.elapsed() < Duration::from_millis(millis) {
+                        core::hint::spin_loop();
+                    }
+                    println!("\nspun {} ms", millis);
+                });
+                match spawned {
+                    Ok(handle) => println!("thread {}", handle.id()),
+                    Err(err) => println!("failed to start a thread: {}", err),
+                }
+            } else {
+                println!("usage: spin <milliseconds>");
+            }
         }
         _ => println!("unknown command or misusage: {}", cmd),
     };
 }
 
+fn print_acpi() {
+    let Some(acpi) = acpi::get() else {
+        println!("no ACPI tables");
+        return;
+    };
+
+    println!(
+        "ACPI revision {} by {}, RSDP at {:#x}, tables listed in the {}",
+        acpi.revision,
+        acpi.oem_id(),
+        acpi.rsdp_address.as_u64(),
+        if acpi.xsdt { "XSDT" } else { "RSDT" }
+    );
+    println!("sig  {:>12} {:>6} rev oem    table id", "address", "length");
+    for table in &acpi.tables {
+        let length = table.header.length;
+        println!(
+            "{:4} {:>#12x} {:>6} {:>3} {:6} {:8}{}",
+            table.signature(),
+            table.address.as_u64(),
+            length,
+            table.header.revision,
+            table.oem_id(),
+            table.oem_table_id(),
+            if table.checksum_ok {
+                ""
+            } else {
+                " bad checksum"
+            }
+        );
+    }
+
+    if let Some(madt) = &acpi.madt {
+        println!(
+            "MADT: {} CPU(s), {} IOAPIC(s), {} override(s), local APIC at {:#x}",
+            madt.local_apics.len(),
+            madt.io_apics.len(),
+            madt.overrides.len(),
+            madt.local_apic_address.as_u64()
+        );
+    }
+    if let Some(fadt) = &acpi.fadt {
+        println!(
+            "FADT: SCI IRQ {}, PM1a control {:#x}, PM1b control {:#x}, PM timer {:#x}, century register {:#x}",
+            fadt.sci_interrupt,
+            fadt.pm1a_control_block,
+            fadt.pm1b_control_block,
+            fadt.pm_timer_block,
+            fadt.century_register
+        );
+        if let Some(reset) = fadt.reset_register {
+            println!(
+                "      reset by writing {:#x} to {:?} {:#x}",
+                fadt.reset_value, reset.space, reset.address
+            );
+        }
+    }
+    if let Some(hpet) = &acpi.hpet {
+        println!(
+            "HPET: at {:#x}, {} comparator(s), {} bit counter",
+            hpet.address.as_u64(),
+            hpet.comparators,
+            if hpet.counter_64_bit { 64 } else { 32 }
+        );
+    }
+    if let Some(mcfg) = &acpi.mcfg {
+        for region in &mcfg.regions {
+            println!(
+                "MCFG: segment {} buses {}-{} at {:#x}",
+                region.segment,
+                region.start_bus,
+                region.end_bus,
+                region.base_address.as_u64()
+            );
+        }
+    }
+}
+
 pub fn join_paths(path: &str, next: &str, out: &mut String) {
     out.clear();
     if !next.starts_with(FS_SEP) {
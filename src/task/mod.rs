@@ -1,9 +1,12 @@
 pub mod executor;
+pub mod join;
 pub mod keyboard;
 pub mod simple_executor;
+pub mod sync;
 
 extern crate alloc;
 use core::{
+    fmt,
     future::Future,
     pin::Pin,
     sync::atomic::AtomicU64,
@@ -12,30 +15,95 @@ use core::{
 
 use alloc::boxed::Box;
 
+use crate::allocator::slab::SlabCache;
+
+/// Tasks are created and dropped all the time, so the executor keeps them in
+/// their own slab cache.
+static TASK_CACHE: SlabCache<Task> = SlabCache::new("task");
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, core::sync::atomic::Ordering::Relaxed))
     }
+
+    pub fn from_u64(id: u64) -> Self {
+        TaskId(id)
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
 }
 
 pub struct Task {
     id: TaskId,
-    future: Pin<Box<dyn Future<Output = ()>>>,
+    name: &'static str,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    /// Tasks move between CPUs, so the future has to be `Send`. The task is
+    /// named after the future's type.
+    pub fn new<F: Future<Output = ()> + Send + 'static>(future: F) -> Self {
+        Self::named(type_name_of::<F>(), future)
+    }
+
+    /// A task with the name `ps` shows for it.
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + Send + 'static) -> Self {
         Self {
             id: TaskId::new(),
+            name,
             future: Box::pin(future),
         }
     }
 
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
+
+/// The type name of `F`, without the `{{closure}}` async functions end in.
+fn type_name_of<F>() -> &'static str {
+    let name = core::any::type_name::<F>();
+    name.strip_suffix("::{{closure}}").unwrap_or(name)
+}
+
+/// Lets the executor run other tasks before this one continues.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
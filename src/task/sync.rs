@@ -0,0 +1,92 @@
+// Synchronization for tasks. Waiting gives the CPU back to the executor
+// instead of spinning, and everything that only wakes others (sending on a
+// channel, `Notify::notify_one`, `Semaphore::add_permits`, ...) never waits,
+// so interrupt handlers can use it too.
+
+extern crate alloc;
+
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+pub mod rwlock;
+pub mod semaphore;
+
+use core::task::Waker;
+
+use alloc::collections::VecDeque;
+
+pub use self::{
+    mutex::{Mutex, MutexGuard},
+    notify::Notify,
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    semaphore::{Semaphore, SemaphorePermit},
+};
+
+/// Tasks waiting for a primitive, oldest first. Lives inside the
+/// primitive's `IrqMutex`, wakers are only woken once that is released.
+struct Waiters {
+    next_key: u64,
+    queue: VecDeque<(u64, Waker)>,
+}
+
+impl Waiters {
+    const fn new() -> Self {
+        Self {
+            next_key: 0,
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Queues the waker, or updates it if `key` is queued already.
+    fn register(&mut self, key: &mut Option<u64>, waker: &Waker) {
+        if let Some(key) = *key {
+            if let Some((_, queued)) = self.queue.iter_mut().find(|(queued, _)| *queued == key) {
+                if !queued.will_wake(waker) {
+                    *queued = waker.clone();
+                }
+                return;
+            }
+        }
+
+        let new_key = self.next_key;
+        self.next_key += 1;
+        self.queue.push_back((new_key, waker.clone()));
+        *key = Some(new_key);
+    }
+
+    /// Whether `key` still waits, it was woken otherwise.
+    fn contains(&self, key: u64) -> bool {
+        self.queue.iter().any(|(queued, _)| *queued == key)
+    }
+
+    /// Removes the waiter, returns `false` if it was woken already.
+    fn remove(&mut self, key: u64) -> bool {
+        match self.queue.iter().position(|(queued, _)| *queued == key) {
+            Some(index) => {
+                self.queue.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Takes the oldest waiter out, to be woken once the lock is released.
+    fn pop(&mut self) -> Option<Waker> {
+        self.queue.pop_front().map(|(_, waker)| waker)
+    }
+
+    fn take_all(&mut self) -> VecDeque<(u64, Waker)> {
+        core::mem::take(&mut self.queue)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+fn wake_all(waiters: VecDeque<(u64, Waker)>) {
+    for (_, waker) in waiters {
+        waker.wake();
+    }
+}
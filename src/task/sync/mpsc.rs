@@ -0,0 +1,307 @@
+extern crate alloc;
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use alloc::{collections::VecDeque, sync::Arc};
+use futures_util::{task::AtomicWaker, Stream};
+
+use crate::sync::{level, IrqMutex};
+
+use super::{wake_all, Waiters};
+
+/// The receiver is gone, the value comes back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// a bounded channel has no room left
+    Full(T),
+    Closed(T),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// every sender is gone and nothing is left to receive
+    Closed,
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel closed")
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel closed"),
+        }
+    }
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel empty"),
+            TryRecvError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    /// senders waiting for room in a bounded channel
+    waiters: Waiters,
+}
+
+struct Channel<T> {
+    state: IrqMutex<State<T>>,
+    /// `None` for unbounded channels
+    capacity: Option<usize>,
+    receiver: AtomicWaker,
+}
+
+/// A bounded channel, `send` waits while `capacity` values are queued. The
+/// queue is allocated up front, so `try_send` never allocates and is safe to
+/// use from interrupt handlers.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity has to be at least 1");
+    new(VecDeque::with_capacity(capacity), Some(capacity))
+}
+
+/// A channel that never makes senders wait. Sending allocates when the queue
+/// grows, interrupt handlers should use a bounded channel.
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    new(VecDeque::new(), None)
+}
+
+fn new<T>(queue: VecDeque<T>, capacity: Option<usize>) -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        state: IrqMutex::ordered(
+            "channel",
+            level::TASK_SYNC,
+            State {
+                queue,
+                senders: 1,
+                receiver_alive: true,
+                waiters: Waiters::new(),
+            },
+        ),
+        capacity,
+        receiver: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Waits for room in a bounded channel, then queues `value`.
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+            key: None,
+        }
+    }
+
+    /// Queues `value` without waiting.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        {
+            let mut state = self.channel.state.lock();
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if self.is_full(&state) {
+                return Err(TrySendError::Full(value));
+            }
+            state.queue.push_back(value);
+        }
+        self.channel.receiver.wake();
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.channel.state.lock().receiver_alive
+    }
+
+    fn is_full(&self, state: &State<T>) -> bool {
+        self.channel
+            .capacity
+            .is_some_and(|capacity| state.queue.len() >= capacity)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.state.lock().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.channel.state.lock();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.channel.receiver.wake();
+        }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    key: Option<u64>,
+}
+
+// the value is only moved, never pinned
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let value = this
+            .value
+            .take()
+            .expect("SendFuture polled after it completed");
+        let channel = &this.sender.channel;
+
+        {
+            let mut state = channel.state.lock();
+            if !state.receiver_alive {
+                return Poll::Ready(Err(SendError(value)));
+            }
+            // senders that were woken go first
+            let waited = this.key.is_some_and(|key| !state.waiters.contains(key));
+            if this.sender.is_full(&state) || !(waited || state.waiters.is_empty()) {
+                state.waiters.register(&mut this.key, context.waker());
+                this.value = Some(value);
+                return Poll::Pending;
+            }
+            if let Some(key) = this.key.take() {
+                state.waiters.remove(key);
+            }
+            state.queue.push_back(value);
+        }
+
+        channel.receiver.wake();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        let Some(key) = self.key else {
+            return;
+        };
+
+        let waker = {
+            let mut state = self.sender.channel.state.lock();
+            // woken for room it will not use, pass it on
+            if !state.waiters.remove(key) && !self.sender.is_full(&state) {
+                state.waiters.pop()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Receives what all the senders send, in order. Also a `Stream` that ends
+/// once every sender is gone.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// `None` once every sender is gone and the queue is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        core::future::poll_fn(|context| self.poll_recv(context)).await
+    }
+
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let (value, waker) = {
+            let mut state = self.channel.state.lock();
+            match state.queue.pop_front() {
+                Some(value) => (value, state.waiters.pop()),
+                None if state.senders == 0 => return Err(TryRecvError::Closed),
+                None => return Err(TryRecvError::Empty),
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(value)
+    }
+
+    pub fn poll_recv(&mut self, context: &mut Context) -> Poll<Option<T>> {
+        // fast path
+        match self.try_recv() {
+            Ok(value) => return Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+
+        self.channel.receiver.register(context.waker());
+        match self.try_recv() {
+            Ok(value) => {
+                self.channel.receiver.take();
+                Poll::Ready(Some(value))
+            }
+            Err(TryRecvError::Closed) => {
+                self.channel.receiver.take();
+                Poll::Ready(None)
+            }
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(context)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let waiters = {
+            let mut state = self.channel.state.lock();
+            state.receiver_alive = false;
+            state.waiters.take_all()
+        };
+        wake_all(waiters);
+    }
+}
@@ -0,0 +1,166 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::sync::{level, IrqMutex};
+
+use super::Waiters;
+
+struct State {
+    locked: bool,
+    waiters: Waiters,
+}
+
+/// A mutex for tasks, `lock` waits without blocking the CPU so the guard can
+/// be held across an `.await`.
+pub struct Mutex<T> {
+    state: IrqMutex<State>,
+    value: UnsafeCell<T>,
+}
+
+// the value is only reached through a guard, which the lock makes unique
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqMutex::ordered(
+                "task mutex",
+                level::TASK_SYNC,
+                State {
+                    locked: false,
+                    waiters: Waiters::new(),
+                },
+            ),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> Lock<'_, T> {
+        Lock {
+            mutex: self,
+            key: None,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            return None;
+        }
+        state.locked = true;
+        Some(MutexGuard { mutex: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.locked = false;
+            state.waiters.pop()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex")
+            .field("locked", &self.state.lock().locked)
+            .finish()
+    }
+}
+
+/// Future returned by `Mutex::lock`.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    /// place in the waiters, once it had to wait
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+        if !state.locked {
+            state.locked = true;
+            if let Some(key) = self.key.take() {
+                state.waiters.remove(key);
+            }
+            return Poll::Ready(MutexGuard { mutex });
+        }
+
+        state.waiters.register(&mut self.key, context.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Lock<'_, T> {
+    fn drop(&mut self) {
+        let Some(key) = self.key else {
+            return;
+        };
+
+        let waker = {
+            let mut state = self.mutex.state.lock();
+            // woken for an unlock it will not use, pass it on
+            if !state.waiters.remove(key) && !state.locked {
+                state.waiters.pop()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
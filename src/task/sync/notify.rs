@@ -0,0 +1,190 @@
+extern crate alloc;
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use alloc::vec::Vec;
+
+use crate::sync::{level, IrqMutex};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notified {
+    No,
+    /// by `notify_one`, handed on if the waiter goes away without seeing it
+    One,
+    /// by `notify_waiters`
+    All,
+}
+
+struct Waiter {
+    key: u64,
+    waker: Waker,
+    notified: Notified,
+}
+
+struct State {
+    /// `notify_one` without anyone waiting, the next `notified` completes
+    /// right away
+    permit: bool,
+    next_key: u64,
+    /// stay here once notified until their future sees it
+    waiters: Vec<Waiter>,
+}
+
+/// Wakes tasks waiting for an event, like a condition variable without the
+/// lock. Both ways to notify can be used from interrupt handlers.
+pub struct Notify {
+    state: IrqMutex<State>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: IrqMutex::ordered(
+                "notify",
+                level::TASK_SYNC,
+                State {
+                    permit: false,
+                    next_key: 0,
+                    waiters: Vec::new(),
+                },
+            ),
+        }
+    }
+
+    /// Completes once the notify is notified after this was first polled.
+    pub fn notified(&self) -> NotifiedFuture<'_> {
+        NotifiedFuture {
+            notify: self,
+            key: None,
+        }
+    }
+
+    /// Wakes the longest waiting task, or lets the next `notified` complete
+    /// right away when nobody is waiting.
+    pub fn notify_one(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            Self::notify_one_locked(&mut state)
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    fn notify_one_locked(state: &mut State) -> Option<Waker> {
+        match state
+            .waiters
+            .iter_mut()
+            .find(|waiter| waiter.notified == Notified::No)
+        {
+            Some(waiter) => {
+                waiter.notified = Notified::One;
+                Some(waiter.waker.clone())
+            }
+            None => {
+                state.permit = true;
+                None
+            }
+        }
+    }
+
+    /// Wakes every task waiting right now, without storing a permit.
+    pub fn notify_waiters(&self) {
+        let wakers: Vec<Waker> = {
+            let mut state = self.state.lock();
+            state
+                .waiters
+                .iter_mut()
+                .filter(|waiter| waiter.notified == Notified::No)
+                .map(|waiter| {
+                    waiter.notified = Notified::All;
+                    waiter.waker.clone()
+                })
+                .collect()
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `Notify::notified`.
+pub struct NotifiedFuture<'a> {
+    notify: &'a Notify,
+    key: Option<u64>,
+}
+
+impl Future for NotifiedFuture<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        let mut state = self.notify.state.lock();
+
+        let Some(key) = self.key else {
+            if core::mem::take(&mut state.permit) {
+                return Poll::Ready(());
+            }
+            let key = state.next_key;
+            state.next_key += 1;
+            state.waiters.push(Waiter {
+                key,
+                waker: context.waker().clone(),
+                notified: Notified::No,
+            });
+            drop(state);
+            self.key = Some(key);
+            return Poll::Pending;
+        };
+
+        let index = state
+            .waiters
+            .iter()
+            .position(|waiter| waiter.key == key)
+            .expect("notified future lost its waiter");
+        let waiter = &mut state.waiters[index];
+        if waiter.notified != Notified::No {
+            state.waiters.swap_remove(index);
+            drop(state);
+            self.key = None;
+            return Poll::Ready(());
+        }
+        if !waiter.waker.will_wake(context.waker()) {
+            waiter.waker = context.waker().clone();
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for NotifiedFuture<'_> {
+    fn drop(&mut self) {
+        let Some(key) = self.key else {
+            return;
+        };
+
+        let waker = {
+            let mut state = self.notify.state.lock();
+            let Some(index) = state.waiters.iter().position(|waiter| waiter.key == key) else {
+                return;
+            };
+            let waiter = state.waiters.swap_remove(index);
+            if waiter.notified == Notified::One {
+                Notify::notify_one_locked(&mut state)
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
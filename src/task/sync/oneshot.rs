@@ -0,0 +1,128 @@
+extern crate alloc;
+
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use alloc::sync::Arc;
+use futures_util::task::AtomicWaker;
+
+use crate::sync::{level, IrqMutex};
+
+/// The sender was dropped without sending.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sender dropped without sending")
+    }
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+}
+
+struct Channel<T> {
+    state: IrqMutex<State<T>>,
+    receiver: AtomicWaker,
+}
+
+/// A channel for a single value, e.g. to get a result back from an interrupt
+/// handler or another task.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let channel = Arc::new(Channel {
+        state: IrqMutex::ordered(
+            "oneshot",
+            level::TASK_SYNC,
+            State {
+                value: None,
+                sender_alive: true,
+                receiver_alive: true,
+            },
+        ),
+        receiver: AtomicWaker::new(),
+    });
+
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Hands `value` to the receiver, or back if the receiver is gone. Never
+    /// waits.
+    pub fn send(self, value: T) -> Result<(), T> {
+        {
+            let mut state = self.channel.state.lock();
+            if !state.receiver_alive {
+                return Err(value);
+            }
+            state.value = Some(value);
+        }
+        // dropping self wakes the receiver
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        !self.channel.state.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().sender_alive = false;
+        self.channel.receiver.wake();
+    }
+}
+
+/// Resolves to the value, or `RecvError` if the sender was dropped first.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// `None` while the value has not been sent yet.
+    pub fn try_recv(&mut self) -> Option<Result<T, RecvError>> {
+        let mut state = self.channel.state.lock();
+        match state.value.take() {
+            Some(value) => Some(Ok(value)),
+            None if !state.sender_alive => Some(Err(RecvError)),
+            None => None,
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Some(result) = self.try_recv() {
+            return Poll::Ready(result);
+        }
+
+        self.channel.receiver.register(context.waker());
+        match self.try_recv() {
+            Some(result) => Poll::Ready(result),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.channel.state.lock().receiver_alive = false;
+    }
+}
@@ -0,0 +1,257 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::sync::{level, IrqMutex};
+
+use super::{wake_all, Waiters};
+
+struct State {
+    readers: usize,
+    writer: bool,
+    /// readers and writers, all woken whenever the lock becomes free
+    waiters: Waiters,
+}
+
+/// A reader-writer lock for tasks. New readers wait while a writer is
+/// waiting, so a steady stream of readers cannot starve writers.
+pub struct RwLock<T> {
+    state: IrqMutex<State>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqMutex::ordered(
+                "task rwlock",
+                level::TASK_SYNC,
+                State {
+                    readers: 0,
+                    writer: false,
+                    waiters: Waiters::new(),
+                },
+            ),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> Read<'_, T> {
+        Read {
+            lock: self,
+            key: None,
+        }
+    }
+
+    pub fn write(&self) -> Write<'_, T> {
+        Write {
+            lock: self,
+            key: None,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || !state.waiters.is_empty() {
+            return None;
+        }
+        state.readers += 1;
+        Some(RwLockReadGuard { lock: self })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.writer || state.readers > 0 {
+            return None;
+        }
+        state.writer = true;
+        Some(RwLockWriteGuard { lock: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn release(&self, write: bool) {
+        let waiters = {
+            let mut state = self.state.lock();
+            if write {
+                state.writer = false;
+            } else {
+                state.readers -= 1;
+            }
+            if state.writer || state.readers > 0 {
+                return;
+            }
+            state.waiters.take_all()
+        };
+        wake_all(waiters);
+    }
+
+    /// Shared by `Read` and `Write`, `write` says which one is asking.
+    fn poll_acquire(&self, key: &mut Option<u64>, write: bool, context: &Context) -> bool {
+        let mut state = self.state.lock();
+        // a waiter that was woken goes first, others queue behind the waiters
+        let woken = key.is_some_and(|key| !state.waiters.contains(key));
+        let free = if write {
+            !state.writer && state.readers == 0
+        } else {
+            !state.writer && (woken || state.waiters.is_empty())
+        };
+
+        if free {
+            if let Some(key) = key.take() {
+                state.waiters.remove(key);
+            }
+            if write {
+                state.writer = true;
+            } else {
+                state.readers += 1;
+            }
+            return true;
+        }
+
+        state.waiters.register(key, context.waker());
+        false
+    }
+
+    fn cancel(&self, key: Option<u64>) {
+        let Some(key) = key else {
+            return;
+        };
+
+        let waiters = {
+            let mut state = self.state.lock();
+            let woken = !state.waiters.remove(key);
+            if woken && !state.writer && state.readers == 0 {
+                // the lock is free and this waiter will not take it
+                state.waiters.take_all()
+            } else {
+                Default::default()
+            }
+        };
+        wake_all(waiters);
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("RwLock")
+            .field("readers", &state.readers)
+            .field("writer", &state.writer)
+            .finish()
+    }
+}
+
+/// Future returned by `RwLock::read`.
+pub struct Read<'a, T> {
+    lock: &'a RwLock<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for Read<'a, T> {
+    type Output = RwLockReadGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let lock = self.lock;
+        if lock.poll_acquire(&mut self.key, false, context) {
+            Poll::Ready(RwLockReadGuard { lock })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Read<'_, T> {
+    fn drop(&mut self) {
+        self.lock.cancel(self.key);
+    }
+}
+
+/// Future returned by `RwLock::write`.
+pub struct Write<'a, T> {
+    lock: &'a RwLock<T>,
+    key: Option<u64>,
+}
+
+impl<'a, T> Future for Write<'a, T> {
+    type Output = RwLockWriteGuard<'a, T>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let lock = self.lock;
+        if lock.poll_acquire(&mut self.key, true, context) {
+            Poll::Ready(RwLockWriteGuard { lock })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Write<'_, T> {
+    fn drop(&mut self) {
+        self.lock.cancel(self.key);
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(false);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+unsafe impl<T: Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.release(true);
+    }
+}
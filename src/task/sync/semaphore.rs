@@ -0,0 +1,136 @@
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::sync::{level, IrqMutex};
+
+use super::Waiters;
+
+struct State {
+    permits: usize,
+    waiters: Waiters,
+}
+
+/// Hands out a limited number of permits, tasks wait for one when none are
+/// left. `add_permits` can be used from interrupt handlers.
+pub struct Semaphore {
+    state: IrqMutex<State>,
+}
+
+impl Semaphore {
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: IrqMutex::ordered(
+                "semaphore",
+                level::TASK_SYNC,
+                State {
+                    permits,
+                    waiters: Waiters::new(),
+                },
+            ),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().permits
+    }
+
+    /// Waits for a permit, which is returned when the `SemaphorePermit` is
+    /// dropped.
+    pub fn acquire(&self) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            key: None,
+        }
+    }
+
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock();
+        // waiting tasks go first
+        if state.permits == 0 || !state.waiters.is_empty() {
+            return None;
+        }
+        state.permits -= 1;
+        Some(SemaphorePermit { semaphore: self })
+    }
+
+    /// Adds permits and wakes as many waiting tasks.
+    pub fn add_permits(&self, count: usize) {
+        self.state.lock().permits += count;
+        // one at a time, wakers never run with the lock held
+        for _ in 0..count {
+            let Some(waker) = self.state.lock().waiters.pop() else {
+                break;
+            };
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by `Semaphore::acquire`.
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    key: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        let semaphore = self.semaphore;
+        let mut state = semaphore.state.lock();
+        // tasks that were woken may take a permit, the rest wait their turn
+        let may_take = match self.key {
+            Some(key) => !state.waiters.contains(key),
+            None => state.waiters.is_empty(),
+        };
+        if may_take && state.permits > 0 {
+            state.permits -= 1;
+            self.key = None;
+            return Poll::Ready(SemaphorePermit { semaphore });
+        }
+
+        state.waiters.register(&mut self.key, context.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let Some(key) = self.key else {
+            return;
+        };
+
+        let waker = {
+            let mut state = self.semaphore.state.lock();
+            // woken for a permit it will not take, pass it on
+            if !state.waiters.remove(key) && state.permits > 0 {
+                state.waiters.pop()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl SemaphorePermit<'_> {
+    /// Keeps the permit taken for good.
+    pub fn forget(self) {
+        core::mem::forget(self);
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.add_permits(1);
+    }
+}
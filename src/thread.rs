@@ -0,0 +1,563 @@
+extern crate alloc;
+
+mod switch;
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+use crate::{
+    cpu,
+    memory::{stack::KernelStack, vm::VmError},
+    sync::{self, level, IrqMutex},
+    time::{self, Instant},
+};
+
+const THREAD_STACK_SIZE: u64 = 4096 * 16;
+/// Timer ticks a thread runs before others of its priority get a turn.
+const TIME_SLICE: u64 = 10;
+const PRIORITIES: usize = 3;
+
+static SCHEDULER: IrqMutex<Scheduler> =
+    IrqMutex::ordered("scheduler", level::SCHEDULER, Scheduler::new());
+/// The current thread is halted in `idle` waiting for an interrupt.
+static IDLING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// Ready threads of a higher priority always run first, threads of the same
+/// priority take turns every `TIME_SLICE` ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Running,
+    Ready,
+    Sleeping,
+    /// waiting for a thread to finish
+    Blocked,
+    Finished,
+}
+
+impl fmt::Display for ThreadState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            ThreadState::Running => "running",
+            ThreadState::Ready => "ready",
+            ThreadState::Sleeping => "sleeping",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Finished => "finished",
+        })
+    }
+}
+
+/// A thread as `threads` lists it.
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub state: ThreadState,
+    /// timer ticks the thread was running for
+    pub ticks: u64,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    priority: Priority,
+    state: ThreadState,
+    /// saved stack pointer while the thread is not running
+    rsp: u64,
+    /// `None` for the boot thread, which runs on the bootloader's stack
+    stack: Option<KernelStack>,
+    /// tick a sleeping thread wakes up at
+    wake_at: u64,
+    /// threads blocked in `join` on this one
+    joiners: Vec<ThreadId>,
+    ticks: u64,
+}
+
+impl Thread {
+    fn new(name: &'static str, priority: Priority, stack: Option<KernelStack>) -> Self {
+        Self {
+            id: ThreadId::new(),
+            name,
+            priority,
+            state: ThreadState::Ready,
+            rsp: 0,
+            stack,
+            wake_at: 0,
+            joiners: Vec::new(),
+            ticks: 0,
+        }
+    }
+}
+
+struct Scheduler {
+    /// boxed so the saved stack pointers stay put while the map changes
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: [VecDeque<ThreadId>; PRIORITIES],
+    /// `None` before `init`
+    current: Option<ThreadId>,
+    /// runs when nothing else is ready, never queued
+    idle: Option<ThreadId>,
+    /// CPU the scheduler runs on, the one the timer interrupt arrives at
+    cpu: usize,
+    slice_left: u64,
+    /// earliest `wake_at` of the sleeping threads
+    next_wake: u64,
+    /// finished threads, their stacks are freed once another thread runs. Not
+    /// touched between picking the next thread and switching to it, so the
+    /// outgoing thread can still save its stack pointer in here.
+    finished: Vec<Thread>,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Self {
+            threads: BTreeMap::new(),
+            ready: [const { VecDeque::new() }; PRIORITIES],
+            current: None,
+            idle: None,
+            cpu: 0,
+            slice_left: TIME_SLICE,
+            next_wake: u64::MAX,
+            finished: Vec::new(),
+        }
+    }
+
+    fn is_running_here(&self) -> bool {
+        self.current.is_some() && cpu::try_current().map_or(0, |cpu| cpu.index()) == self.cpu
+    }
+
+    fn current_mut(&mut self) -> &mut Thread {
+        let id = self.current.expect("scheduler is not running");
+        self.threads.get_mut(&id).expect("current thread is gone")
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        let Some(thread) = self.threads.get_mut(&id) else {
+            return;
+        };
+        if Some(id) == self.idle || thread.state == ThreadState::Ready {
+            return;
+        }
+        thread.state = ThreadState::Ready;
+        self.ready[thread.priority as usize].push_back(id);
+    }
+
+    fn highest_ready(&self) -> Option<Priority> {
+        [Priority::High, Priority::Normal, Priority::Low]
+            .into_iter()
+            .find(|priority| !self.ready[*priority as usize].is_empty())
+    }
+
+    fn pop_ready(&mut self) -> Option<ThreadId> {
+        let priority = self.highest_ready()?;
+        self.ready[priority as usize].pop_front()
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        if now < self.next_wake {
+            return;
+        }
+
+        self.next_wake = u64::MAX;
+        for thread in self.threads.values_mut() {
+            if thread.state != ThreadState::Sleeping {
+                continue;
+            }
+            if thread.wake_at <= now {
+                thread.state = ThreadState::Ready;
+                self.ready[thread.priority as usize].push_back(thread.id);
+            } else {
+                self.next_wake = self.next_wake.min(thread.wake_at);
+            }
+        }
+    }
+
+    /// Picks the thread to run next. A current thread that is still runnable
+    /// keeps running when nothing else is ready. Returns where to save the
+    /// current stack pointer and the one to switch to.
+    fn reschedule(&mut self) -> Option<(*mut u64, u64)> {
+        let current_id = self.current?;
+        let idle = self.idle.expect("scheduler has no idle thread");
+        let current = self.threads.get_mut(&current_id)?;
+        let runnable = matches!(current.state, ThreadState::Running | ThreadState::Ready);
+
+        let next_id = match self.pop_ready() {
+            Some(next) => next,
+            None if runnable && current_id != idle => {
+                self.current_mut().state = ThreadState::Running;
+                return None;
+            }
+            None => idle,
+        };
+        if next_id == current_id {
+            self.current_mut().state = ThreadState::Running;
+            return None;
+        }
+
+        if runnable {
+            // back to the end of its queue, `make_ready` skips threads that
+            // are already marked ready and the idle thread
+            self.current_mut().state = ThreadState::Running;
+            self.make_ready(current_id);
+        }
+
+        let current = if self.current_mut().state == ThreadState::Finished {
+            let thread = self
+                .threads
+                .remove(&current_id)
+                .expect("current thread is gone");
+            self.finished.push(*thread);
+            self.finished.last_mut().unwrap()
+        } else {
+            self.threads.get_mut(&current_id).unwrap()
+        };
+        let old_rsp = &mut current.rsp as *mut u64;
+
+        let next = self
+            .threads
+            .get_mut(&next_id)
+            .expect("ready thread is gone");
+        next.state = ThreadState::Running;
+        let new_rsp = next.rsp;
+
+        self.current = Some(next_id);
+        self.slice_left = TIME_SLICE;
+        IDLING.store(false, Ordering::Relaxed);
+        Some((old_rsp, new_rsp))
+    }
+}
+
+/// Turns the running code into the first thread, `main`, and starts the idle
+/// thread. Threads only run on the CPU this is called on, the one the timer
+/// interrupt is delivered to. Needs the heap.
+pub fn init() -> Result<(), VmError> {
+    let stack = KernelStack::new(THREAD_STACK_SIZE, "idle thread")?;
+    let idle = prepare(
+        Thread::new("idle", Priority::Low, Some(stack)),
+        Box::new(idle_main),
+    );
+
+    let mut main = Box::new(Thread::new("main", Priority::Normal, None));
+    main.state = ThreadState::Running;
+
+    let cpu = cpu::try_current().map_or(0, |cpu| cpu.index());
+    {
+        let mut scheduler = SCHEDULER.lock();
+        scheduler.cpu = cpu;
+        scheduler.idle = Some(idle.id);
+        scheduler.current = Some(main.id);
+        scheduler.threads.insert(idle.id, idle);
+        scheduler.threads.insert(main.id, main);
+    }
+
+    log::info!("Threads run on CPU {}", cpu);
+    Ok(())
+}
+
+fn idle_main() {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Points a new thread's stack at `thread_main`, which runs `main`.
+fn prepare(thread: Thread, main: Box<dyn FnOnce() + Send>) -> Box<Thread> {
+    let mut thread = Box::new(thread);
+    let top = thread
+        .stack
+        .as_ref()
+        .expect("new threads have a stack")
+        .top();
+    let argument = Box::into_raw(Box::new(main)) as u64;
+    thread.rsp = unsafe { switch::initial_stack(top.as_u64(), argument) };
+    thread
+}
+
+/// First code a new thread runs, entered from `thread_trampoline` with
+/// interrupts disabled.
+extern "C" fn thread_main(main: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(main) };
+    interrupts::enable();
+    reap();
+
+    main();
+    exit()
+}
+
+/// Frees the stacks of finished threads. Never called from the timer
+/// interrupt, unmapping takes locks the interrupted code may hold.
+fn reap() {
+    let finished = core::mem::take(&mut SCHEDULER.lock().finished);
+    drop(finished);
+}
+
+/// Runs `f` and then a thread switch, atomically. `f` says what the current
+/// thread becomes, `None` keeps running it. Panics when the running code is
+/// not a thread, e.g. on another CPU, `current` is what the scheduler's CPU
+/// runs.
+fn switch_with(f: impl FnOnce(&mut Scheduler, ThreadId) -> Option<ThreadState>) {
+    sync::assert_no_locks_held("switching threads");
+
+    let enabled = interrupts::are_enabled();
+    interrupts::disable();
+    let switch = {
+        let mut scheduler = SCHEDULER.lock();
+        if !scheduler.is_running_here() {
+            drop(scheduler);
+            panic!("thread switch requested outside of a thread");
+        }
+        let current = scheduler.current.unwrap();
+        match f(&mut scheduler, current) {
+            Some(state) => {
+                scheduler.current_mut().state = state;
+                scheduler.reschedule()
+            }
+            None => None,
+        }
+    };
+
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { switch::switch(old_rsp, new_rsp) };
+    }
+    if enabled {
+        interrupts::enable();
+        reap();
+    }
+}
+
+/// Whether the running code is a thread the scheduler can switch away from.
+fn is_thread() -> bool {
+    SCHEDULER.lock().is_running_here()
+}
+
+/// Called from the timer interrupt after the end of interrupt was sent. Wakes
+/// sleeping threads and switches when the time slice is over, a thread of
+/// higher priority became ready or the current thread was idling.
+pub(crate) fn preempt() {
+    let switch = {
+        let mut scheduler = SCHEDULER.lock();
+        if !scheduler.is_running_here() {
+            return;
+        }
+        scheduler.wake_sleepers(time::ticks());
+        scheduler.current_mut().ticks += 1;
+        scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+
+        let Some(ready) = scheduler.highest_ready() else {
+            return;
+        };
+        let current = scheduler.current_mut().priority;
+        let idle = scheduler.current == scheduler.idle || IDLING.load(Ordering::Relaxed);
+        // lower priorities only get a turn when higher ones block or idle
+        let slice_over = scheduler.slice_left == 0 && ready >= current;
+        if !(idle || ready > current || slice_over) {
+            return;
+        }
+        scheduler.reschedule()
+    };
+
+    if let Some((old_rsp, new_rsp)) = switch {
+        unsafe { switch::switch(old_rsp, new_rsp) };
+    }
+}
+
+/// Starts a thread running `f` with normal priority.
+pub fn spawn<F, T>(f: F) -> Result<JoinHandle<T>, VmError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_named("thread", Priority::Normal, f)
+}
+
+pub fn spawn_named<F, T>(
+    name: &'static str,
+    priority: Priority,
+    f: F,
+) -> Result<JoinHandle<T>, VmError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stack = KernelStack::new(THREAD_STACK_SIZE, name)?;
+    let result = Arc::new(IrqMutex::ordered("thread result", level::JOIN_HANDLE, None));
+    let slot = result.clone();
+    let thread = prepare(
+        Thread::new(name, priority, Some(stack)),
+        Box::new(move || {
+            let value = f();
+            *slot.lock() = Some(value);
+        }),
+    );
+
+    let id = thread.id;
+    let mut scheduler = SCHEDULER.lock();
+    scheduler.threads.insert(id, thread);
+    scheduler.make_ready(id);
+    Ok(JoinHandle { id, result })
+}
+
+/// Lets other ready threads run first, including ones of lower priority.
+pub fn yield_now() {
+    if !is_thread() {
+        core::hint::spin_loop();
+        return;
+    }
+    switch_with(|_, _| Some(ThreadState::Ready));
+}
+
+/// Blocks the thread for at least `duration`. Busy waits where no scheduler
+/// runs.
+pub fn sleep(duration: Duration) {
+    if !is_thread() {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    // one tick more, the current tick is already partly over
+    let wake_at = time::ticks() + time::duration_to_ticks(duration) + 1;
+    switch_with(|scheduler, _| {
+        scheduler.current_mut().wake_at = wake_at;
+        scheduler.next_wake = scheduler.next_wake.min(wake_at);
+        Some(ThreadState::Sleeping)
+    });
+}
+
+/// Ends the current thread. Its stack is freed once another thread runs.
+/// Panics when called outside of a thread.
+pub fn exit() -> ! {
+    switch_with(|scheduler, _| {
+        let joiners = core::mem::take(&mut scheduler.current_mut().joiners);
+        for joiner in joiners {
+            scheduler.make_ready(joiner);
+        }
+        Some(ThreadState::Finished)
+    });
+    unreachable!("finished thread was scheduled again");
+}
+
+/// For code with nothing to do, called with interrupts disabled. Lets other
+/// threads run, or halts until the next interrupt if there are none. Returns
+/// with interrupts enabled.
+pub fn idle() {
+    if !is_thread() {
+        interrupts::enable_and_hlt();
+        return;
+    }
+
+    let ready = SCHEDULER.lock().highest_ready().is_some();
+    if ready {
+        switch_with(|_, _| Some(ThreadState::Ready));
+        interrupts::enable();
+        reap();
+    } else {
+        // a timer tick with a thread ready switches away right away
+        IDLING.store(true, Ordering::Relaxed);
+        interrupts::enable_and_hlt();
+        IDLING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Every thread that has not finished, ordered by id.
+pub fn threads() -> Vec<ThreadInfo> {
+    SCHEDULER
+        .lock()
+        .threads
+        .values()
+        .map(|thread| ThreadInfo {
+            id: thread.id,
+            name: thread.name,
+            priority: thread.priority,
+            state: thread.state,
+            ticks: thread.ticks,
+        })
+        .collect()
+}
+
+/// Owns the result of a spawned thread. Dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    result: Arc<IrqMutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Whether the thread has returned.
+    pub fn is_finished(&self) -> bool {
+        self.result.lock().is_some()
+    }
+
+    /// Blocks until the thread returns and hands back its result. Spins where
+    /// no scheduler runs.
+    pub fn join(self) -> T {
+        loop {
+            if let Some(value) = self.result.lock().take() {
+                return value;
+            }
+
+            if !is_thread() {
+                core::hint::spin_loop();
+                continue;
+            }
+            switch_with(|scheduler, current| {
+                // the result is stored before the thread finishes
+                let thread = scheduler.threads.get_mut(&self.id)?;
+                if thread.state == ThreadState::Finished {
+                    return None;
+                }
+                thread.joiners.push(current);
+                Some(ThreadState::Blocked)
+            });
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use core::arch::global_asm;
+
+// `thread_switch(old_rsp, new_rsp)` pushes the callee saved registers, stores
+// the stack pointer through `old_rsp` and continues whatever thread saved
+// `new_rsp`. The caller saved registers are already on the stack by the time
+// it is called, either by the compiler or by the interrupt handler.
+//
+// A new thread's stack is laid out so the first switch to it returns into
+// `thread_trampoline` with its argument in r12.
+global_asm!(
+    ".global thread_switch",
+    "thread_switch:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    // the zero below the trampoline's address is the return address now,
+    // ending backtraces like the AP trampoline does
+    "jmp {entry}",
+    entry = sym super::thread_main,
+);
+
+extern "C" {
+    fn thread_switch(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+/// Registers `thread_switch` pops, in the order they are on the stack.
+const SAVED_REGISTERS: usize = 6;
+const R12: usize = 3;
+
+/// Prepares a fresh stack so switching to it calls `thread_main(argument)`.
+/// Returns the stack pointer to switch to.
+///
+/// # Safety
+///
+/// `stack_top` has to be the 16 byte aligned top of a mapped stack with room
+/// for a few words.
+pub(super) unsafe fn initial_stack(stack_top: u64, argument: u64) -> u64 {
+    let top = stack_top as *mut u64;
+    let mut frame = [0u64; SAVED_REGISTERS + 2];
+    frame[R12] = argument;
+    frame[SAVED_REGISTERS] = thread_trampoline as *const () as u64;
+    // frame[SAVED_REGISTERS + 1] stays 0, the fake return address
+
+    let rsp = top.sub(frame.len());
+    core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp, frame.len());
+    rsp as u64
+}
+
+/// Saves the running thread's registers into `old_rsp` and resumes the thread
+/// `new_rsp` belongs to. Returns once something switches back.
+///
+/// # Safety
+///
+/// Interrupts have to be disabled, `old_rsp` has to stay valid until the
+/// switch back and `new_rsp` has to come from `initial_stack` or an earlier
+/// switch.
+pub(super) unsafe fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    thread_switch(old_rsp, new_rsp);
+}
@@ -0,0 +1,150 @@
+pub mod hpet;
+pub mod instant;
+pub mod pit;
+pub mod rtc;
+pub mod timer;
+pub mod tsc;
+
+use core::{
+    sync::atomic::{AtomicU16, AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub use instant::{clocksource, Clocksource, Instant};
+pub use timer::{sleep, sleep_until, Sleep};
+
+/// Rate the timer interrupt is programmed to.
+pub const TICK_FREQUENCY: u64 = 1000;
+
+const NANOS_PER_SECOND: u128 = 1_000_000_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Input clock cycles per tick, the tick rate is `PIT_FREQUENCY / divisor`.
+static DIVISOR: AtomicU16 = AtomicU16::new(0);
+
+/// Programs the PIT to `TICK_FREQUENCY`, before that the BIOS rate of about
+/// 18.2 Hz is in effect and nothing counts ticks. Calibrates the TSC against
+/// the PIT and makes it the clock of `Instant` if it is invariant.
+pub fn init() {
+    let divisor = pit::set_frequency(TICK_FREQUENCY);
+    DIVISOR.store(divisor, Ordering::Relaxed);
+
+    if tsc::is_present() {
+        let frequency = tsc::calibrate_with_pit();
+        if tsc::is_invariant() {
+            instant::switch_to(Clocksource::Tsc, frequency);
+        }
+    }
+}
+
+/// Starts the HPET if ACPI describes one and recalibrates the TSC against it.
+/// `Instant` reads the TSC if it is invariant, otherwise the HPET if its
+/// counter is 64 bits wide, and counts timer interrupts if neither can be
+/// used. Needs the kernel address space.
+pub fn init_hpet() {
+    let hpet = hpet::init();
+    let invariant = tsc::is_invariant();
+
+    let frequency = match hpet {
+        Some(hpet) if tsc::is_present() => Some(tsc::calibrate_with_hpet(hpet)),
+        _ => tsc::frequency(),
+    };
+    match (frequency, hpet) {
+        (Some(frequency), _) if invariant => instant::switch_to(Clocksource::Tsc, frequency),
+        (_, Some(hpet)) if hpet.counter_is_64_bit() => {
+            instant::switch_to(Clocksource::Hpet, hpet.frequency())
+        }
+        _ => {}
+    }
+
+    match tsc::frequency() {
+        Some(frequency) => log::info!(
+            "TSC runs at {}.{:03} MHz{}",
+            frequency / 1_000_000,
+            frequency / 1000 % 1000,
+            if invariant { ", invariant" } else { "" }
+        ),
+        None => log::info!("No TSC"),
+    }
+    log::info!("Clocksource is {}", clocksource());
+}
+
+/// Called on every timer interrupt.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer interrupts since `init`, the monotonic clock of the kernel.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn divisor() -> u128 {
+    match DIVISOR.load(Ordering::Relaxed) {
+        0 => 65536,
+        divisor => divisor as u128,
+    }
+}
+
+/// Time covered by `ticks` timer interrupts.
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos = ticks as u128 * divisor() * NANOS_PER_SECOND / pit::PIT_FREQUENCY as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Timer interrupts needed to cover `duration`, rounded up.
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let tick_nanos = divisor() * NANOS_PER_SECOND;
+    let ticks = (duration.as_nanos() * pit::PIT_FREQUENCY as u128).div_ceil(tick_nanos);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// Time since `init`.
+pub fn uptime() -> Duration {
+    ticks_to_duration(ticks())
+}
+
+#[test_case]
+fn ticks_advance() {
+    let start = ticks();
+    while ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(uptime() >= ticks_to_duration(start + 5));
+}
+
+#[test_case]
+fn instant_measures_ticks() {
+    // start on a tick boundary so both clocks cover the same interval
+    let start_tick = ticks() + 1;
+    while ticks() < start_tick {
+        core::hint::spin_loop();
+    }
+    let start = Instant::now();
+    while ticks() < start_tick + 50 {
+        core::hint::spin_loop();
+    }
+    let elapsed = start.elapsed();
+
+    let expected = ticks_to_duration(50);
+    assert!(elapsed >= expected - expected / 10, "{:?}", elapsed);
+    assert!(elapsed <= expected + expected / 10, "{:?}", elapsed);
+}
+
+#[test_case]
+fn instant_is_monotonic() {
+    let mut last = Instant::now();
+    for _ in 0..1000 {
+        let now = Instant::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn duration_round_trip() {
+    let duration = Duration::from_millis(250);
+    let ticks = duration_to_ticks(duration);
+    assert!(ticks_to_duration(ticks) >= duration);
+    assert!(ticks_to_duration(ticks - 1) < duration);
+}
@@ -0,0 +1,105 @@
+use core::time::Duration;
+
+use spin::Once;
+
+use crate::{
+    acpi,
+    memory::vm::{ioremap, CacheMode, IoMapping},
+};
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xF0;
+const REGISTERS_SIZE: u64 = 0x400;
+
+const ENABLE: u64 = 1 << 0;
+const FEMTOSECONDS_PER_NANOSECOND: u64 = 1_000_000;
+
+static HPET: Once<Hpet> = Once::new();
+
+/// High precision event timer, only its free running main counter is used.
+pub struct Hpet {
+    mmio: IoMapping,
+    /// length of one counter tick
+    period_fs: u64,
+    /// 32 bit counters wrap every few minutes
+    counter_64_bit: bool,
+}
+
+impl Hpet {
+    /// Main counter, only the low 32 bits count if it is not 64 bits wide.
+    pub fn counter(&self) -> u64 {
+        if self.counter_64_bit {
+            self.mmio.read(MAIN_COUNTER)
+        } else {
+            self.mmio.read::<u32>(MAIN_COUNTER) as u64
+        }
+    }
+
+    /// Whether the counter is 64 bits wide and never wraps in practice, a 32
+    /// bit one is only good for measuring short intervals.
+    pub fn counter_is_64_bit(&self) -> bool {
+        self.counter_64_bit
+    }
+
+    /// Ticks from `start` to `end`, across at most one wrap of the counter.
+    pub fn ticks_between(&self, start: u64, end: u64) -> u64 {
+        if self.counter_64_bit {
+            end.wrapping_sub(start)
+        } else {
+            (end as u32).wrapping_sub(start as u32) as u64
+        }
+    }
+
+    /// Counter ticks per second.
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+
+    pub fn ticks_to_duration(&self, ticks: u64) -> Duration {
+        let nanos = ticks as u128 * self.period_fs as u128 / FEMTOSECONDS_PER_NANOSECOND as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// Maps and starts the HPET the ACPI tables describe, needs the kernel
+/// address space. Returns `None` if there is none.
+pub fn init() -> Option<&'static Hpet> {
+    let table = acpi::get()
+        .and_then(|acpi| acpi.hpet)
+        .or_else(acpi::hpet::parse)?;
+    let mmio = match ioremap(table.address, REGISTERS_SIZE, CacheMode::Uncached) {
+        Ok(mmio) => mmio,
+        Err(err) => {
+            log::error!("failed to map the HPET: {}", err);
+            return None;
+        }
+    };
+
+    let period_fs = mmio.read::<u64>(CAPABILITIES) >> 32;
+    if period_fs == 0 || period_fs > 100_000_000 {
+        log::error!("HPET reports an invalid period of {} fs", period_fs);
+        return None;
+    }
+
+    let configuration = mmio.read::<u64>(CONFIGURATION);
+    mmio.write(CONFIGURATION, configuration | ENABLE);
+
+    let hpet = HPET.call_once(|| Hpet {
+        mmio,
+        period_fs,
+        counter_64_bit: table.counter_64_bit,
+    });
+    log::info!(
+        "HPET at {:?} running at {} Hz, {} bit counter",
+        table.address,
+        hpet.frequency(),
+        if hpet.counter_64_bit { 64 } else { 32 }
+    );
+    Some(hpet)
+}
+
+/// The HPET once `init` found one.
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
+}
@@ -0,0 +1,214 @@
+use core::{
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+    time::Duration,
+};
+
+use x86_64::instructions::interrupts;
+
+use super::{hpet, tsc, NANOS_PER_SECOND};
+
+/// Counter `Instant::now` reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Clocksource {
+    /// timer interrupts, only millisecond resolution
+    Ticks,
+    Tsc,
+    Hpet,
+}
+
+impl Clocksource {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Clocksource::Tsc,
+            2 => Clocksource::Hpet,
+            _ => Clocksource::Ticks,
+        }
+    }
+
+    fn read(self) -> u64 {
+        match self {
+            Clocksource::Ticks => super::uptime().as_nanos() as u64,
+            Clocksource::Tsc => tsc::read(),
+            Clocksource::Hpet => hpet::get().map_or(0, |hpet| hpet.counter()),
+        }
+    }
+}
+
+impl fmt::Display for Clocksource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Clocksource::Ticks => write!(f, "ticks"),
+            Clocksource::Tsc => write!(f, "tsc"),
+            Clocksource::Hpet => write!(f, "hpet"),
+        }
+    }
+}
+
+/// Clock state, written rarely and read from anywhere including interrupt
+/// handlers, so it is guarded by a sequence count instead of a lock. Odd while
+/// a switch is in progress.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+static SOURCE: AtomicU8 = AtomicU8::new(Clocksource::Ticks as u8);
+/// Source counter and nanoseconds since boot at the last switch.
+static BASE_COUNTER: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOS: AtomicU64 = AtomicU64::new(0);
+/// Source counter rate in Hz, unused for `Ticks` which counts nanoseconds.
+static SOURCE_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+struct Clock {
+    source: Clocksource,
+    base_counter: u64,
+    base_nanos: u64,
+    frequency: u64,
+}
+
+impl Clock {
+    fn load() -> Self {
+        loop {
+            let sequence = SEQUENCE.load(Ordering::Acquire);
+            if sequence & 1 != 0 {
+                core::hint::spin_loop();
+                continue;
+            }
+
+            let clock = Clock {
+                source: Clocksource::from_u8(SOURCE.load(Ordering::Relaxed)),
+                base_counter: BASE_COUNTER.load(Ordering::Relaxed),
+                base_nanos: BASE_NANOS.load(Ordering::Relaxed),
+                frequency: SOURCE_FREQUENCY.load(Ordering::Relaxed),
+            };
+            if SEQUENCE.load(Ordering::Acquire) == sequence {
+                return clock;
+            }
+        }
+    }
+
+    fn nanos_at(&self, counter: u64) -> u64 {
+        let elapsed = counter.wrapping_sub(self.base_counter);
+        let nanos = match self.source {
+            Clocksource::Ticks => elapsed as u128,
+            _ => elapsed as u128 * NANOS_PER_SECOND / self.frequency as u128,
+        };
+        self.base_nanos + nanos as u64
+    }
+
+    fn now(&self) -> u64 {
+        self.nanos_at(self.source.read())
+    }
+}
+
+/// Makes `Instant::now` read `source`, which counts at `frequency` Hz. Time
+/// carries on from the old source, so instants taken before stay comparable.
+pub(super) fn switch_to(source: Clocksource, frequency: u64) {
+    interrupts::without_interrupts(|| {
+        let now = Clock::load().now();
+        let counter = source.read();
+
+        SEQUENCE.fetch_add(1, Ordering::Acquire);
+        SOURCE.store(source as u8, Ordering::Relaxed);
+        BASE_COUNTER.store(counter, Ordering::Relaxed);
+        BASE_NANOS.store(now, Ordering::Relaxed);
+        SOURCE_FREQUENCY.store(frequency, Ordering::Relaxed);
+        SEQUENCE.fetch_add(1, Ordering::Release);
+    });
+}
+
+/// Source `Instant::now` currently reads.
+pub fn clocksource() -> Clocksource {
+    Clock::load().source
+}
+
+/// A point on the monotonic clock, with the resolution of the best counter
+/// the machine has. Only meaningful compared to other instants.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    /// since boot
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant {
+            nanos: Clock::load().now(),
+        }
+    }
+
+    /// Time from boot to this instant.
+    pub fn since_boot(&self) -> Duration {
+        Duration::from_nanos(self.nanos)
+    }
+
+    /// Time from `earlier` to `self`, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        self.saturating_duration_since(earlier)
+    }
+
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.nanos
+            .checked_sub(earlier.nanos)
+            .map(Duration::from_nanos)
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        self.checked_duration_since(earlier).unwrap_or_default()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Instant {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, duration: Duration) {
+        *self = *self - duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
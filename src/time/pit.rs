@@ -0,0 +1,80 @@
+use x86_64::instructions::{interrupts, port::Port};
+
+/// Input clock of the 8253/8254 programmable interval timer in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Gate and output of channel 2 and the speaker enable, from the keyboard controller.
+const PORT_B: u16 = 0x61;
+
+const GATE_2: u8 = 1 << 0;
+const SPEAKER: u8 = 1 << 1;
+const OUTPUT_2: u8 = 1 << 5;
+
+// Fields of the mode/command register: channel, access mode, operating mode,
+// BCD flag. Binary counting is bit 0 clear.
+const SELECT_CHANNEL_0: u8 = 0b00 << 6;
+const SELECT_CHANNEL_2: u8 = 0b10 << 6;
+const ACCESS_LATCH: u8 = 0b00 << 4;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_0: u8 = 0b000 << 1;
+const MODE_2: u8 = 0b010 << 1;
+
+/// Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const RATE_GENERATOR: u8 = SELECT_CHANNEL_0 | ACCESS_LOW_HIGH | MODE_2;
+/// Channel 2, low byte then high byte, mode 0 (interrupt on terminal count), binary.
+const ONE_SHOT_2: u8 = SELECT_CHANNEL_2 | ACCESS_LOW_HIGH | MODE_0;
+/// Channel 0 counter latch command.
+const LATCH_COUNT: u8 = SELECT_CHANNEL_0 | ACCESS_LATCH;
+
+/// Makes channel 0 fire IRQ 0 `frequency` times a second, returns the divisor
+/// actually used since the rate is only approximate.
+pub fn set_frequency(frequency: u64) -> u16 {
+    let divisor = (PIT_FREQUENCY / frequency).clamp(1, u16::MAX as u64) as u16;
+
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(RATE_GENERATOR);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    });
+    divisor
+}
+
+/// Current value of the channel 0 down counter.
+pub fn read_count() -> u16 {
+    interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(LATCH_COUNT);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        let low = channel.read() as u16;
+        let high = channel.read() as u16;
+        high << 8 | low
+    })
+}
+
+/// Spins until channel 2 has counted down `count` input clock cycles. Channel 2
+/// only drives the speaker, so channel 0 keeps ticking meanwhile. `start` runs
+/// right before the count starts, e.g. to sample another clock.
+pub fn busy_wait(count: u16, start: impl FnOnce()) {
+    interrupts::without_interrupts(|| unsafe {
+        let mut port_b = Port::<u8>::new(PORT_B);
+        // gate low holds the counter, keep the speaker quiet
+        let control = port_b.read() & !(GATE_2 | SPEAKER);
+        port_b.write(control);
+
+        Port::<u8>::new(COMMAND).write(ONE_SHOT_2);
+        let mut channel = Port::<u8>::new(CHANNEL_2);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+
+        start();
+        port_b.write(control | GATE_2);
+        while port_b.read() & OUTPUT_2 == 0 {
+            core::hint::spin_loop();
+        }
+
+        port_b.write(control);
+    });
+}
@@ -0,0 +1,381 @@
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{
+    acpi,
+    interrupts::irq::{self, IrqError, IrqReturn},
+};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Set in the index port to keep NMIs masked while a register is selected,
+/// `deselect` clears it again.
+const NMI_DISABLE: u8 = 0x80;
+
+const SECONDS: u8 = 0x00;
+const ALARM_SECONDS: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const ALARM_MINUTES: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const ALARM_HOURS: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+const STATUS_C: u8 = 0x0C;
+const STATUS_D: u8 = 0x0D;
+
+const UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const RATE_MASK: u8 = 0x0F;
+const HOUR_24: u8 = 1 << 1;
+const BINARY: u8 = 1 << 2;
+const ALARM_INTERRUPT: u8 = 1 << 5;
+const PERIODIC_INTERRUPT: u8 = 1 << 6;
+const PM: u8 = 1 << 7;
+
+const RTC_IRQ: u8 = 8;
+
+/// Index and data port have to be used as a pair.
+static CMOS: Mutex<()> = Mutex::new(());
+
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+/// Wall clock at boot in seconds since the Unix epoch, and the uptime it was
+/// read at, so `now` does not have to touch the CMOS.
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+static BOOT_UPTIME_NANOS: AtomicU64 = AtomicU64::new(0);
+
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+static ALARM_FIRED: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::new(CMOS_INDEX).write(NMI_DISABLE | register);
+        let value = Port::new(CMOS_DATA).read();
+        deselect();
+        value
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    unsafe {
+        Port::new(CMOS_INDEX).write(NMI_DISABLE | register);
+        Port::new(CMOS_DATA).write(value);
+        deselect();
+    }
+}
+
+/// Unmasks NMIs again, leaving the read only status register D selected.
+unsafe fn deselect() {
+    Port::<u8>::new(CMOS_INDEX).write(STATUS_D);
+}
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let _cmos = CMOS.lock();
+        f()
+    })
+}
+
+/// Calendar date and time of day, UTC as far as the firmware keeps the RTC in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00.
+    pub fn unix_timestamp(&self) -> u64 {
+        // days from civil, see http://howardhinnant.github.io/date_algorithms.html
+        let (year, month) = if self.month <= 2 {
+            (self.year as i64 - 1, self.month as i64 + 9)
+        } else {
+            (self.year as i64, self.month as i64 - 3)
+        };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * month + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+
+        days as u64 * 86_400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / 86_400) as i64 + 719_468;
+        let seconds = timestamp % 86_400;
+
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Registers exactly as the RTC holds them, still in its BCD or 12 hour format.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw(century_register: u8) -> RawTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: match century_register {
+            0 => 0,
+            register => read_register(register),
+        },
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+/// Reads the date and time from the CMOS.
+pub fn read() -> DateTime {
+    let century_register = CENTURY_REGISTER.load(Ordering::Relaxed);
+
+    let (raw, status_b) = with_cmos(|| {
+        // an update can still start between the flag check and the reads,
+        // read until two results agree
+        let mut raw = read_raw(century_register);
+        loop {
+            let again = read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(STATUS_B))
+    });
+
+    let binary = status_b & BINARY != 0;
+    let decode = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let mut hour = decode(raw.hour & !PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 am is midnight, 12 pm is noon
+        hour %= 12;
+        if raw.hour & PM != 0 {
+            hour += 12;
+        }
+    }
+
+    let year = decode(raw.year) as u16;
+    let year = match century_register {
+        0 => 2000 + year,
+        _ => decode(raw.century) as u16 * 100 + year,
+    };
+
+    DateTime {
+        year,
+        month: decode(raw.month),
+        day: decode(raw.day),
+        hour,
+        minute: decode(raw.minute),
+        second: decode(raw.second),
+    }
+}
+
+/// Reads the clock once and claims IRQ 8, `now` stays unknown before this.
+pub fn init() -> Result<(), IrqError> {
+    let fadt = acpi::get()
+        .and_then(|acpi| acpi.fadt)
+        .or_else(acpi::fadt::parse);
+    if let Some(fadt) = fadt {
+        CENTURY_REGISTER.store(fadt.century_register, Ordering::Relaxed);
+    }
+
+    let now = read();
+    BOOT_UPTIME_NANOS.store(super::uptime().as_nanos() as u64, Ordering::Relaxed);
+    BOOT_TIME.store(now.unix_timestamp(), Ordering::Relaxed);
+
+    irq::register_irq(RTC_IRQ, "rtc", |_| {
+        // reading status C acknowledges, the RTC raises no further interrupt until then
+        let status = with_cmos(|| read_register(STATUS_C));
+        if status & PERIODIC_INTERRUPT != 0 {
+            PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+        }
+        if status & ALARM_INTERRUPT != 0 {
+            ALARM_FIRED.store(true, Ordering::Release);
+            ALARM_WAKER.wake();
+        }
+        IrqReturn::Handled
+    })?;
+
+    log::info!("RTC reads {}", now);
+    Ok(())
+}
+
+/// Current date and time, `None` before `init`.
+pub fn now() -> Option<DateTime> {
+    let boot_time = BOOT_TIME.load(Ordering::Relaxed);
+    if boot_time == 0 {
+        return None;
+    }
+
+    let since_boot = super::uptime().saturating_sub(Duration::from_nanos(
+        BOOT_UPTIME_NANOS.load(Ordering::Relaxed),
+    ));
+    Some(DateTime::from_unix_timestamp(
+        boot_time + since_boot.as_secs(),
+    ))
+}
+
+/// Makes the RTC raise IRQ 8 at `32768 >> (rate - 1)` Hz, `rate` from 3
+/// (8192 Hz) to 15 (2 Hz). `None` turns the periodic interrupt off.
+pub fn set_periodic_rate(rate: Option<u8>) {
+    with_cmos(|| {
+        let status_b = read_register(STATUS_B);
+        match rate {
+            Some(rate) => {
+                let rate = rate.clamp(3, 15);
+                let status_a = read_register(STATUS_A);
+                write_register(STATUS_A, (status_a & !RATE_MASK) | rate);
+                write_register(STATUS_B, status_b | PERIODIC_INTERRUPT);
+            }
+            None => write_register(STATUS_B, status_b & !PERIODIC_INTERRUPT),
+        }
+        read_register(STATUS_C);
+    });
+}
+
+/// Periodic interrupts seen since `set_periodic_rate`.
+pub fn periodic_interrupts() -> u64 {
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+/// Arms the daily alarm for `hour:minute:second` in the RTC's time, the
+/// returned future completes when it goes off.
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> Alarm {
+    ALARM_FIRED.store(false, Ordering::Release);
+
+    with_cmos(|| {
+        let status_b = read_register(STATUS_B);
+        let encode = |value: u8| {
+            if status_b & BINARY != 0 {
+                value
+            } else {
+                to_bcd(value)
+            }
+        };
+        let hour = if status_b & HOUR_24 != 0 {
+            encode(hour)
+        } else {
+            let pm = if hour >= 12 { PM } else { 0 };
+            encode(match hour % 12 {
+                0 => 12,
+                hour => hour,
+            }) | pm
+        };
+
+        write_register(ALARM_SECONDS, encode(second));
+        write_register(ALARM_MINUTES, encode(minute));
+        write_register(ALARM_HOURS, hour);
+        write_register(STATUS_B, status_b | ALARM_INTERRUPT);
+        read_register(STATUS_C);
+    });
+
+    Alarm { _private: () }
+}
+
+/// Completes when the alarm set by `set_alarm` fires.
+pub struct Alarm {
+    _private: (),
+}
+
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if ALARM_FIRED.load(Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+
+        ALARM_WAKER.register(cx.waker());
+        if ALARM_FIRED.load(Ordering::Acquire) {
+            ALARM_WAKER.take();
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[test_case]
+fn unix_timestamp_round_trip() {
+    let date = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 5,
+    };
+    assert_eq!(date.unix_timestamp(), 1_709_213_825);
+    assert_eq!(DateTime::from_unix_timestamp(date.unix_timestamp()), date);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
@@ -0,0 +1,186 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::{duration_to_ticks, ticks};
+
+/// Slots of the timer wheel, a timer lives in slot `deadline % WHEEL_SLOTS`.
+const WHEEL_SLOTS: usize = 256;
+
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel::new());
+/// Earliest deadline in the wheel, lets `wake_expired` return right away on
+/// most ticks.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+struct Timer {
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+struct Wheel {
+    slots: [Vec<Timer>; WHEEL_SLOTS],
+    /// tick up to which all slots have been expired
+    expired_until: u64,
+}
+
+impl Wheel {
+    const fn new() -> Self {
+        Self {
+            slots: [const { Vec::new() }; WHEEL_SLOTS],
+            expired_until: 0,
+        }
+    }
+
+    fn slot(deadline: u64) -> usize {
+        (deadline % WHEEL_SLOTS as u64) as usize
+    }
+
+    /// Adds or updates a timer, returns `false` without adding it if
+    /// `expire` already went past its deadline on another CPU, its slot would
+    /// not be looked at again until the wheel comes round.
+    fn insert(&mut self, id: u64, deadline: u64, waker: &Waker) -> bool {
+        if deadline < self.expired_until {
+            return false;
+        }
+        let slot = &mut self.slots[Self::slot(deadline)];
+        match slot.iter_mut().find(|timer| timer.id == id) {
+            Some(timer) if timer.waker.will_wake(waker) => {}
+            Some(timer) => timer.waker = waker.clone(),
+            None => slot.push(Timer {
+                id,
+                deadline,
+                waker: waker.clone(),
+            }),
+        }
+        NEXT_DEADLINE.fetch_min(deadline, Ordering::Relaxed);
+        true
+    }
+
+    fn remove(&mut self, id: u64, deadline: u64) -> Option<Timer> {
+        let slot = &mut self.slots[Self::slot(deadline)];
+        let index = slot.iter().position(|timer| timer.id == id)?;
+        Some(slot.swap_remove(index))
+    }
+
+    /// Moves every timer due at `now` into `expired`.
+    fn expire(&mut self, now: u64, expired: &mut Vec<Timer>) {
+        // after a full turn every slot has been looked at
+        let first = self
+            .expired_until
+            .max(now.saturating_sub(WHEEL_SLOTS as u64 - 1));
+        for tick in first..=now {
+            let slot = &mut self.slots[Self::slot(tick)];
+            let mut index = 0;
+            while index < slot.len() {
+                if slot[index].deadline <= now {
+                    expired.push(slot.swap_remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+        }
+        self.expired_until = now + 1;
+
+        let next = self
+            .slots
+            .iter()
+            .flatten()
+            .map(|timer| timer.deadline)
+            .min()
+            .unwrap_or(u64::MAX);
+        NEXT_DEADLINE.store(next, Ordering::Relaxed);
+    }
+
+    fn len(&self) -> usize {
+        self.slots.iter().map(Vec::len).sum()
+    }
+}
+
+/// Wakes the tasks whose sleep is over. The executor calls this from its run
+/// loop, the timer interrupt that ends a deadline also ends its `hlt`.
+pub fn wake_expired() {
+    let now = ticks();
+    if now < NEXT_DEADLINE.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut expired = Vec::new();
+    interrupts::without_interrupts(|| WHEEL.lock().expire(now, &mut expired));
+    for timer in expired {
+        timer.waker.wake();
+    }
+}
+
+/// Number of sleeping timers.
+pub fn pending() -> usize {
+    interrupts::without_interrupts(|| WHEEL.lock().len())
+}
+
+/// Future that completes once the tick counter reaches its deadline.
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+    registered: bool,
+}
+
+/// Completes after at least `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    // one tick more, the current tick is already partly over
+    sleep_until(ticks() + duration_to_ticks(duration) + 1)
+}
+
+/// Completes once `ticks()` reaches `deadline`.
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        id: NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed),
+        deadline,
+        registered: false,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        let (id, deadline) = (self.id, self.deadline);
+        if !interrupts::without_interrupts(|| WHEEL.lock().insert(id, deadline, cx.waker())) {
+            // the tick counter passed the deadline after the check above
+            return Poll::Ready(());
+        }
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self.registered {
+            let timer =
+                interrupts::without_interrupts(|| WHEEL.lock().remove(self.id, self.deadline));
+            // drop the waker with interrupts enabled
+            drop(timer);
+        }
+    }
+}
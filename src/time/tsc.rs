@@ -0,0 +1,100 @@
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::instructions::interrupts;
+
+use super::{hpet::Hpet, pit};
+
+const MAX_EXTENDED_LEAF: u32 = 0x8000_0000;
+const ADVANCED_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const INVARIANT_TSC: u32 = 1 << 8;
+const HAS_TSC: u32 = 1 << 4;
+
+/// Calibration runs, the shortest is the one least disturbed by SMIs or the host.
+const CALIBRATION_RUNS: usize = 3;
+/// Length of one calibration run against the PIT, about 20 ms.
+const PIT_CALIBRATION_COUNT: u16 = (pit::PIT_FREQUENCY / 50) as u16;
+/// Length of one calibration run against the HPET.
+const HPET_CALIBRATION_MILLIS: u64 = 20;
+
+/// Calibrated rate in Hz, zero before calibration.
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Current value of the time stamp counter.
+#[inline]
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+pub fn is_present() -> bool {
+    unsafe { __cpuid(1) }.edx & HAS_TSC != 0
+}
+
+/// Whether the TSC runs at a constant rate in every P-, C- and T-state, only
+/// then it can be used as a clock on its own.
+pub fn is_invariant() -> bool {
+    let max_leaf = unsafe { __cpuid(MAX_EXTENDED_LEAF) }.eax;
+    max_leaf >= ADVANCED_POWER_MANAGEMENT
+        && unsafe { __cpuid(ADVANCED_POWER_MANAGEMENT) }.edx & INVARIANT_TSC != 0
+}
+
+/// Calibrated rate in Hz, `None` before calibration.
+pub fn frequency() -> Option<u64> {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// Measures the TSC rate against channel 2 of the PIT.
+pub fn calibrate_with_pit() -> u64 {
+    let frequency = shortest_run(|| {
+        let mut start = 0;
+        pit::busy_wait(PIT_CALIBRATION_COUNT, || start = read());
+        let elapsed = read() - start;
+        (elapsed, PIT_CALIBRATION_COUNT as u64, pit::PIT_FREQUENCY)
+    });
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// Measures the TSC rate against the HPET main counter, which is more precise
+/// than the PIT.
+pub fn calibrate_with_hpet(hpet: &Hpet) -> u64 {
+    let hpet_frequency = hpet.frequency();
+    let wait = hpet_frequency * HPET_CALIBRATION_MILLIS / 1000;
+
+    let frequency = shortest_run(|| {
+        interrupts::without_interrupts(|| {
+            let hpet_start = hpet.counter();
+            let start = read();
+            let mut hpet_now = hpet_start;
+            while hpet.ticks_between(hpet_start, hpet_now) < wait {
+                hpet_now = hpet.counter();
+            }
+            let elapsed = read() - start;
+            (
+                elapsed,
+                hpet.ticks_between(hpet_start, hpet_now),
+                hpet_frequency,
+            )
+        })
+    });
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// Runs `run`, which returns TSC cycles, reference clock cycles and the
+/// reference frequency, a few times and converts the run that took the least
+/// TSC cycles per reference cycle to Hz.
+fn shortest_run(mut run: impl FnMut() -> (u64, u64, u64)) -> u64 {
+    (0..CALIBRATION_RUNS)
+        .map(|_| {
+            let (cycles, reference_cycles, reference_frequency) = run();
+            (cycles as u128 * reference_frequency as u128 / reference_cycles as u128) as u64
+        })
+        .min()
+        .unwrap_or(0)
+}
@@ -8,13 +8,16 @@ use vga::{
 use volatile::Volatile;
 
 use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::{interrupts, port::Port};
+use x86_64::instructions::port::Port;
 
-use crate::{serial_print, serial_println};
+use crate::{
+    serial_print, serial_println,
+    sync::{level, IrqMutex},
+};
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new());
+    pub static ref WRITER: IrqMutex<Writer> =
+        IrqMutex::ordered("vga writer", level::WRITER, Writer::new());
     // pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
     //     column_position: 0,
     //     color_code: ColorCode::new(Color::White, Color::Black),
@@ -245,20 +248,16 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
-    interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
-    })
+    WRITER.lock().write_fmt(args).unwrap();
 }
 #[test_case]
 fn test_vga_buffer() {
-    interrupts::without_interrupts(|| {
-        let mut writer = WRITER.lock();
-        let s = "test string";
-        writeln!(writer, "{}", s).unwrap();
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.text.read_character(i, BUFFER_HEIGHT - 2);
-            // let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.get_character()), c);
-        }
-    })
+    let mut writer = WRITER.lock();
+    let s = "test string";
+    writeln!(writer, "{}", s).unwrap();
+    for (i, c) in s.chars().enumerate() {
+        let screen_char = writer.text.read_character(i, BUFFER_HEIGHT - 2);
+        // let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        assert_eq!(char::from(screen_char.get_character()), c);
+    }
 }
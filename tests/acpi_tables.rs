@@ -0,0 +1,93 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(samanthi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    acpi::madt::{self, Polarity, TriggerMode},
+    allocator, memory,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+/// MADT of a two CPU machine with one IOAPIC, the length in the header is
+/// left at 0 since the parser does not look at it.
+fn madt_bytes() -> Vec<u8> {
+    let mut table = Vec::new();
+    table.extend_from_slice(b"APIC");
+    table.resize(36, 0);
+    table.extend_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    // dual 8259s present
+    table.extend_from_slice(&1u32.to_le_bytes());
+
+    // local APICs, processor id, APIC id, enabled
+    table.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    table.extend_from_slice(&[0, 8, 1, 1, 1, 0, 0, 0]);
+    // IOAPIC 2 at 0xFEC00000, starting at GSI 0
+    table.extend_from_slice(&[1, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    // ISA IRQ 0 on GSI 2, bus defaults
+    table.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0x00, 0x00]);
+    // ISA IRQ 9 on GSI 9, active high and level triggered
+    table.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0D, 0x00]);
+    table
+}
+
+#[test_case]
+fn parses_madt() {
+    let madt = madt::parse_table(&madt_bytes()).unwrap();
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xFEE0_0000));
+    assert!(madt.legacy_pics);
+
+    let apic_ids: Vec<u8> = madt.local_apics.iter().map(|apic| apic.apic_id).collect();
+    assert_eq!(apic_ids, [0, 1]);
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(madt.io_apics[0].id, 2);
+    assert_eq!(madt.io_apics[0].address, PhysAddr::new(0xFEC0_0000));
+    assert_eq!(madt.io_apics[0].gsi_base, 0);
+
+    let timer = madt.isa_override(0).unwrap();
+    assert_eq!(timer.gsi, 2);
+    assert_eq!(timer.polarity, Polarity::Conforming);
+    assert_eq!(timer.trigger, TriggerMode::Conforming);
+    let sci = madt.isa_override(9).unwrap();
+    assert_eq!(sci.gsi, 9);
+    assert_eq!(sci.polarity, Polarity::ActiveHigh);
+    assert_eq!(sci.trigger, TriggerMode::Level);
+    assert!(madt.isa_override(1).is_none());
+}
+
+#[test_case]
+fn madt_stops_at_truncated_entry() {
+    let mut table = madt_bytes();
+    // an IOAPIC entry claiming more bytes than the table has left
+    table.extend_from_slice(&[1, 12, 3, 0]);
+    let madt = madt::parse_table(&table).unwrap();
+    assert_eq!(madt.io_apics.len(), 1);
+    assert_eq!(madt.isa_override(9).map(|entry| entry.gsi), Some(9));
+
+    assert!(madt::parse_table(&table[..40]).is_none());
+}
@@ -0,0 +1,152 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(samanthi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    panic::PanicInfo,
+    ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator::{
+        self,
+        debug::{Corruption, DebugHeap},
+    },
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+const ARENA_SIZE: usize = 64 * 1024;
+
+#[repr(align(4096))]
+struct Memory(UnsafeCell<[u8; ARENA_SIZE]>);
+
+unsafe impl Sync for Memory {}
+
+static MEMORY: Memory = Memory(UnsafeCell::new([0; ARENA_SIZE]));
+
+/// Hands out memory from a static array and never reuses it, so freed blocks
+/// keep their headers and a second free can be told apart from garbage.
+struct Arena {
+    used: AtomicUsize,
+}
+
+unsafe impl GlobalAlloc for Arena {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let start = self
+            .used
+            .load(Ordering::Relaxed)
+            .next_multiple_of(layout.align());
+        if start + layout.size() > ARENA_SIZE {
+            return ptr::null_mut();
+        }
+        self.used.store(start + layout.size(), Ordering::Relaxed);
+        (MEMORY.0.get() as *mut u8).add(start)
+    }
+
+    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+}
+
+static ARENA: Arena = Arena {
+    used: AtomicUsize::new(0),
+};
+static HEAP: DebugHeap<Arena> = DebugHeap::new(&ARENA);
+
+#[test_case]
+fn red_zone_overwrite_is_reported() {
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe {
+        let ptr = HEAP.alloc(layout);
+        assert!(!ptr.is_null());
+        assert_eq!(HEAP.verify(ptr, layout), Ok(()));
+
+        // one byte past the end
+        let saved = ptr.add(24).read();
+        ptr.add(24).write(0);
+        let corruption = HEAP.verify(ptr, layout).unwrap_err();
+        assert!(matches!(
+            corruption,
+            Corruption::RedZone {
+                size: 24,
+                before: false,
+                offset: 0,
+                ..
+            }
+        ));
+        assert!(corruption.to_string().starts_with("red zone after"));
+
+        ptr.add(24).write(saved);
+        ptr.sub(3).write(0);
+        assert!(matches!(
+            HEAP.verify(ptr, layout),
+            Err(Corruption::RedZone { before: true, .. })
+        ));
+    }
+}
+
+#[test_case]
+fn double_free_is_reported() {
+    let layout = Layout::from_size_align(100, 16).unwrap();
+    unsafe {
+        let ptr = HEAP.alloc(layout);
+        assert!(!ptr.is_null());
+        let (count, bytes) = HEAP.outstanding();
+        HEAP.dealloc(ptr, layout);
+        assert_eq!(HEAP.outstanding(), (count - 1, bytes - 100));
+
+        let corruption = HEAP.verify(ptr, layout).unwrap_err();
+        assert_eq!(
+            corruption,
+            Corruption::DoubleFree {
+                addr: ptr as usize,
+                size: 100
+            }
+        );
+        assert!(corruption.to_string().starts_with("double free of"));
+    }
+}
+
+#[test_case]
+fn layout_mismatch_is_reported() {
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe {
+        let ptr = HEAP.alloc(layout);
+        let wrong = Layout::from_size_align(32, 8).unwrap();
+        assert!(matches!(
+            HEAP.verify(ptr, wrong),
+            Err(Corruption::LayoutMismatch {
+                allocated: (64, 8),
+                freed: (32, 8),
+                ..
+            })
+        ));
+        HEAP.dealloc(ptr, layout);
+    }
+}
@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(samanthi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::memory::{
+    self, fault,
+    vm::{self, AreaKind},
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+
+    test_main();
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+const REGION_SIZE: u64 = 64 * 4096;
+
+#[test_case]
+fn lazy_region_is_backed_on_touch() {
+    let start = vm::reserve(REGION_SIZE, 4096, AreaKind::Anonymous, "test").unwrap();
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    fault::register_lazy_region("test", start, REGION_SIZE, flags).unwrap();
+
+    let frames_before = memory::frame::stats().used;
+    let pages = start.as_mut_ptr::<[u64; 512]>();
+    for page in [0, 7, 63] {
+        let page = unsafe { &mut *pages.add(page) };
+        assert!(page.iter().all(|&word| word == 0));
+        page[100] = 0xdead_beef;
+        assert_eq!(page[100], 0xdead_beef);
+    }
+    // only the touched pages take frames, plus maybe some page tables
+    let used = memory::frame::stats().used - frames_before;
+    assert!((3..=6).contains(&used));
+
+    fault::unregister_lazy_region(start).unwrap();
+    vm::release(start);
+    assert!(memory::frame::stats().used - frames_before <= 3);
+}
+
+#[test_case]
+fn overlapping_regions_are_rejected() {
+    let start = vm::reserve(2 * REGION_SIZE, 4096, AreaKind::Anonymous, "test").unwrap();
+    let flags = PageTableFlags::WRITABLE;
+    fault::register_lazy_region("first", start, REGION_SIZE, flags).unwrap();
+
+    assert_eq!(
+        fault::register_lazy_region("second", start + 4096u64, REGION_SIZE, flags),
+        Err(fault::RegionError::Overlapping)
+    );
+    assert_eq!(
+        fault::register_lazy_region("unaligned", start + 12u64, 4096, flags),
+        Err(fault::RegionError::Unaligned)
+    );
+    fault::unregister_lazy_region(start).unwrap();
+    vm::release(start);
+}
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    acpi, allocator, cpu, interrupts, memory, serial_print, serial_println, smp,
+    task::{
+        self,
+        executor::{Executor, EXIT_FLAG},
+        Task,
+    },
+    time::Instant,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// More than the old fixed size queue held.
+const TASKS: usize = 200;
+const YIELDS: usize = 10;
+
+static FINISHED: AtomicUsize = AtomicUsize::new(0);
+/// Bit per CPU that polled one of the tasks.
+static CPUS_USED: AtomicU64 = AtomicU64::new(0);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("executor::tasks_spread_over_cpus...\t");
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    smp::reserve_trampoline();
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init();
+    interrupts::init_apic();
+    assert!(smp::init() > 1, "needs more than one CPU");
+
+    let mut executor = Executor::new();
+    for _ in 0..TASKS {
+        executor.spawn(Task::new(worker()));
+    }
+    executor.spawn(Task::new(check()));
+    executor.run()
+}
+
+async fn worker() {
+    for _ in 0..YIELDS {
+        CPUS_USED.fetch_or(1 << cpu::current().index(), Ordering::Relaxed);
+        // keep the CPU busy for a moment so the others have something to steal
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_micros(20) {
+            core::hint::spin_loop();
+        }
+        task::yield_now().await;
+    }
+    FINISHED.fetch_add(1, Ordering::Release);
+}
+
+async fn check() {
+    let start = Instant::now();
+    while FINISHED.load(Ordering::Acquire) < TASKS {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "tasks did not finish"
+        );
+        task::yield_now().await;
+    }
+    assert!(
+        CPUS_USED.load(Ordering::Relaxed).count_ones() > 1,
+        "only one CPU ran tasks"
+    );
+
+    serial_println!("[ok]");
+    EXIT_FLAG.store(true, Ordering::Relaxed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
@@ -1,7 +1,8 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
 #![feature(custom_test_frameworks)]
+#![test_runner(samanthi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 extern crate alloc;
 use core::panic::PanicInfo;
@@ -12,18 +13,17 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use samanthi::allocator;
-    use samanthi::memory::{self, BootInfoFrameAllocator};
+    use samanthi::memory;
     use x86_64::VirtAddr;
 
     samanthi::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
 
-    // test_main();
-    simple_allocation();
-    loop {}
+    test_main();
+    samanthi::hlt_loop()
 }
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -32,7 +32,7 @@ fn panic(info: &PanicInfo) -> ! {
 
 use alloc::boxed::Box;
 
-// #[test_case]
+#[test_case]
 fn simple_allocation() {
     let heap_value_1 = Box::new(41);
     let heap_value_2 = Box::new(13);
@@ -40,7 +40,15 @@ fn simple_allocation() {
     assert_eq!(*heap_value_2, 13);
 }
 
-// use alloc::vec::Vec;
+use alloc::vec::Vec;
+use samanthi::allocator::HEAP_SIZE;
+
+#[test_case]
+fn heap_grows_past_initial_size() {
+    let n = 2 * HEAP_SIZE / 8;
+    let vec: Vec<u64> = (0..n as u64).collect();
+    assert_eq!(vec.iter().sum::<u64>(), (n as u64 - 1) * n as u64 / 2);
+}
 
 // #[test_case]
 // fn large_vec() {
@@ -0,0 +1,51 @@
+#![no_std]
+#![no_main]
+
+use core::{arch::asm, fmt::Write, panic::PanicInfo};
+use samanthi::{exit_qemu, hlt_loop, serial_print, serial_println, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::ud2_is_reported...\t");
+    samanthi::init();
+
+    unsafe { asm!("ud2") };
+
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop()
+}
+
+/// Keeps the panic message to compare it without an allocator.
+struct Message {
+    buf: [u8; 128],
+    len: usize,
+}
+
+impl Write for Message {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = (self.len + s.len()).min(self.buf.len());
+        self.buf[self.len..end].copy_from_slice(&s.as_bytes()[..end - self.len]);
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut message = Message {
+        buf: [0; 128],
+        len: 0,
+    };
+    let _ = write!(message, "{}", info.message());
+
+    if &message.buf[..message.len] == b"EXCEPTION: INVALID OPCODE" {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("unexpected panic: {}", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop()
+}
@@ -0,0 +1,102 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(samanthi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::{
+    arch::asm,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator,
+    interrupts::irq::{self, IrqError, IrqReturn},
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+/// Not routed to any controller, so only `int` raises it.
+const TEST_VECTOR: u8 = 0x60;
+
+fn raise_test_vector() {
+    unsafe { asm!("int 0x60") };
+}
+
+fn count(vector: u8) -> (u64, u64) {
+    irq::stats()
+        .into_iter()
+        .find(|stats| stats.vector == vector)
+        .map_or((0, 0), |stats| (stats.count, stats.unhandled))
+}
+
+#[test_case]
+fn shared_handlers_all_run() {
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    let first = irq::register_vector(TEST_VECTOR, "first", true, |_| {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::NotMine
+    })
+    .unwrap();
+    let second = irq::register_vector(TEST_VECTOR, "second", true, |vector| {
+        assert_eq!(vector, TEST_VECTOR);
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        IrqReturn::Handled
+    })
+    .unwrap();
+
+    let (count_before, unhandled_before) = count(TEST_VECTOR);
+    raise_test_vector();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 2);
+    assert_eq!(count(TEST_VECTOR), (count_before + 1, unhandled_before));
+
+    assert!(irq::unregister(second));
+    raise_test_vector();
+    assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+    assert_eq!(count(TEST_VECTOR), (count_before + 2, unhandled_before + 1));
+
+    assert!(irq::unregister(first));
+    assert!(!irq::unregister(first));
+}
+
+#[test_case]
+fn exclusive_vectors_are_not_shared() {
+    let handle =
+        irq::register_vector(TEST_VECTOR, "exclusive", false, |_| IrqReturn::Handled).unwrap();
+    assert_eq!(
+        irq::register_vector(TEST_VECTOR, "other", true, |_| IrqReturn::Handled),
+        Err(IrqError::Busy)
+    );
+    assert!(irq::unregister(handle));
+}
+
+#[test_case]
+fn reserved_vectors_are_rejected() {
+    for vector in [14, 32, 0xFF] {
+        assert_eq!(
+            irq::register_vector(vector, "reserved", false, |_| IrqReturn::Handled),
+            Err(IrqError::Reserved)
+        );
+    }
+}
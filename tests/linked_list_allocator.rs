@@ -0,0 +1,219 @@
+//! Stress tests for `LinkedListAllocator`. The allocator only manages a static
+//! arena here, so next to running in QEMU with the rest of the suite the tests
+//! can run directly on the host:
+//!
+//! `cargo test --test linked_list_allocator --target x86_64-unknown-linux-gnu -Zbuild-std=std --no-default-features`
+
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, addr_of_mut},
+};
+use samanthi::allocator::linked_list::{LinkedListAllocator, Locked};
+
+const ARENA_SIZE: usize = 64 * 1024;
+
+#[repr(C, align(4096))]
+struct Arena([u8; ARENA_SIZE]);
+
+static mut ARENA: Arena = Arena([0; ARENA_SIZE]);
+
+#[cfg(target_os = "none")]
+macro_rules! test_print {
+    ($($arg:tt)*) => { samanthi::serial_print!($($arg)*) };
+}
+
+#[cfg(not(target_os = "none"))]
+macro_rules! test_print {
+    ($($arg:tt)*) => { print!($($arg)*) };
+}
+
+#[cfg(target_os = "none")]
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    run_tests();
+    samanthi::exit_qemu(samanthi::QemuExitCode::Success);
+    samanthi::hlt_loop()
+}
+
+#[cfg(target_os = "none")]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+#[cfg(not(target_os = "none"))]
+fn main() {
+    run_tests();
+}
+
+fn run_tests() {
+    let tests: &[(&str, fn())] = &[
+        ("coalesces_freed_neighbours", coalesces_freed_neighbours),
+        ("realloc_in_place", realloc_in_place),
+        ("random_alloc_free_sequences", random_alloc_free_sequences),
+    ];
+
+    for (name, test) in tests {
+        test_print!("linked_list_allocator::{}...\t", name);
+        test();
+        test_print!("[ok]\n");
+    }
+}
+
+fn new_allocator() -> Locked<LinkedListAllocator> {
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(addr_of_mut!(ARENA) as usize, ARENA_SIZE)
+    };
+    allocator
+}
+
+fn assert_fully_free(allocator: &Locked<LinkedListAllocator>) {
+    let allocator = allocator.lock();
+    assert_eq!(allocator.free_bytes(), ARENA_SIZE);
+    assert_eq!(allocator.largest_free_region(), ARENA_SIZE);
+    assert_eq!(allocator.stats().in_use, 0);
+}
+
+fn coalesces_freed_neighbours() {
+    let allocator = new_allocator();
+    let layout = Layout::from_size_align(ARENA_SIZE / 4, 8).unwrap();
+
+    let blocks = [(); 4].map(|_| unsafe { allocator.alloc(layout) });
+    assert!(blocks.iter().all(|block| !block.is_null()));
+    assert!(unsafe { allocator.alloc(Layout::new::<u64>()) }.is_null());
+
+    for index in [1, 3, 0, 2] {
+        unsafe { allocator.dealloc(blocks[index], layout) };
+    }
+    assert_fully_free(&allocator);
+
+    let whole = Layout::from_size_align(ARENA_SIZE, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(whole) };
+    assert!(!ptr.is_null());
+    unsafe { allocator.dealloc(ptr, whole) };
+}
+
+fn realloc_in_place() {
+    let allocator = new_allocator();
+    let small = Layout::from_size_align(128, 8).unwrap();
+
+    let a = unsafe { allocator.alloc(small) };
+    let b = unsafe { allocator.alloc(small) };
+    let c = unsafe { allocator.alloc(small) };
+    unsafe { allocator.dealloc(b, small) };
+
+    let grown = unsafe { allocator.realloc(a, small, 256) };
+    assert_eq!(grown, a);
+    let grown_layout = Layout::from_size_align(256, 8).unwrap();
+
+    // c is in the way now, so growing further has to move
+    let moved = unsafe { allocator.realloc(a, grown_layout, 512) };
+    assert_ne!(moved, a);
+    let moved_layout = Layout::from_size_align(512, 8).unwrap();
+
+    let shrunk = unsafe { allocator.realloc(moved, moved_layout, 24) };
+    assert_eq!(shrunk, moved);
+
+    unsafe {
+        allocator.dealloc(shrunk, Layout::from_size_align(24, 8).unwrap());
+        allocator.dealloc(c, small);
+    }
+    assert_fully_free(&allocator);
+}
+
+/// xorshift64, good enough to shuffle allocation patterns
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+const SLOTS: usize = 64;
+
+fn random_alloc_free_sequences() {
+    let allocator = new_allocator();
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut slots: [Option<(*mut u8, Layout, u8)>; SLOTS] = [None; SLOTS];
+
+    for round in 0..20_000 {
+        let slot = rng.next() % SLOTS;
+
+        match slots[slot].take() {
+            Some((ptr, layout, fill)) => {
+                assert_filled(ptr, layout.size(), fill);
+
+                if rng.next().is_multiple_of(4) {
+                    let new_size = 1 + rng.next() % 2048;
+                    let new_ptr = unsafe { allocator.realloc(ptr, layout, new_size) };
+                    if new_ptr.is_null() {
+                        slots[slot] = Some((ptr, layout, fill));
+                        continue;
+                    }
+                    assert_filled(new_ptr, layout.size().min(new_size), fill);
+
+                    let new_layout = Layout::from_size_align(new_size, layout.align()).unwrap();
+                    unsafe { ptr::write_bytes(new_ptr, fill, new_size) };
+                    assert_disjoint(&slots, new_ptr, new_size);
+                    slots[slot] = Some((new_ptr, new_layout, fill));
+                } else {
+                    unsafe { allocator.dealloc(ptr, layout) };
+                }
+            }
+            None => {
+                let size = 1 + rng.next() % 2048;
+                let align = 1 << (rng.next() % 7);
+                let layout = Layout::from_size_align(size, align).unwrap();
+
+                let ptr = unsafe { allocator.alloc(layout) };
+                if ptr.is_null() {
+                    continue;
+                }
+                assert_eq!(ptr as usize % align, 0);
+                assert_disjoint(&slots, ptr, size);
+
+                let fill = round as u8;
+                unsafe { ptr::write_bytes(ptr, fill, size) };
+                slots[slot] = Some((ptr, layout, fill));
+            }
+        }
+    }
+
+    for (ptr, layout, fill) in slots.iter_mut().filter_map(Option::take) {
+        assert_filled(ptr, layout.size(), fill);
+        unsafe { allocator.dealloc(ptr, layout) };
+    }
+    assert_fully_free(&allocator);
+}
+
+fn assert_filled(ptr: *mut u8, size: usize, fill: u8) {
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, size) };
+    assert!(
+        bytes.iter().all(|&byte| byte == fill),
+        "allocation at {:p} was overwritten",
+        ptr
+    );
+}
+
+fn assert_disjoint(slots: &[Option<(*mut u8, Layout, u8)>], ptr: *mut u8, size: usize) {
+    let (start, end) = (ptr as usize, ptr as usize + size);
+    for (other, layout, _) in slots.iter().flatten() {
+        let (other_start, other_end) = (*other as usize, *other as usize + layout.size());
+        assert!(
+            end <= other_start || other_end <= start,
+            "allocation at {:p} overlaps {:p}",
+            ptr,
+            other
+        );
+    }
+}
@@ -0,0 +1,47 @@
+#![no_std]
+#![no_main]
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use samanthi::{
+    exit_qemu, hlt_loop, serial, serial_print, serial_println,
+    sync::{level, IrqMutex},
+    QemuExitCode,
+};
+
+const MESSAGE: &str = "panic while holding the scheduler level lock";
+
+/// Ordered above the serial port, printing inside it breaks the lock order.
+static LOCK: IrqMutex<()> = IrqMutex::ordered("test lock", level::SCHEDULER, ());
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    samanthi::init();
+    serial_print!("panic_holding_lock::message_gets_out...\t");
+
+    let _guard = LOCK.lock();
+    panic!("{}", MESSAGE);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // a lock order report from printing would land here a second time
+    if PANICKED.swap(true, Ordering::Relaxed) {
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop()
+    }
+
+    unsafe { serial::unlock_for_panic() };
+    if info.message().as_str() != Some(MESSAGE) {
+        serial_println!("[failed]\nError: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+        hlt_loop()
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop()
+}
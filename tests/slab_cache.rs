@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(samanthi::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::panic::PanicInfo;
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator::{
+        self,
+        slab::{SlabBox, SlabCache},
+    },
+    memory,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+#[test_case]
+fn reuse_and_shrink() {
+    static CACHE: SlabCache<[u64; 20]> = SlabCache::new("test");
+
+    let frames_before = memory::frame::stats().free;
+    let mut objects: Vec<SlabBox<[u64; 20]>> = (0..200u64)
+        .map(|i| CACHE.alloc([i; 20]).ok().unwrap())
+        .collect();
+    for (i, object) in objects.iter().enumerate() {
+        assert_eq!(object[19], i as u64);
+    }
+    assert_eq!(CACHE.stats().in_use, 200);
+
+    // freed objects get handed out again before new slabs are allocated
+    let slabs = CACHE.stats().slabs;
+    objects.truncate(100);
+    objects.extend((0..100u64).map(|i| CACHE.alloc([i; 20]).ok().unwrap()));
+    assert_eq!(CACHE.stats().slabs, slabs);
+
+    drop(objects);
+    CACHE.shrink();
+    assert_eq!(CACHE.stats().slabs, 0);
+    assert_eq!(memory::frame::stats().free, frames_before);
+}
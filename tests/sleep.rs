@@ -0,0 +1,60 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{panic::PanicInfo, sync::atomic::Ordering, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator, memory, serial_print, serial_println,
+    task::{
+        executor::{Executor, EXIT_FLAG},
+        Task,
+    },
+    time::{self, Instant},
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("sleep::sleepers_wake_in_deadline_order...\t");
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    for millis in [30, 10, 20] {
+        executor.spawn(Task::new(sleeper(millis, order.clone())));
+    }
+    executor.spawn(Task::new(check(order)));
+    executor.run()
+}
+
+async fn sleeper(millis: u64, order: Arc<Mutex<Vec<u64>>>) {
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(millis)).await;
+    let slept = start.elapsed();
+    assert!(slept >= Duration::from_millis(millis), "{:?}", slept);
+    order.lock().push(millis);
+}
+
+async fn check(order: Arc<Mutex<Vec<u64>>>) {
+    time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(*order.lock(), [10, 20, 30]);
+    assert_eq!(time::timer::pending(), 0);
+
+    serial_println!("[ok]");
+    EXIT_FLAG.store(true, Ordering::Relaxed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
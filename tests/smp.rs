@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    acpi, allocator, exit_qemu, interrupts, memory, serial_print, serial_println,
+    smp::{self, CpuState, SmpError},
+    time::Instant,
+    QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// QEMU is started with `-smp 4`.
+const EXPECTED_CPUS: usize = 4;
+
+static RAN: AtomicUsize = AtomicUsize::new(0);
+static APIC_IDS: [AtomicU8; EXPECTED_CPUS] = [const { AtomicU8::new(0xFF) }; EXPECTED_CPUS];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("smp::application_processors_run_work...\t");
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    smp::reserve_trampoline();
+    allocator::init_heap().expect("heap initialization failed");
+    acpi::init();
+    interrupts::init_apic();
+
+    assert_eq!(smp::init(), EXPECTED_CPUS);
+    let cpus = smp::cpus();
+    assert!(cpus.iter().all(|cpu| cpu.state() == CpuState::Online));
+
+    let bsp = cpus.iter().position(|cpu| cpu.bsp).unwrap();
+    assert_eq!(
+        smp::run_on(bsp, || {}).unwrap_err(),
+        SmpError::BootProcessor
+    );
+
+    for (index, _) in cpus.iter().enumerate().filter(|(_, cpu)| !cpu.bsp) {
+        smp::run_on(index, move || {
+            APIC_IDS[index].store(interrupts::apic::local_apic_id(), Ordering::Relaxed);
+            RAN.fetch_add(1, Ordering::Release);
+        })
+        .unwrap();
+    }
+
+    let start = Instant::now();
+    while RAN.load(Ordering::Acquire) < EXPECTED_CPUS - 1 {
+        assert!(start.elapsed() < Duration::from_secs(1), "work did not run");
+        core::hint::spin_loop();
+    }
+
+    // every AP ran its own work
+    for (index, cpu) in cpus.iter().enumerate().filter(|(_, cpu)| !cpu.bsp) {
+        assert_eq!(APIC_IDS[index].load(Ordering::Relaxed), cpu.apic_id);
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
@@ -0,0 +1,73 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{panic::PanicInfo, sync::atomic::Ordering, time::Duration};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator, memory, serial_print, serial_println,
+    task::{
+        self,
+        executor::{Executor, Spawner, EXIT_FLAG},
+        join::JoinError,
+        Task,
+    },
+    time,
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("spawn::join_and_cancel...\t");
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(check(executor.spawner())));
+    executor.run()
+}
+
+async fn check(spawner: Spawner) {
+    assert_eq!(spawner.spawn(async { 6 * 7 }).await, Ok(42));
+
+    // a task spawning and joining its own task
+    let inner = spawner.clone();
+    let outer = spawner.spawn(async move {
+        let handle = inner.spawn(async { 1u64 });
+        handle.await.unwrap() + 1
+    });
+    assert_eq!(outer.await, Ok(2));
+
+    let sleeper = spawner.spawn_named("sleeper", async {
+        time::sleep(Duration::from_secs(10)).await;
+    });
+    task::yield_now().await;
+    let listed = spawner
+        .tasks()
+        .into_iter()
+        .find(|task| task.id == sleeper.id())
+        .expect("sleeper is not listed");
+    assert_eq!(listed.name, "sleeper");
+    assert!(listed.polls >= 1);
+
+    assert!(sleeper.cancel());
+    assert!(sleeper.is_finished());
+    let id = sleeper.id();
+    assert_eq!(sleeper.await, Err(JoinError::Cancelled));
+    assert!(spawner.tasks().iter().all(|task| task.id != id));
+    assert!(!spawner.cancel(id));
+
+    serial_println!("[ok]");
+    EXIT_FLAG.store(true, Ordering::Relaxed);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
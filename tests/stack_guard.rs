@@ -0,0 +1,99 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+use core::{arch::asm, panic::PanicInfo};
+
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use samanthi::{
+    exit_qemu, hlt_loop,
+    memory::{self, stack::KernelStack},
+    serial_print, serial_println, QemuExitCode,
+};
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
+};
+
+const STACK_NAME: &str = "overflow test stack";
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("stack_guard::overflow_hits_guard_page...\t");
+    samanthi::gdt::init();
+    init_test_idt();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+
+    let stack = KernelStack::new(4096 * 4, STACK_NAME).expect("failed to allocate stack");
+    let top = stack.leak();
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "call {overflow}",
+            top = in(reg) top.as_u64(),
+            overflow = sym stack_overflow,
+            options(noreturn)
+        )
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
+
+#[allow(unconditional_recursion)]
+extern "C" fn stack_overflow() {
+    stack_overflow();
+    volatile::Volatile::new(0).read();
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(samanthi::gdt::PAGE_FAULT_IST_INDEX);
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(samanthi::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+
+        idt
+    };
+}
+
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    if memory::stack::guard_page_owner(Cr2::read()) == Ok(Some(STACK_NAME)) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("page fault at {:?} outside the guard page", Cr2::read());
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    serial_println!("[failed]");
+    serial_println!("double fault instead of a page fault in the guard page");
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
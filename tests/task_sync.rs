@@ -0,0 +1,157 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use core::{arch::asm, panic::PanicInfo, sync::atomic::Ordering};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator,
+    interrupts::irq::{self, IrqReturn},
+    memory, serial_print, serial_println,
+    task::{
+        self,
+        executor::{Executor, Spawner, EXIT_FLAG},
+        sync::{mpsc, oneshot, Mutex, Notify, RwLock, Semaphore},
+        Task,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// Not routed to any controller, so only `int` raises it.
+const TEST_VECTOR: u8 = 0x60;
+
+static NOTIFY: Notify = Notify::new();
+static SEMAPHORE: Semaphore = Semaphore::new(1);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("task_sync::primitives...\t");
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(check(executor.spawner())));
+    executor.run()
+}
+
+async fn check(spawner: Spawner) {
+    mutex_is_exclusive(&spawner).await;
+    rwlock_readers_and_writers(&spawner).await;
+    bounded_channel(&spawner).await;
+    oneshot_from_task(&spawner).await;
+    interrupt_handler_wakes_tasks().await;
+
+    serial_println!("[ok]");
+    EXIT_FLAG.store(true, Ordering::Relaxed);
+}
+
+async fn mutex_is_exclusive(spawner: &Spawner) {
+    let counter = Arc::new(Mutex::new(0u32));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            spawner.spawn(async move {
+                for _ in 0..25 {
+                    let mut value = counter.lock().await;
+                    let seen = *value;
+                    // others run while the guard is held and have to wait
+                    task::yield_now().await;
+                    *value = seen + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(*counter.lock().await, 100);
+}
+
+async fn rwlock_readers_and_writers(spawner: &Spawner) {
+    let lock = Arc::new(RwLock::new(0u32));
+
+    let first = lock.read().await;
+    let second = lock.read().await;
+    assert!(lock.try_write().is_none());
+
+    let writer = {
+        let lock = lock.clone();
+        spawner.spawn(async move { *lock.write().await += 1 })
+    };
+    task::yield_now().await;
+    // the waiting writer keeps new readers out
+    assert!(lock.try_read().is_none());
+    drop((first, second));
+
+    writer.await.unwrap();
+    assert_eq!(*lock.read().await, 1);
+}
+
+async fn bounded_channel(spawner: &Spawner) {
+    let (sender, mut receiver) = mpsc::channel(2);
+    let producer = spawner.spawn(async move {
+        for value in 0..10u32 {
+            sender.send(value).await.unwrap();
+        }
+    });
+
+    let mut received = Vec::new();
+    while let Some(value) = receiver.recv().await {
+        received.push(value);
+    }
+    assert_eq!(received, (0..10).collect::<Vec<_>>());
+    producer.await.unwrap();
+
+    let (sender, receiver) = mpsc::channel(1);
+    sender.try_send(1u8).unwrap();
+    assert_eq!(sender.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    drop(receiver);
+    assert_eq!(sender.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+}
+
+async fn oneshot_from_task(spawner: &Spawner) {
+    let (sender, receiver) = oneshot::channel();
+    spawner.spawn(async move { sender.send(42u32).unwrap() });
+    assert_eq!(receiver.await, Ok(42));
+
+    let (sender, receiver) = oneshot::channel::<u32>();
+    drop(sender);
+    assert_eq!(receiver.await, Err(oneshot::RecvError));
+}
+
+async fn interrupt_handler_wakes_tasks() {
+    let permit = SEMAPHORE.acquire().await;
+    permit.forget();
+    assert!(SEMAPHORE.try_acquire().is_none());
+
+    let (sender, mut receiver) = mpsc::channel(4);
+    let handle = irq::register_vector(TEST_VECTOR, "task sync test", false, move |_| {
+        sender.try_send(7u32).unwrap();
+        NOTIFY.notify_one();
+        SEMAPHORE.add_permits(1);
+        IrqReturn::Handled
+    })
+    .unwrap();
+
+    unsafe { asm!("int 0x60") };
+    assert_eq!(receiver.recv().await, Some(7));
+    // the permit was stored, nobody was waiting yet
+    NOTIFY.notified().await;
+    drop(SEMAPHORE.acquire().await);
+
+    irq::unregister(handle);
+    // the handler and its sender are gone
+    assert_eq!(receiver.recv().await, None);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}
@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use bootloader::{entry_point, BootInfo};
+use samanthi::{
+    allocator, exit_qemu, memory, serial_print, serial_println,
+    thread::{self, Priority, ThreadState},
+    time::Instant,
+    QemuExitCode,
+};
+use x86_64::VirtAddr;
+
+static STOP: AtomicBool = AtomicBool::new(false);
+static COUNTERS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("thread::spawn_sleep_and_preempt...\t");
+    samanthi::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { memory::init(phys_mem_offset) };
+    unsafe { memory::frame::init(&boot_info.memory_map, phys_mem_offset) };
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init().expect("failed to start threads");
+
+    let handle = thread::spawn(|| 6 * 7).expect("failed to spawn");
+    assert_eq!(handle.join(), 42);
+
+    let start = Instant::now();
+    thread::sleep(Duration::from_millis(20));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    // neither thread ever yields, both only make progress if the timer
+    // preempts them
+    let spinners = [0, 1].map(|index| {
+        thread::spawn_named("spinner", Priority::Normal, move || {
+            while !STOP.load(Ordering::Relaxed) {
+                COUNTERS[index].fetch_add(1, Ordering::Relaxed);
+            }
+            index
+        })
+        .expect("failed to spawn")
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(COUNTERS
+        .iter()
+        .all(|counter| counter.load(Ordering::Relaxed) > 0));
+
+    let listed = thread::threads();
+    assert!(listed
+        .iter()
+        .any(|thread| thread.name == "main" && thread.state == ThreadState::Running));
+    assert_eq!(
+        listed
+            .iter()
+            .filter(|thread| thread.name == "spinner")
+            .count(),
+        2
+    );
+
+    STOP.store(true, Ordering::Relaxed);
+    for (index, spinner) in spinners.into_iter().enumerate() {
+        assert_eq!(spinner.join(), index);
+    }
+
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    samanthi::hlt_loop()
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    samanthi::test_panic_handler(info)
+}